@@ -1,4 +1,20 @@
-use clap::{Parser, ValueEnum, ValueHint};
+use std::{os::unix::io::RawFd, path::PathBuf, time::Duration};
+
+use clap::{Parser, Subcommand, ValueEnum, ValueHint};
+use http::Method;
+use ipnet::IpNet;
+
+use crate::{
+    filesystem::{normalize_prefix, SymlinkPolicy},
+    httpfs::{
+        acl::parse_ip_range,
+        client_ip::{parse_trusted_proxy, ForwardedHeader, TrustedProxy},
+        listener::{parse_bind, parse_mode, BindAddress},
+        security::{ActiveContent, FrameOptions},
+        tokens::{parse_lifetime, Scope},
+        vhost::{parse_vhost, HostPattern, VirtualHostSpec},
+    },
+};
 
 #[derive(ValueEnum, Clone, Copy, Debug)]
 pub enum Color {
@@ -19,10 +35,15 @@ impl Color {
 }
 
 // httpfs [-v] [-p PORT] [-d PATH-TO-DIR]
+// httpfs --share-secret PATH share /path/to/file [--expires 24h]
+// httpfs --token-file PATH token mint --name NAME [--scope read,write] [--prefix /dir] [--expires 30d]
 
 #[derive(Debug, Parser)]
 #[clap(version, about)]
 pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     /// Should the output be in color?
     #[clap(long, value_enum, global = true, default_value = "auto")]
     pub color: Color,
@@ -35,9 +56,387 @@ pub struct Cli {
     #[clap(short, long, default_value_t = 8080)]
     pub port: u16,
 
+    /// Address to listen on, can be repeated to listen on several addresses, default is 127.0.0.1
+    /// Either ADDR:PORT (`[::]:8080`, `localhost:0`) or an IP address that uses --port (`0.0.0.0`, `::`)
+    #[clap(short, long = "bind", value_name = "ADDR", value_parser = parse_bind)]
+    pub binds: Vec<BindAddress>,
+
+    /// Path of a Unix domain socket to listen on, can be repeated
+    /// Only listens on TCP as well if --bind is also given. A leftover socket file from a previous run is replaced
+    #[clap(long = "unix", value_name = "PATH", value_hint = ValueHint::FilePath)]
+    pub unix_sockets: Vec<PathBuf>,
+
+    /// File mode for --unix sockets, in octal like 660
+    #[clap(long, value_name = "MODE", value_parser = parse_mode)]
+    pub unix_mode: Option<u32>,
+
+    /// Owner for --unix sockets, as USER[:GROUP] names or numeric ids
+    #[clap(long, value_name = "USER[:GROUP]")]
+    pub unix_owner: Option<String>,
+
+    /// Serve on an already listening TCP or Unix socket inherited from the parent process, can be repeated
+    /// Sockets passed by systemd socket activation (LISTEN_FDS) are picked up automatically.
+    /// When any sockets are inherited, --bind and --unix are ignored
+    #[clap(long = "listen-fd", value_name = "FD")]
+    pub listen_fds: Vec<RawFd>,
+
+    /// PEM certificate (chain) to serve HTTPS with, changes to the file are picked up without a restart
+    #[clap(long, value_name = "PATH", requires = "tls_key", value_hint = ValueHint::FilePath)]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[clap(long, value_name = "PATH", requires = "tls_cert", value_hint = ValueHint::FilePath)]
+    pub tls_key: Option<PathBuf>,
+
+    /// Address to serve HTTPS on, can be repeated, same format as --bind
+    /// Without this every TCP listener serves HTTPS, with it --bind listeners stay plain HTTP
+    #[clap(long = "tls-bind", value_name = "ADDR", value_parser = parse_bind, requires = "tls_cert")]
+    pub tls_binds: Vec<BindAddress>,
+
+    /// Expect a PROXY protocol v1 or v2 header, like HAProxy's `send-proxy`, at the start of every
+    /// connection on every listener, and use the client address it gives instead of the load
+    /// balancer's. Connections without a valid header are closed
+    #[clap(long)]
+    pub proxy_protocol: bool,
+
+    /// Require HTTP Basic auth, with users and password hashes from a htpasswd-style file (USER:HASH per line)
+    /// Supports bcrypt (`htpasswd -B`), sha-crypt ($5$/$6$) and argon2 hashes
+    #[clap(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    pub auth_file: Option<PathBuf>,
+
+    /// Realm shown by browsers when asking for a password
+    #[clap(long, value_name = "REALM", default_value = "httpfs")]
+    pub auth_realm: String,
+
+    /// Also accept `Authorization: Bearer` API tokens from this file, manage it with `httpfs token`
+    /// Changes to the file are picked up without a restart
+    #[clap(long, value_name = "PATH", global = true, value_hint = ValueHint::FilePath)]
+    pub token_file: Option<PathBuf>,
+
+    /// Secret key for signing share links, made with `httpfs share` if it doesn't exist yet
+    /// Requests with a valid `?expires=...&sig=...` link skip authentication and access rules
+    #[clap(long, value_name = "PATH", global = true, value_hint = ValueHint::FilePath)]
+    pub share_secret: Option<PathBuf>,
+
+    /// Check every request against access rules from a file, see the readme for the format
+    /// With --auth-file, anonymous requests are then only rejected if the rules say so
+    #[clap(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    pub acl_file: Option<PathBuf>,
+
+    /// Log requests the access rules would deny, but don't deny them
+    #[clap(long, requires = "acl_file")]
+    pub acl_dry_run: bool,
+
+    /// Only let clients in these ranges connect, like 10.0.0.0/8 or a single address, can be repeated
+    #[clap(long = "allow-ip", value_name = "CIDR", value_parser = parse_ip_net)]
+    pub allow_ips: Vec<IpNet>,
+
+    /// Never let clients in these ranges connect, even if --allow-ip allows them, can be repeated
+    #[clap(long = "deny-ip", value_name = "CIDR", value_parser = parse_ip_net)]
+    pub deny_ips: Vec<IpNet>,
+
+    /// Believe the client address that proxies in this range put in --client-ip-header, can be
+    /// repeated. `unix` trusts every connection to a --unix socket, ie. from nginx
+    /// The client address is then used for --allow-ip/--deny-ip, access rules, rate limits and logs
+    #[clap(long = "trusted-proxy", value_name = "CIDR|unix", value_parser = parse_trusted_proxy)]
+    pub trusted_proxies: Vec<TrustedProxy>,
+
+    /// Header trusted proxies put the client address in
+    #[clap(
+        long,
+        value_enum,
+        default_value = "x-forwarded-for",
+        requires = "trusted_proxies"
+    )]
+    pub client_ip_header: ForwardedHeader,
+
+    /// Let scripts on this origin read responses, like https://dashboard.example.com, can be repeated
+    /// `*` allows any origin, `reflect` allows any origin by echoing it back, which works with --cors-credentials
+    #[clap(long = "cors-origin", value_name = "ORIGIN")]
+    pub cors_origins: Vec<String>,
+
+    /// Methods cross-origin requests may use
+    #[clap(
+        long,
+        value_name = "METHODS",
+        value_delimiter = ',',
+        default_value = "GET,HEAD,POST",
+        value_parser = parse_method,
+        requires = "cors_origins"
+    )]
+    pub cors_methods: Vec<Method>,
+
+    /// Request headers cross-origin requests may send
+    #[clap(
+        long,
+        value_name = "HEADERS",
+        value_delimiter = ',',
+        default_value = "Authorization,Content-Type",
+        requires = "cors_origins"
+    )]
+    pub cors_headers: Vec<String>,
+
+    /// Let cross-origin requests send credentials, like Basic auth or tokens
+    #[clap(long, requires = "cors_origins")]
+    pub cors_credentials: bool,
+
+    /// Seconds browsers may cache the answer to a preflight request
+    #[clap(long, value_name = "SECS", requires = "cors_origins")]
+    pub cors_max_age: Option<u64>,
+
+    /// Directory with `listing.html`, `404.html` and `error.html` templates to use instead of the
+    /// built in ones, any that are missing stay built in. See the readme for the placeholders
+    #[clap(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    pub template_dir: Option<PathBuf>,
+
+    /// Don't send X-Content-Type-Options, Referrer-Policy, X-Frame-Options or --csp
+    #[clap(long)]
+    pub no_security_headers: bool,
+
+    /// Content-Security-Policy to send with every response, like "default-src 'self'"
+    #[clap(long, value_name = "POLICY")]
+    pub csp: Option<String>,
+
+    /// Referrer-Policy to send with every response, `off` to leave it out
+    #[clap(long, value_name = "POLICY", default_value = "no-referrer")]
+    pub referrer_policy: String,
+
+    /// X-Frame-Options to send with every response
+    #[clap(long, value_enum, default_value = "deny")]
+    pub frame_options: FrameOptions,
+
+    /// How to serve HTML, SVG and XML files from places uploads are allowed, since anyone who can
+    /// upload could make them run scripts as this server. `sandbox` blocks the scripts and
+    /// `attachment` makes browsers download the files, `allow` serves them like any other file
+    #[clap(long, value_enum, default_value = "sandbox")]
+    pub active_content: ActiveContent,
+
+    /// Most connections to serve at once, more get a 503 until some finish
+    #[clap(long, value_name = "N")]
+    pub max_connections: Option<usize>,
+
+    /// Most connections to serve at once from a single IP, more get a 429 until some finish
+    ///
+    /// This counts the address that connected, before any request is read, so every client
+    /// behind a --trusted-proxy shares the proxy's count. A PROXY protocol header's address is
+    /// counted though
+    #[clap(long, value_name = "N")]
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Requests per second each IP may make on average, faster ones get a 429
+    #[clap(long, value_name = "REQ/S", value_parser = parse_rate)]
+    pub rate_limit: Option<f64>,
+
+    /// Requests each IP may make in a quick burst before --rate-limit kicks in
+    #[clap(long, value_name = "N", default_value_t = 10, requires = "rate_limit")]
+    pub rate_burst: u32,
+
+    /// Longest request line (method, URI and version) in bytes, longer ones get a 414
+    #[clap(long, value_name = "SIZE", default_value = "8K", value_parser = parse_size)]
+    pub max_request_line: usize,
+
+    /// Most headers in a request, more get a 431
+    #[clap(long, value_name = "N", default_value_t = 100)]
+    pub max_headers: usize,
+
+    /// Most bytes of headers all together, more get a 431
+    #[clap(long, value_name = "SIZE", default_value = "64K", value_parser = parse_size)]
+    pub max_header_bytes: usize,
+
+    /// Largest request body, like 100M or 2G, bigger ones get a 413
+    #[clap(long, value_name = "SIZE", default_value = "100M", value_parser = parse_size)]
+    pub max_body: usize,
+
+    /// Different body limit for paths under a prefix, as PREFIX=SIZE like /incoming=2G, can be repeated
+    #[clap(long = "max-body-for", value_name = "PREFIX=SIZE", value_parser = parse_body_limit)]
+    pub body_limits: Vec<(String, usize)>,
+
+    /// Largest single chunk of a chunked request body, bigger ones get a 413
+    #[clap(long, value_name = "SIZE", default_value = "16M", value_parser = parse_size)]
+    pub max_chunk: usize,
+
+    /// Seconds a client has to send the request line and headers, slower ones get a 408
+    #[clap(long, value_name = "SECS", default_value_t = 30.0, value_parser = parse_seconds)]
+    pub read_timeout: f64,
+
+    /// Seconds a client may send nothing at all before getting a 408, even in the middle of a body
+    #[clap(long, value_name = "SECS", default_value_t = 15.0, value_parser = parse_seconds)]
+    pub idle_timeout: f64,
+
+    /// Work around bare LF line endings, obsolete header line folding, and requests with both
+    /// Content-Length and Transfer-Encoding (or repeated Content-Lengths), instead of rejecting them.
+    /// Unsafe behind a proxy that might read such requests differently
+    #[clap(long)]
+    pub lenient_parsing: bool,
+
+    /// Seconds to let active connections finish after SIGINT/SIGTERM before aborting them, default is 10
+    #[clap(long, value_name = "SECS", default_value_t = 10.0, value_parser = parse_seconds)]
+    pub grace_period: f64,
+
     /// Path to the directory to serve, default is current working directory
     #[clap(short, long, default_value = ".", value_hint = ValueHint::DirPath)]
     pub dir: String,
+
+    /// Don't allow uploads or any other changes, for every host
+    #[clap(long)]
+    pub read_only: bool,
+
+    /// Only allow uploads under this URL path, like /incoming, can be repeated
+    /// Without it uploads are allowed anywhere (unless --read-only)
+    #[clap(long = "upload-dir", value_name = "PREFIX", value_parser = normalize_prefix)]
+    pub upload_dirs: Vec<String>,
+
+    /// Which symlinks in served directories are followed, for reads and writes
+    /// Requests through a symlink that isn't allowed get a 403
+    #[clap(long, value_enum, value_name = "POLICY", default_value = "within-root")]
+    pub symlinks: SymlinkPolicy,
+
+    /// Show and serve dotfiles like .git/ and .env, which are hidden by default
+    #[clap(long)]
+    pub show_hidden: bool,
+
+    /// Hide files matching a .gitignore-style pattern, like `*.swp` or `/secrets/`, can be repeated
+    /// Hidden files are left out of listings and get a 404 for every method
+    #[clap(long, value_name = "PATTERN")]
+    pub hide: Vec<String>,
+
+    /// Also hide whatever .gitignore and .ignore files in the served directories ignore
+    #[clap(long)]
+    pub ignore_files: bool,
+
+    /// Disable directory listings for the default host
+    #[clap(long)]
+    pub no_listing: bool,
+
+    /// Serve a different directory for requests with a matching Host header, as PATTERN=DIR[,OPTION...]
+    /// PATTERN is a hostname or a wildcard like `*.example.com`, OPTION can be `listing`, `no-listing`,
+    /// `read-only` or `read-write`.
+    /// Requests that match no virtual host are served from --dir
+    #[clap(long = "vhost", value_name = "PATTERN=DIR", value_parser = parse_vhost)]
+    pub vhosts: Vec<VirtualHostSpec>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Manage the API tokens in --token-file
+    #[clap(subcommand)]
+    Token(TokenCommand),
+
+    /// Print a link to a single path that works without logging in, until it expires
+    Share {
+        /// Path to share, like /reports/q3.pdf
+        path: String,
+
+        /// How long until the link expires, like 24h, 7d or 90m
+        #[clap(long, value_name = "LIFETIME", default_value = "24h", value_parser = parse_lifetime)]
+        expires: Duration,
+
+        /// Method the link can be used with, a GET link also works for HEAD
+        #[clap(long, default_value = "GET", value_parser = parse_method)]
+        method: Method,
+
+        /// Virtual host the link is for, its pattern as given to --vhost, default is the default host
+        #[clap(long, value_name = "PATTERN", value_parser = HostPattern::parse)]
+        host: Option<HostPattern>,
+
+        /// Where the server is reachable, like https://files.example.com, to print a full URL
+        #[clap(long, value_name = "URL")]
+        base_url: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TokenCommand {
+    /// Create a new token and print it, it can't be shown again later
+    Mint {
+        /// Name to identify the token by in logs and access rules (`token:NAME`)
+        #[clap(long)]
+        name: String,
+
+        /// What the token may do, comma separated
+        #[clap(
+            long = "scope",
+            value_enum,
+            value_delimiter = ',',
+            default_value = "read"
+        )]
+        scopes: Vec<Scope>,
+
+        /// Only allow paths under this one
+        #[clap(long, default_value = "/")]
+        prefix: String,
+
+        /// How long until the token expires, like 30d, 12h or 90m, default is never
+        #[clap(long, value_name = "LIFETIME", value_parser = parse_lifetime)]
+        expires: Option<Duration>,
+    },
+
+    /// Revoke a token by its id, running servers stop accepting it right away
+    Revoke { id: String },
+
+    /// List every token, without their secrets
+    List,
+}
+
+fn parse_seconds(arg: &str) -> Result<f64, String> {
+    arg.parse::<f64>()
+        .ok()
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .ok_or_else(|| format!("Expected a number of seconds, got '{}'", arg))
+}
+
+/// Parse a size in bytes, with an optional K, M or G suffix (powers of 1024)
+/// ```
+/// assert_eq!(parse_size("512"), Ok(512));
+/// assert_eq!(parse_size("64K"), Ok(65536));
+/// assert_eq!(parse_size("2g"), Ok(2147483648));
+/// ```
+fn parse_size(arg: &str) -> Result<usize, String> {
+    let invalid = || format!("Expected a size like 512, 64K, 10M or 2G, got '{}'", arg);
+
+    let (number, multiplier) = match arg.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&arg[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&arg[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&arg[..i], 1 << 30),
+        _ => (arg, 1),
+    };
+
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(invalid)
+}
+
+fn parse_body_limit(arg: &str) -> Result<(String, usize), String> {
+    let (prefix, size) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("Expected PREFIX=SIZE, got '{}'", arg))?;
+
+    Ok((normalize_prefix(prefix)?, parse_size(size)?))
+}
+
+fn parse_rate(arg: &str) -> Result<f64, String> {
+    arg.parse::<f64>()
+        .ok()
+        .filter(|rate| rate.is_finite() && *rate > 0.0)
+        .ok_or_else(|| {
+            format!(
+                "Expected a positive number of requests per second, got '{}'",
+                arg
+            )
+        })
+}
+
+/// Parse a method like `GET` or `post`
+fn parse_method(arg: &str) -> Result<Method, String> {
+    Method::from_bytes(arg.to_ascii_uppercase().as_bytes())
+        .map_err(|_| format!("Invalid method '{}'", arg))
+}
+
+fn parse_ip_net(arg: &str) -> Result<IpNet, String> {
+    parse_ip_range(arg)
+        .ok_or_else(|| format!("Expected an IP range like 10.0.0.0/8, got '{}'", arg))
 }
 
 pub const VERBOSE: u8 = 1;
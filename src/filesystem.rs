@@ -1,7 +1,12 @@
-use std::path::{Component, Path, PathBuf};
+use std::{
+    ffi::OsString,
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
 
+use clap::ValueEnum;
 use mime_guess::Mime;
-use tokio::fs;
+use tokio::{fs, io::AsyncWriteExt};
 
 pub struct ServerFile {
     pub content: Vec<u8>,
@@ -23,9 +28,14 @@ pub async fn get_file(path: impl AsRef<Path>) -> Option<ServerFile> {
 
 #[derive(Eq, PartialEq)]
 pub struct DirEntry {
-    pub name: String,
+    /// Not necessarily UTF-8, so files with any name can be listed and linked to
+    pub name: OsString,
+    /// For symlinks that may be followed, whether they point to a directory
     pub is_directory: bool,
+    pub is_symlink: bool,
     pub mime: Mime,
+    /// For files, or what a symlink that may be followed points to, in bytes
+    pub size: Option<u64>,
 }
 
 impl PartialOrd for DirEntry {
@@ -46,7 +56,16 @@ impl Ord for DirEntry {
     }
 }
 
-pub async fn get_directory(path: impl AsRef<Path>) -> Option<Vec<DirEntry>> {
+/// List `relative`, a directory inside `root`, following only the symlinks `policy` allows
+///
+/// A symlink that can't be followed is listed as what it is, a link, without looking at where it
+/// points
+pub async fn get_directory(
+    root: impl AsRef<Path>,
+    relative: impl AsRef<Path>,
+    policy: SymlinkPolicy,
+) -> Option<Vec<DirEntry>> {
+    let path = root.as_ref().join(&relative);
     let mut entries = match fs::read_dir(&path).await {
         Ok(entries) => entries,
         Err(_) => return None,
@@ -62,24 +81,257 @@ pub async fn get_directory(path: impl AsRef<Path>) -> Option<Vec<DirEntry>> {
             Err(_) => continue,
         };
 
-        let name = match entry.file_name().into_string() {
-            Ok(name) => name,
+        let name = entry.file_name();
+        let mime = mime_guess::from_path(&name).first_or_octet_stream();
+        let file_type = match entry.file_type().await {
+            Ok(file_type) => file_type,
             Err(_) => continue,
         };
 
-        let mime = mime_guess::from_path(entry.file_name()).first_or_octet_stream();
-        let is_directory = entry.file_type().await.unwrap().is_dir();
+        // file_type() doesn't follow symlinks, so ask where they point separately
+        let is_symlink = file_type.is_symlink();
+        let follow =
+            is_symlink && symlinks_allowed(&root, relative.as_ref().join(&name), policy).await;
+
+        let is_directory = if follow {
+            is_directory(entry.path()).await
+        } else {
+            file_type.is_dir()
+        };
+
+        let size = if is_directory {
+            None
+        } else if follow {
+            fs::metadata(entry.path()).await.ok().map(|m| m.len())
+        } else {
+            // Doesn't follow symlinks
+            entry.metadata().await.ok().map(|m| m.len())
+        };
 
         res.push(DirEntry {
             name,
             mime,
             is_directory,
+            is_symlink,
+            size,
         });
     }
 
     Some(res)
 }
 
+/// Write a file so that readers either see the old content or the new content, never half of it
+///
+/// The content is written to a temporary file next to `path` and then renamed over it. If writing is
+/// interrupted (an error, or the server shutting down and dropping the future) the temporary file
+/// is removed, so an aborted upload leaves nothing behind
+pub async fn write_file_atomic(path: impl AsRef<Path>, content: &[u8]) -> std::io::Result<()> {
+    let mut file = AtomicFile::create(path).await?;
+    file.write_all(content).await?;
+    file.commit().await
+}
+
+/// A file written bit by bit that only replaces `path` once it's complete, like `write_file_atomic`
+///
+/// Dropping it without calling `commit` removes the temporary file
+pub struct AtomicFile {
+    file: fs::File,
+    temp: TempFile,
+    path: PathBuf,
+}
+
+impl AtomicFile {
+    pub async fn create(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        static UPLOAD_ID: AtomicU64 = AtomicU64::new(0);
+
+        let path = path.as_ref();
+        let mut temp_name = OsString::from(".");
+        temp_name.push(path.file_name().unwrap_or_default());
+        temp_name.push(format!(
+            ".{}-{}.upload",
+            std::process::id(),
+            UPLOAD_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let temp_path = path.with_file_name(temp_name);
+        let temp = TempFile(Some(temp_path.clone()));
+        let file = fs::File::create(&temp_path).await?;
+
+        Ok(Self {
+            file,
+            temp,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub async fn write_all(&mut self, content: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(content).await
+    }
+
+    /// Replace the file at `path` with everything written so far
+    pub async fn commit(self) -> std::io::Result<()> {
+        let AtomicFile {
+            file,
+            mut temp,
+            path,
+        } = self;
+
+        file.sync_all().await?;
+        drop(file);
+
+        if let Some(temp_path) = &temp.0 {
+            fs::rename(temp_path, path).await?;
+        }
+        temp.0.take();
+
+        Ok(())
+    }
+}
+
+/// Removes the file when dropped, unless the path was taken out
+struct TempFile(Option<PathBuf>);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Some(path) = self.0.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Rename `from` to `to`, failing with `AlreadyExists` rather than replacing whatever is at `to`
+///
+/// Where the filesystem can't rename without replacing, files and symlinks are hard linked to
+/// `to` and then unlinked, and empty directories are made again at `to`
+pub async fn rename_no_replace(from: &Path, to: &Path) -> std::io::Result<()> {
+    let (from, to) = (from.to_path_buf(), to.to_path_buf());
+    tokio::task::spawn_blocking(move || rename_no_replace_blocking(&from, &to))
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)))
+}
+
+/// `rename_no_replace` for callers that aren't async
+pub fn rename_no_replace_blocking(from: &Path, to: &Path) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+        let c_path = |path: &Path| {
+            CString::new(path.as_os_str().as_bytes())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+        };
+        let (c_from, c_to) = (c_path(from)?, c_path(to)?);
+
+        let result = unsafe {
+            libc::renameat2(
+                libc::AT_FDCWD,
+                c_from.as_ptr(),
+                libc::AT_FDCWD,
+                c_to.as_ptr(),
+                libc::RENAME_NOREPLACE,
+            )
+        };
+        if result == 0 {
+            return Ok(());
+        }
+
+        let error = std::io::Error::last_os_error();
+        match error.raw_os_error() {
+            // Old kernels and some filesystems don't support the flag
+            Some(libc::EINVAL) | Some(libc::ENOSYS) => {}
+            _ => return Err(error),
+        }
+    }
+
+    relink(from, to)
+}
+
+/// Move `from` to `to` by making it again there and removing the original, which fails rather
+/// than replacing anything at `to`
+fn relink(from: &Path, to: &Path) -> std::io::Result<()> {
+    let metadata = std::fs::symlink_metadata(from)?;
+
+    if metadata.is_dir() {
+        // Fails if something was put there meanwhile, like linking does for files
+        std::fs::create_dir(to)?;
+        std::fs::set_permissions(to, metadata.permissions())?;
+
+        if let Err(e) = std::fs::remove_dir(from) {
+            let _ = std::fs::remove_dir(to);
+            return Err(e);
+        }
+    } else {
+        std::fs::hard_link(from, to)?;
+
+        if let Err(e) = std::fs::remove_file(from) {
+            let _ = std::fs::remove_file(to);
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Which symlinks inside a served directory can be followed
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Follow every symlink, even ones pointing outside the served directory
+    Follow,
+    /// Only follow symlinks that stay inside the served directory
+    WithinRoot,
+    /// Never follow symlinks
+    Never,
+}
+
+/// Check that `relative` can be reached from `root` without breaking the symlink policy
+///
+/// Every existing part of the path is checked, so this works for writes to files that don't
+/// exist yet too: a symlinked parent directory pointing outside the root is caught before
+/// anything is created through it. Symlinks pointing nowhere are never allowed
+pub async fn symlinks_allowed(
+    root: impl AsRef<Path>,
+    relative: impl AsRef<Path>,
+    policy: SymlinkPolicy,
+) -> bool {
+    if policy == SymlinkPolicy::Follow {
+        return true;
+    }
+
+    // The root itself is trusted, even if it's a symlink
+    let root = match fs::canonicalize(&root).await {
+        Ok(root) => root,
+        Err(_) => return false,
+    };
+
+    let mut current = root.clone();
+
+    for component in relative.as_ref().components() {
+        current.push(component);
+
+        let metadata = match fs::symlink_metadata(&current).await {
+            Ok(metadata) => metadata,
+            // Nothing further down exists yet, so there are no more symlinks to follow
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return true,
+            Err(_) => return false,
+        };
+
+        if !metadata.file_type().is_symlink() {
+            continue;
+        }
+
+        if policy == SymlinkPolicy::Never {
+            return false;
+        }
+
+        match fs::canonicalize(&current).await {
+            Ok(target) if target.starts_with(&root) => current = target,
+            _ => return false,
+        }
+    }
+
+    true
+}
+
 pub async fn is_directory(path: impl AsRef<Path>) -> bool {
     let metadata = fs::metadata(&path).await;
     metadata.map(|m| m.is_dir()).unwrap_or(false)
@@ -108,3 +360,202 @@ pub fn flatten_path(path: impl AsRef<Path>) -> PathBuf {
             acc
         })
 }
+
+/// Prefixes are compared a path segment at a time, so store them without a trailing `/`
+pub fn normalize_prefix(prefix: &str) -> Result<String, String> {
+    if !prefix.starts_with('/') {
+        return Err(format!("path prefix '{}' must start with /", prefix));
+    }
+
+    match prefix.trim_end_matches('/') {
+        "" => Ok("/".to_string()),
+        trimmed => Ok(trimmed.to_string()),
+    }
+}
+
+/// Whether `path` is `prefix` itself or inside it, `/artifacts-old` isn't inside `/artifacts`
+pub fn is_under(path: &str, prefix: &str) -> bool {
+    prefix == "/"
+        || path == prefix
+        || path
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn checks_symlinks_against_the_policy() {
+        use SymlinkPolicy::*;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("docs/a.txt"), "a").unwrap();
+        std::fs::create_dir(dir.path().join("outside")).unwrap();
+
+        let link = |target: &str, name: &str| {
+            std::os::unix::fs::symlink(target, root.join(name)).unwrap();
+        };
+        link("docs", "manual");
+        link("../outside", "escape");
+        link("manual", "chained");
+        link("escape", "chained-escape");
+        link("missing", "dangling");
+
+        let cases = [
+            // No symlinks at all
+            ("docs/a.txt", [true, true, true]),
+            ("docs/new.txt", [true, true, true]),
+            ("manual/a.txt", [true, true, false]),
+            ("escape/new.txt", [true, false, false]),
+            ("chained/a.txt", [true, true, false]),
+            ("chained-escape/new.txt", [true, false, false]),
+            ("dangling", [true, false, false]),
+        ];
+
+        for (path, expected) in cases {
+            for (policy, allowed) in [Follow, WithinRoot, Never].into_iter().zip(expected) {
+                assert_eq!(
+                    symlinks_allowed(&root, path, policy).await,
+                    allowed,
+                    "{} with {:?}",
+                    path,
+                    policy
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn normalizes_prefixes() {
+        assert_eq!(
+            normalize_prefix("/artifacts/"),
+            Ok("/artifacts".to_string())
+        );
+        assert_eq!(
+            normalize_prefix("/artifacts//"),
+            Ok("/artifacts".to_string())
+        );
+        assert_eq!(normalize_prefix("/a/b"), Ok("/a/b".to_string()));
+        assert_eq!(normalize_prefix("/"), Ok("/".to_string()));
+        assert_eq!(normalize_prefix("//"), Ok("/".to_string()));
+        assert!(normalize_prefix("artifacts").is_err());
+        assert!(normalize_prefix("").is_err());
+    }
+
+    #[test]
+    fn checks_paths_are_under_prefixes() {
+        assert!(is_under("/artifacts/a.zip", "/artifacts"));
+        assert!(is_under("/artifacts", "/artifacts"));
+        assert!(is_under("/artifacts/", "/artifacts"));
+        assert!(is_under("/anything", "/"));
+        assert!(!is_under("/artifacts-old/a.zip", "/artifacts"));
+        assert!(!is_under("/", "/artifacts"));
+    }
+
+    #[tokio::test]
+    async fn renames_without_replacing() {
+        let root = tempfile::tempdir().unwrap();
+        let path = |name: &str| root.path().join(name);
+
+        std::fs::write(path("a"), "a").unwrap();
+        std::fs::write(path("b"), "b").unwrap();
+        std::fs::create_dir(path("dir")).unwrap();
+
+        let error = rename_no_replace(&path("a"), &path("b")).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+        let error = rename_no_replace(&path("a"), &path("dir"))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(path("b")).unwrap(), b"b");
+
+        rename_no_replace(&path("a"), &path("c")).await.unwrap();
+        assert!(!path("a").exists());
+        assert_eq!(std::fs::read(path("c")).unwrap(), b"a");
+
+        rename_no_replace(&path("dir"), &path("moved"))
+            .await
+            .unwrap();
+        assert!(path("moved").is_dir());
+
+        let error = rename_no_replace(&path("a"), &path("d")).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn relinks_without_replacing() {
+        let root = tempfile::tempdir().unwrap();
+        let path = |name: &str| root.path().join(name);
+
+        std::fs::write(path("a"), "a").unwrap();
+        std::fs::write(path("b"), "b").unwrap();
+        std::os::unix::fs::symlink("a", path("link")).unwrap();
+        std::fs::create_dir(path("dir")).unwrap();
+        std::fs::create_dir(path("full")).unwrap();
+        std::fs::write(path("full/file"), "").unwrap();
+
+        let error = relink(&path("a"), &path("b")).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+        let error = relink(&path("dir"), &path("b")).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(path("b")).unwrap(), b"b");
+
+        relink(&path("a"), &path("c")).unwrap();
+        assert!(!path("a").exists());
+        assert_eq!(std::fs::read(path("c")).unwrap(), b"a");
+
+        // The link itself moves, not what it points to
+        relink(&path("link"), &path("moved-link")).unwrap();
+        assert_eq!(
+            std::fs::read_link(path("moved-link")).unwrap(),
+            Path::new("a")
+        );
+
+        relink(&path("dir"), &path("moved")).unwrap();
+        assert!(path("moved").is_dir() && !path("dir").exists());
+
+        // Directories that aren't empty stay where they are
+        assert!(relink(&path("full"), &path("emptied")).is_err());
+        assert!(path("full/file").exists() && !path("emptied").exists());
+    }
+
+    #[tokio::test]
+    async fn lists_symlinks_it_may_not_follow_as_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(dir.join("secret.txt"), "outside the root").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret.txt"), root.join("secret.txt")).unwrap();
+        std::os::unix::fs::symlink("docs", root.join("manual")).unwrap();
+
+        let entry = |entries: &[DirEntry], name: &str| {
+            let entry = entries.iter().find(|e| e.name == name).unwrap();
+            (entry.is_directory, entry.size)
+        };
+
+        let entries = get_directory(&root, "", SymlinkPolicy::Follow)
+            .await
+            .unwrap();
+        assert_eq!(entry(&entries, "secret.txt"), (false, Some(16)));
+        assert_eq!(entry(&entries, "manual"), (true, None));
+
+        let entries = get_directory(&root, "", SymlinkPolicy::WithinRoot)
+            .await
+            .unwrap();
+        let link_size = std::fs::symlink_metadata(root.join("secret.txt"))
+            .unwrap()
+            .len();
+        assert_eq!(entry(&entries, "secret.txt"), (false, Some(link_size)));
+        assert_eq!(entry(&entries, "manual"), (true, None));
+
+        let entries = get_directory(&root, "", SymlinkPolicy::Never)
+            .await
+            .unwrap();
+        assert_eq!(entry(&entries, "manual"), (false, Some(4)));
+    }
+}
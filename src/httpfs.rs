@@ -1,10 +1,32 @@
+pub mod acl;
+pub mod actions;
+pub mod activation;
+pub mod auth;
+pub mod client_ip;
+pub mod config;
 pub mod connection;
+pub mod cors;
+pub mod csrf;
+pub mod escape;
+pub mod form;
 pub mod formatting;
 pub mod get;
 pub mod head;
+pub mod hidden;
+pub mod limits;
+pub mod listener;
 pub mod log;
 pub mod message;
+pub mod multipart;
 pub mod parse;
 pub mod parse_error;
 pub mod post;
+pub mod proxy_protocol;
+pub mod redirect;
+pub mod security;
 pub mod server;
+pub mod share;
+pub mod template;
+pub mod tls;
+pub mod tokens;
+pub mod vhost;
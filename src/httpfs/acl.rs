@@ -0,0 +1,289 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::IpAddr,
+    path::Path,
+    str::FromStr,
+};
+
+use globset::{Glob, GlobBuilder, GlobMatcher};
+use http::{header, Method, Response};
+use ipnet::IpNet;
+
+use crate::httpfs::{auth::Principal, message::ByteResponse};
+
+/// Access control rules, loaded from a file like:
+///
+/// ```text
+/// # Groups of users: group NAME USER...
+/// group ci ci-bot deploy-bot
+///
+/// # Rules: allow|deny METHODS PATH-GLOB WHO...
+/// # The first rule matching the method, path and any of WHO decides
+/// allow GET,HEAD /**           *
+/// allow POST     /artifacts/** group:ci
+/// deny  *        /releases/**  *
+///
+/// # What happens when no rule matches, deny if left out
+/// default deny
+/// ```
+///
+/// WHO is `*`, `anonymous`, `authenticated`, `user:NAME`, `group:NAME`, `token:NAME` or `ip:CIDR`
+#[derive(Debug)]
+pub struct AccessRules {
+    rules: Vec<Rule>,
+    groups: HashMap<String, HashSet<String>>,
+    default_allow: bool,
+    /// Log what would be denied instead of denying it
+    pub dry_run: bool,
+}
+
+#[derive(Debug)]
+struct Rule {
+    allow: bool,
+    /// Empty means any method
+    methods: Vec<Method>,
+    path: GlobMatcher,
+    who: Vec<Who>,
+    /// Line in the rules file, to explain denials
+    line: usize,
+}
+
+#[derive(Debug)]
+enum Who {
+    Everyone,
+    Anonymous,
+    Authenticated,
+    User(String),
+    Group(String),
+    Token(String),
+    Ip(IpNet),
+}
+
+/// The result of checking a request against the rules
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Deny(String),
+}
+
+impl AccessRules {
+    pub fn load(path: impl AsRef<Path>, dry_run: bool) -> io::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read access rules {}: {}", path.display(), e),
+            )
+        })?;
+
+        let mut rules = AccessRules {
+            rules: vec![],
+            groups: HashMap::new(),
+            default_allow: false,
+            dry_run,
+        };
+
+        for (number, line) in content.lines().enumerate() {
+            rules.parse_line(line, number + 1).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {}", path.display(), number + 1, e),
+                )
+            })?;
+        }
+
+        Ok(rules)
+    }
+
+    fn parse_line(&mut self, line: &str, number: usize) -> Result<(), String> {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let mut words = line.split_ascii_whitespace();
+
+        let keyword = match words.next() {
+            Some(keyword) => keyword,
+            None => return Ok(()),
+        };
+
+        match keyword {
+            "group" => {
+                let name = words.next().ok_or("group name missing")?;
+                self.groups
+                    .entry(name.to_string())
+                    .or_default()
+                    .extend(words.map(|w| w.to_string()));
+            }
+            "default" => {
+                self.default_allow = match words.next() {
+                    Some("allow") => true,
+                    Some("deny") => false,
+                    _ => return Err("expected 'default allow' or 'default deny'".to_string()),
+                };
+            }
+            "allow" | "deny" => {
+                let methods = words.next().ok_or("methods missing")?;
+                let path = words.next().ok_or("path glob missing")?;
+                let who = words.map(Who::from_str).collect::<Result<Vec<_>, _>>()?;
+
+                if who.is_empty() {
+                    return Err(
+                        "who the rule applies to is missing, use * for everyone".to_string()
+                    );
+                }
+
+                self.rules.push(Rule {
+                    allow: keyword == "allow",
+                    methods: parse_methods(methods)?,
+                    path: compile_glob(path)?,
+                    who,
+                    line: number,
+                });
+            }
+            _ => return Err(format!("unknown keyword '{}'", keyword)),
+        }
+
+        Ok(())
+    }
+
+    /// Decide if `principal` connecting from `ip` may use `method` on `path`
+    ///
+    /// `path` is the decoded and flattened request path, ie. `/releases/v1.zip`. Flattening drops
+    /// a trailing `/`, so a path also matches with one put back, and `/releases/**` covers the
+    /// `/releases` directory as well as what's in it
+    pub fn check(
+        &self,
+        method: &Method,
+        path: &str,
+        principal: &Principal,
+        ip: Option<IpAddr>,
+    ) -> Decision {
+        let directory = format!("{}/", path.trim_end_matches('/'));
+
+        let rule = self.rules.iter().find(|rule| {
+            (rule.methods.is_empty() || rule.methods.contains(method))
+                && (rule.path.is_match(path) || rule.path.is_match(&directory))
+                && rule.who.iter().any(|who| self.is(who, principal, ip))
+        });
+
+        match rule {
+            Some(rule) if rule.allow => Decision::Allow,
+            Some(rule) => Decision::Deny(format!("denied by the rule on line {}", rule.line)),
+            None if self.default_allow => Decision::Allow,
+            None => Decision::Deny(format!("no rule allows {} {}", method, path)),
+        }
+    }
+
+    fn is(&self, who: &Who, principal: &Principal, ip: Option<IpAddr>) -> bool {
+        match who {
+            Who::Everyone => true,
+            Who::Anonymous => *principal == Principal::Anonymous,
+            Who::Authenticated => *principal != Principal::Anonymous,
+            Who::User(name) => matches!(principal, Principal::User(user) if user == name),
+            Who::Group(group) => match principal {
+                Principal::User(user) => self
+                    .groups
+                    .get(group)
+                    .is_some_and(|members| members.contains(user)),
+                _ => false,
+            },
+            Who::Token(name) => {
+                matches!(principal, Principal::Token(grant) if grant.name == *name)
+            }
+            Who::Ip(net) => ip.is_some_and(|ip| net.contains(&ip)),
+        }
+    }
+}
+
+impl FromStr for Who {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None => match s {
+                "*" => Ok(Who::Everyone),
+                "anonymous" => Ok(Who::Anonymous),
+                "authenticated" => Ok(Who::Authenticated),
+                _ => Err(format!("unknown principal '{}'", s)),
+            },
+            Some(("user", name)) => Ok(Who::User(name.to_string())),
+            Some(("group", name)) => Ok(Who::Group(name.to_string())),
+            Some(("token", name)) => Ok(Who::Token(name.to_string())),
+            Some(("ip", range)) => parse_ip_range(range)
+                .map(Who::Ip)
+                .ok_or_else(|| format!("invalid IP range '{}'", range)),
+            Some((kind, _)) => Err(format!("unknown principal type '{}'", kind)),
+        }
+    }
+}
+
+/// Parse `10.0.0.0/8`, or a single address like `10.1.2.3` which is treated as a /32 (or /128)
+pub fn parse_ip_range(range: &str) -> Option<IpNet> {
+    range
+        .parse::<IpNet>()
+        .ok()
+        .or_else(|| range.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+/// `*` or a comma separated list like `GET,HEAD`
+fn parse_methods(methods: &str) -> Result<Vec<Method>, String> {
+    if methods == "*" {
+        return Ok(vec![]);
+    }
+
+    methods
+        .split(',')
+        .map(|m| {
+            Method::from_bytes(m.to_ascii_uppercase().as_bytes())
+                .map_err(|_| format!("invalid method '{}'", m))
+        })
+        .collect()
+}
+
+/// Path globs match whole path segments, so `*` doesn't cross a `/` but `**` does
+fn compile_glob(glob: &str) -> Result<GlobMatcher, String> {
+    if !glob.starts_with('/') {
+        return Err(format!("path glob '{}' must start with /", glob));
+    }
+
+    GlobBuilder::new(glob)
+        .literal_separator(true)
+        .build()
+        .map(|g: Glob| g.compile_matcher())
+        .map_err(|e| e.to_string())
+}
+
+pub fn create_403(reason: &str) -> ByteResponse {
+    let body: Vec<u8> = format!("403: Forbidden, {}", reason).into();
+
+    Response::builder()
+        .status(403)
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::CONTENT_TYPE, "text/plain")
+        .header(header::CONNECTION, "close")
+        .body(Some(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(lines: &[&str]) -> AccessRules {
+        let path = std::env::temp_dir().join(format!("httpfs-acl-{}", std::process::id()));
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        let rules = AccessRules::load(&path, false).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        rules
+    }
+
+    #[test]
+    fn directory_globs_cover_the_directory_itself() {
+        let rules = rules(&["deny * /releases/** *", "default allow"]);
+        let check = |path| rules.check(&Method::GET, path, &Principal::Anonymous, None);
+
+        assert!(matches!(check("/releases"), Decision::Deny(_)));
+        assert!(matches!(check("/releases/v1.zip"), Decision::Deny(_)));
+        assert_eq!(check("/releases.txt"), Decision::Allow);
+        assert_eq!(check("/"), Decision::Allow);
+    }
+}
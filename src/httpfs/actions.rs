@@ -0,0 +1,458 @@
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    io,
+    net::IpAddr,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use http::{header, Method, Response, StatusCode};
+use tokio::fs;
+use urlencoding::{decode, decode_binary};
+
+use crate::{
+    filesystem::{flatten_path, is_directory, rename_no_replace, symlinks_allowed},
+    httpfs::acl::create_403,
+    httpfs::auth::Principal,
+    httpfs::config::ServerConfig,
+    httpfs::connection::{check_permission, handle_not_allowed},
+    httpfs::form::create_form_error,
+    httpfs::message::{ByteRequest, ByteResponse},
+    httpfs::redirect::{create_redirect, local_location},
+    httpfs::server::UnrecoverableError,
+    httpfs::vhost::VirtualHost,
+};
+
+/// Run an action posted as `application/x-www-form-urlencoded` to the directory at `client_path`
+///
+/// The `action` field picks what to do, the other fields are:
+/// - `mkdir`: `name`, of the new directory
+/// - `rename`: `entry` and its new `name`, directories have to be empty
+/// - `move`: `entry` and `to`, the directory to move it into, from the root if it starts with `/`,
+///   directories have to be empty
+/// - `delete`: `entry`, directories have to be empty
+///
+/// `entry` is a file or directory in this directory, percent-encoded like its link in the listing.
+/// Making something needs POST there, and removing something needs DELETE, checked the same way
+/// as requests using those methods directly
+pub async fn handle_action(
+    request: &ByteRequest,
+    config: &ServerConfig,
+    host: &VirtualHost,
+    client_path: &Path,
+    principal: &Principal,
+    ip: Option<IpAddr>,
+) -> Result<ByteResponse, UnrecoverableError> {
+    let empty: Vec<u8> = Vec::new();
+    let body = request.body().as_ref().unwrap_or(&empty);
+
+    let fields = match parse_form(body) {
+        Ok(fields) => fields,
+        Err(e) => return Ok(bad_form(&e)),
+    };
+
+    let directory = format!("/{}", client_path.to_string_lossy());
+    let token = fields.get("csrf").map(String::as_str);
+    if let Err(reason) = config
+        .csrf
+        .check_form(token, principal, &host.name, &directory)
+    {
+        return Ok(create_403(&reason));
+    }
+
+    let action = Action {
+        config,
+        host,
+        client_path,
+        principal,
+        ip,
+    };
+
+    let result = match fields.get("action").map(String::as_str) {
+        Some("mkdir") => action.mkdir(&fields).await?,
+        Some("rename") => action.rename(&fields).await?,
+        Some("move") => action.move_to(&fields).await?,
+        Some("delete") => action.delete(&fields).await?,
+        Some(other) => Err(bad_form(&format!("unknown action '{}'", other))),
+        None => Err(bad_form(&missing_field("action"))),
+    };
+
+    let (status, message) = match result {
+        Ok(done) => done,
+        Err(response) => return Ok(response),
+    };
+
+    // Browsers go back to the listing, where they can see what changed
+    let wants_html = request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+
+    if wants_html {
+        return Ok(create_redirect(
+            StatusCode::SEE_OTHER,
+            &local_location(request.uri().path(), None),
+        ));
+    }
+
+    let body: Vec<u8> = format!("{}: {}", status.as_u16(), message).into();
+    Ok(Response::builder()
+        .status(status)
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::CONTENT_TYPE, "text/plain")
+        .header(header::CONNECTION, "close")
+        .body(Some(body))?)
+}
+
+/// What a successful action responds with, or the response explaining why it wasn't done
+type ActionResult = Result<(StatusCode, String), ByteResponse>;
+
+struct Action<'a> {
+    config: &'a ServerConfig,
+    host: &'a VirtualHost,
+    client_path: &'a Path,
+    principal: &'a Principal,
+    ip: Option<IpAddr>,
+}
+
+impl Action<'_> {
+    async fn mkdir(&self, fields: &HashMap<String, String>) -> io::Result<ActionResult> {
+        let target = match self.new_name(fields) {
+            Ok(target) => target,
+            Err(reason) => return Ok(Err(bad_form(&reason))),
+        };
+
+        if let Some(response) = self.check_new(&target, &Method::POST).await {
+            return Ok(Err(response));
+        }
+
+        fs::create_dir(self.host.root.join(&target)).await?;
+        Ok(Ok((
+            StatusCode::CREATED,
+            format!("'{}' created", rule_path(&target)),
+        )))
+    }
+
+    async fn rename(&self, fields: &HashMap<String, String>) -> io::Result<ActionResult> {
+        let (source, target) = match (self.entry(fields), self.new_name(fields)) {
+            (Ok(source), Ok(target)) => (source, target),
+            (Err(reason), _) | (_, Err(reason)) => return Ok(Err(bad_form(&reason))),
+        };
+
+        self.relocate(&source, &target).await
+    }
+
+    async fn move_to(&self, fields: &HashMap<String, String>) -> io::Result<ActionResult> {
+        let source = match self.entry(fields) {
+            Ok(source) => source,
+            Err(reason) => return Ok(Err(bad_form(&reason))),
+        };
+
+        let to = match fields.get("to").map(|to| to.trim()) {
+            Some(to) if !to.is_empty() => to,
+            _ => return Ok(Err(bad_form(&missing_field("to")))),
+        };
+
+        // Flattened like request paths, so `..` can't leave the data directory
+        let destination = if to.starts_with('/') {
+            flatten_path(to)
+        } else {
+            flatten_path(self.client_path.join(to))
+        };
+
+        if !is_directory(self.host.root.join(&destination)).await {
+            return Ok(Err(create_form_error(
+                StatusCode::NOT_FOUND,
+                &format!("'{}' isn't a directory", rule_path(&destination)),
+            )));
+        }
+
+        // `source` is a single name in this directory, so it always has one
+        let target = destination.join(source.file_name().unwrap_or_default());
+        self.relocate(&source, &target).await
+    }
+
+    async fn delete(&self, fields: &HashMap<String, String>) -> io::Result<ActionResult> {
+        let source = match self.entry(fields) {
+            Ok(source) => source,
+            Err(reason) => return Ok(Err(bad_form(&reason))),
+        };
+
+        if let Some(response) = self.check_existing(&source, &Method::DELETE).await {
+            return Ok(Err(response));
+        }
+
+        let path = self.host.root.join(&source);
+        let removed = if fs::symlink_metadata(&path).await?.is_dir() {
+            fs::remove_dir(&path).await
+        } else {
+            fs::remove_file(&path).await
+        };
+
+        match removed {
+            Ok(()) => Ok(Ok((
+                StatusCode::OK,
+                format!("'{}' deleted", rule_path(&source)),
+            ))),
+            // Deleting everything inside would skip the checks on each of those files
+            Err(e) if e.kind() == io::ErrorKind::DirectoryNotEmpty => Ok(Err(create_form_error(
+                StatusCode::CONFLICT,
+                &format!("'{}' isn't empty", rule_path(&source)),
+            ))),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Rename or move `source` to `target`, which is like deleting one and creating the other
+    async fn relocate(&self, source: &Path, target: &Path) -> io::Result<ActionResult> {
+        if let Some(response) = self.check_existing(source, &Method::DELETE).await {
+            return Ok(Err(response));
+        }
+
+        if target.starts_with(source) {
+            return Ok(Err(bad_form(&format!(
+                "'{}' can't be moved into itself",
+                rule_path(source)
+            ))));
+        }
+
+        if let Some(response) = self.check_new(target, &Method::POST).await {
+            return Ok(Err(response));
+        }
+
+        // Everything inside would move along without the checks on each of those files
+        let path = self.host.root.join(source);
+        if fs::symlink_metadata(&path).await?.is_dir()
+            && fs::read_dir(&path).await?.next_entry().await?.is_some()
+        {
+            return Ok(Err(create_form_error(
+                StatusCode::CONFLICT,
+                &format!("'{}' isn't empty", rule_path(source)),
+            )));
+        }
+
+        // Something could have been put at `target` since it was checked, it's never replaced
+        let moved =
+            rename_no_replace(&self.host.root.join(source), &self.host.root.join(target)).await;
+
+        match moved {
+            Ok(()) => Ok(Ok((
+                StatusCode::OK,
+                format!("'{}' moved to '{}'", rule_path(source), rule_path(target)),
+            ))),
+            Err(e) => Ok(Err(match e.kind() {
+                io::ErrorKind::AlreadyExists => create_form_error(
+                    StatusCode::CONFLICT,
+                    &format!("'{}' already exists", rule_path(target)),
+                ),
+                io::ErrorKind::DirectoryNotEmpty => create_form_error(
+                    StatusCode::CONFLICT,
+                    &format!("'{}' isn't empty", rule_path(source)),
+                ),
+                io::ErrorKind::NotFound => create_form_error(
+                    StatusCode::NOT_FOUND,
+                    &format!("'{}' not found", rule_path(source)),
+                ),
+                io::ErrorKind::PermissionDenied => create_form_error(
+                    StatusCode::FORBIDDEN,
+                    &format!(
+                        "'{}' can't be moved to '{}'",
+                        rule_path(source),
+                        rule_path(target)
+                    ),
+                ),
+                _ => return Err(e),
+            })),
+        }
+    }
+
+    /// The `entry` field, as a path relative to the data directory
+    fn entry(&self, fields: &HashMap<String, String>) -> Result<PathBuf, String> {
+        let entry = fields.get("entry").ok_or_else(|| missing_field("entry"))?;
+        let name = OsStr::from_bytes(&decode_binary(entry.as_bytes())).to_os_string();
+
+        single_component(name.as_os_str())
+            .map(|name| self.client_path.join(name))
+            .ok_or_else(|| invalid_name(entry))
+    }
+
+    /// The `name` field, as a path relative to the data directory
+    fn new_name(&self, fields: &HashMap<String, String>) -> Result<PathBuf, String> {
+        let name = fields.get("name").ok_or_else(|| missing_field("name"))?;
+
+        single_component(OsStr::new(name.trim()))
+            .filter(|_| !name.chars().any(char::is_control))
+            .map(|name| self.client_path.join(name))
+            .ok_or_else(|| invalid_name(name))
+    }
+
+    /// Check `relative` exists and `method` may be used on it
+    async fn check_existing(&self, relative: &Path, method: &Method) -> Option<ByteResponse> {
+        let is_dir = is_directory(self.host.root.join(relative)).await;
+
+        // Hidden files are a 404, as if they weren't there
+        if fs::symlink_metadata(self.host.root.join(relative))
+            .await
+            .is_err()
+            || self
+                .config
+                .hidden
+                .is_hidden(&self.host.root, relative, is_dir)
+                .await
+        {
+            return Some(create_form_error(
+                StatusCode::NOT_FOUND,
+                &format!("'{}' not found", rule_path(relative)),
+            ));
+        }
+
+        self.check_change(relative, method).await
+    }
+
+    /// Check nothing is at `relative` yet and `method` may be used on it
+    async fn check_new(&self, relative: &Path, method: &Method) -> Option<ByteResponse> {
+        if self
+            .config
+            .hidden
+            .is_hidden(&self.host.root, relative, false)
+            .await
+        {
+            return Some(bad_form(&format!(
+                "'{}' isn't an allowed name",
+                rule_path(relative)
+            )));
+        }
+
+        if let Some(response) = self.check_change(relative, method).await {
+            return Some(response);
+        }
+
+        if fs::symlink_metadata(self.host.root.join(relative))
+            .await
+            .is_ok()
+        {
+            return Some(create_form_error(
+                StatusCode::CONFLICT,
+                &format!("'{}' already exists", rule_path(relative)),
+            ));
+        }
+
+        None
+    }
+
+    /// The checks an upload to `relative` gets, with `method` for the access rules and tokens
+    async fn check_change(&self, relative: &Path, method: &Method) -> Option<ByteResponse> {
+        let rule_path = rule_path(relative);
+
+        let allowed = self.config.allowed_methods(self.host, &rule_path);
+        if !allowed.contains(&Method::POST) {
+            return Some(handle_not_allowed(&allowed));
+        }
+
+        if let Some(response) =
+            check_permission(self.config, self.principal, method, &rule_path, self.ip)
+        {
+            return Some(response);
+        }
+
+        if !symlinks_allowed(&self.host.root, relative, self.config.symlinks).await {
+            return Some(create_403(
+                "the path goes through a symlink that isn't allowed",
+            ));
+        }
+
+        None
+    }
+}
+
+/// Parse an `application/x-www-form-urlencoded` body, a later field replaces an earlier one
+pub fn parse_form(body: &[u8]) -> Result<HashMap<String, String>, String> {
+    let body = std::str::from_utf8(body).map_err(|_| "the form isn't UTF-8".to_string())?;
+    let decode_field = |text: &str| {
+        decode(&text.replace('+', " "))
+            .map(|decoded| decoded.into_owned())
+            .map_err(|_| "the form isn't UTF-8".to_string())
+    };
+
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((decode_field(key)?, decode_field(value)?))
+        })
+        .collect()
+}
+
+/// `name` if it's one usable path component, not `..` or something with a `/` in it
+fn single_component(name: &OsStr) -> Option<&OsStr> {
+    let bytes = name.as_bytes();
+
+    if bytes.is_empty() || bytes == b"." || bytes == b".." || bytes.contains(&b'/') {
+        return None;
+    }
+
+    Some(name)
+}
+
+/// The path as rules and responses show it, ie. `/incoming/a.zip`
+fn rule_path(relative: &Path) -> String {
+    format!("/{}", relative.to_string_lossy())
+}
+
+fn missing_field(field: &str) -> String {
+    format!("the form has no '{}' field", field)
+}
+
+fn invalid_name(name: &str) -> String {
+    format!("'{}' isn't a usable name", name)
+}
+
+fn bad_form(reason: &str) -> ByteResponse {
+    create_form_error(StatusCode::BAD_REQUEST, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_forms() {
+        let fields = parse_form(b"action=mkdir&name=new+folder%21").unwrap();
+        assert_eq!(fields["action"], "mkdir");
+        assert_eq!(fields["name"], "new folder!");
+
+        let fields = parse_form(b"entry=a%2Bb%23c%3F.txt&to=%2Fdocs&&flag").unwrap();
+        assert_eq!(fields["entry"], "a+b#c?.txt");
+        assert_eq!(fields["to"], "/docs");
+        assert_eq!(fields["flag"], "");
+        assert_eq!(fields.len(), 3);
+
+        assert_eq!(parse_form(b"name=a&name=b").unwrap()["name"], "b");
+        assert!(parse_form(b"").unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_forms_that_arent_utf8() {
+        assert!(parse_form(b"name=caf\xe9").is_err());
+        assert!(parse_form(b"name=caf%E9").is_err());
+    }
+
+    #[test]
+    fn takes_single_components() {
+        assert_eq!(
+            single_component(OsStr::new("a.txt")),
+            Some(OsStr::new("a.txt"))
+        );
+        assert_eq!(single_component(OsStr::new("..a")), Some(OsStr::new("..a")));
+        assert_eq!(
+            single_component(OsStr::from_bytes(b"caf\xe9")),
+            Some(OsStr::from_bytes(b"caf\xe9"))
+        );
+
+        for name in ["", ".", "..", "a/b", "/", "../x"] {
+            assert_eq!(single_component(OsStr::new(name)), None, "{:?}", name);
+        }
+    }
+}
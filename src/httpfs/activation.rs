@@ -0,0 +1,161 @@
+use std::{
+    env,
+    io::{self, ErrorKind},
+    os::unix::io::{FromRawFd, RawFd},
+    path::PathBuf,
+};
+
+use tokio::net::{TcpListener, UnixListener};
+
+use crate::httpfs::listener::Listener;
+
+/// The first fd passed by systemd, 0-2 are stdin/stdout/stderr
+/// https://www.freedesktop.org/software/systemd/man/sd_listen_fds.html
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Find the listening sockets systemd passed us with `LISTEN_FDS`/`LISTEN_PID`
+///
+/// Returns an empty list when we weren't socket activated, or when the variables were meant for
+/// another process (`LISTEN_PID` is not us), which happens if our parent was activated and didn't
+/// clean up its environment
+///
+/// The variables are removed either way, so processes we spawn don't think the sockets are theirs
+pub fn systemd_listen_fds() -> io::Result<Vec<RawFd>> {
+    let pid = match env::var("LISTEN_PID") {
+        Ok(pid) => pid,
+        Err(_) => return Ok(vec![]),
+    };
+    let fds = env::var("LISTEN_FDS");
+
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+
+    if pid.trim().parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(vec![]);
+    }
+
+    let count: RawFd = fds
+        .ok()
+        .and_then(|fds| fds.trim().parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "LISTEN_PID is set but LISTEN_FDS is missing or invalid",
+            )
+        })?;
+
+    Ok((SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count).collect())
+}
+
+/// Take ownership of an inherited listening socket, figuring out if it's TCP or Unix
+///
+/// The fd must not be used by anything else afterwards, the returned listener closes it on drop
+pub fn adopt_listener(fd: RawFd) -> io::Result<Listener> {
+    if !is_listening_socket(fd)? {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("fd {} is not a listening socket", fd),
+        ));
+    }
+
+    // Don't leak the socket into anything we might spawn
+    // SAFETY: fcntl on an fd we own, with no pointers involved
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    match socket_family(fd)? {
+        libc::AF_INET | libc::AF_INET6 => {
+            // SAFETY: we checked it's a listening inet socket, and the caller gives up the fd
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+        }
+        libc::AF_UNIX => {
+            // SAFETY: we checked it's a listening unix socket, and the caller gives up the fd
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+
+            let path = listener
+                .local_addr()?
+                .as_pathname()
+                .map(|p| p.to_path_buf())
+                .unwrap_or_else(|| PathBuf::from(format!("<fd {}>", fd)));
+
+            Ok(Listener::Unix(UnixListener::from_std(listener)?, path))
+        }
+        family => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("fd {} has unsupported socket family {}", fd, family),
+        )),
+    }
+}
+
+fn is_listening_socket(fd: RawFd) -> io::Result<bool> {
+    let mut accepting: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    // SAFETY: accepting and len are valid for writes and len is the size of accepting
+    let status = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut accepting as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if status == -1 {
+        let e = io::Error::last_os_error();
+        // Not a socket at all (or not even open) is just "no", anything else is a real error
+        return match e.raw_os_error() {
+            Some(libc::ENOTSOCK) | Some(libc::EBADF) => Ok(false),
+            _ => Err(e),
+        };
+    }
+
+    Ok(accepting != 0)
+}
+
+fn socket_family(fd: RawFd) -> io::Result<libc::c_int> {
+    // SAFETY: sockaddr_storage is plain data, all zeroes is a valid value
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+    // SAFETY: addr and len are valid for writes and len is the size of addr
+    let status = unsafe {
+        libc::getsockname(
+            fd,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+
+    if status == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(addr.ss_family as libc::c_int)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_the_variables_once_read() {
+        for pid in [std::process::id(), 1] {
+            env::set_var("LISTEN_PID", pid.to_string());
+            env::set_var("LISTEN_FDS", "0");
+            env::set_var("LISTEN_FDNAMES", "");
+
+            assert_eq!(systemd_listen_fds().unwrap(), Vec::<RawFd>::new());
+
+            for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+                assert!(env::var_os(name).is_none(), "{} is still set", name);
+            }
+        }
+    }
+}
@@ -0,0 +1,684 @@
+use std::{collections::HashMap, fmt, io, path::Path, sync::Arc};
+
+use argon2::{
+    password_hash::SaltString, Algorithm, Argon2, Params, PasswordHash, PasswordHasher,
+    PasswordVerifier, Version,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::{header, Response};
+use pwhash::{
+    bcrypt::{BcryptSetup, BcryptVariant},
+    HashSetup,
+};
+
+use crate::httpfs::{
+    message::{ByteRequest, ByteResponse},
+    tokens::{TokenGrant, TokenStore},
+};
+
+/// Who a request was made by
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Principal {
+    Anonymous,
+    /// Logged in with Basic auth
+    User(String),
+    /// Sent a valid `Authorization: Bearer` token
+    Token(TokenGrant),
+    /// Used a signed share link, which only works for one path
+    ShareLink,
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Principal::Anonymous => write!(f, "-"),
+            Principal::User(name) => write!(f, "{}", name),
+            Principal::Token(grant) => write!(f, "token:{}", grant.name),
+            Principal::ShareLink => write!(f, "share-link"),
+        }
+    }
+}
+
+/// Why a request could not be authenticated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    /// Credentials were sent, but they're wrong
+    InvalidCredentials,
+    /// A token was sent, but it's unknown, revoked or expired
+    InvalidToken,
+    /// The Authorization header uses a scheme that isn't enabled
+    UnsupportedScheme,
+    /// The Authorization header couldn't be understood
+    MalformedHeader,
+}
+
+/// Users and password hashes loaded from a htpasswd-style file
+///
+/// ```text
+/// # comments and blank lines are ignored
+/// alice:$2y$05$...              (bcrypt, `htpasswd -B`)
+/// bob:$6$rounds=5000$...         (sha512-crypt, `mkpasswd -m sha-512`)
+/// carol:$argon2id$v=19$...       (argon2)
+/// ```
+#[derive(Debug)]
+pub struct Credentials {
+    users: HashMap<String, String>,
+    /// Hash we check against for unknown users, so they take as long as known ones
+    dummy_hash: String,
+}
+
+impl Credentials {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read credentials {}: {}", path.display(), e),
+            )
+        })?;
+
+        let mut users = HashMap::new();
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |reason: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {}", path.display(), number + 1, reason),
+                )
+            };
+
+            let (user, hash) = line
+                .split_once(':')
+                .ok_or_else(|| invalid("expected USER:HASH"))?;
+
+            if user.is_empty() {
+                return Err(invalid("user name is empty"));
+            }
+
+            if HashAlgorithm::detect(hash).is_none() {
+                return Err(invalid(
+                    "unsupported hash, use bcrypt ($2y$), sha-crypt ($5$/$6$) or argon2 ($argon2id$)",
+                ));
+            }
+
+            users.insert(user.to_string(), hash.to_string());
+        }
+
+        let dummy_hash = match most_common_setup(users.values()) {
+            Some(like) => dummy_hash(like).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: unsupported hash parameters", path.display()),
+                )
+            })?,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} has no users", path.display()),
+                ))
+            }
+        };
+
+        Ok(Self { users, dummy_hash })
+    }
+
+    /// Check a username and password
+    ///
+    /// Hash checks are slow on purpose, so call this from a blocking task
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        let (known, hash) = match self.users.get(user) {
+            Some(hash) => (true, hash),
+            None => (false, &self.dummy_hash),
+        };
+
+        // Always verify something, so response times don't reveal which users exist
+        let matches = verify_hash(password, hash);
+        known && matches
+    }
+}
+
+enum HashAlgorithm {
+    Bcrypt,
+    Sha256Crypt,
+    Sha512Crypt,
+    Argon2,
+}
+
+impl HashAlgorithm {
+    fn detect(hash: &str) -> Option<Self> {
+        if ["$2a$", "$2b$", "$2y$"].iter().any(|p| hash.starts_with(p)) {
+            Some(HashAlgorithm::Bcrypt)
+        } else if hash.starts_with("$5$") {
+            Some(HashAlgorithm::Sha256Crypt)
+        } else if hash.starts_with("$6$") {
+            Some(HashAlgorithm::Sha512Crypt)
+        } else if hash.starts_with("$argon2") {
+            Some(HashAlgorithm::Argon2)
+        } else {
+            None
+        }
+    }
+}
+
+/// The part of a hash that decides how long checking it takes, the scheme and its cost
+fn hash_setup(hash: &str) -> &str {
+    let end = match HashAlgorithm::detect(hash) {
+        // `$2y$05$`
+        Some(HashAlgorithm::Bcrypt) => 7,
+        // `$6$` or `$6$rounds=5000$`
+        Some(HashAlgorithm::Sha256Crypt | HashAlgorithm::Sha512Crypt) => {
+            if hash[3..].starts_with("rounds=") {
+                hash[3..].find('$').map_or(hash.len(), |i| i + 4)
+            } else {
+                3
+            }
+        }
+        // `$argon2id$v=19$m=19456,t=2,p=1`, everything before the salt and hash
+        Some(HashAlgorithm::Argon2) => hash
+            .rmatch_indices('$')
+            .nth(1)
+            .map_or(hash.len(), |(i, _)| i),
+        None => hash.len(),
+    };
+    hash.get(..end).unwrap_or(hash)
+}
+
+/// A hash using the scheme and cost most of the users have
+fn most_common_setup<'a>(hashes: impl Iterator<Item = &'a String>) -> Option<&'a str> {
+    let mut counts: HashMap<&str, (usize, &str)> = HashMap::new();
+
+    for hash in hashes {
+        counts.entry(hash_setup(hash)).or_insert((0, hash)).0 += 1;
+    }
+
+    counts
+        .into_iter()
+        .max_by(|(a, (a_count, _)), (b, (b_count, _))| a_count.cmp(b_count).then(b.cmp(a)))
+        .map(|(_, (_, hash))| hash)
+}
+
+/// Hash a random password the same way as `like`, with a fresh salt
+///
+/// Unknown users are checked against this, so they take as long as the typical known user
+fn dummy_hash(like: &str) -> Option<String> {
+    let mut password = [0u8; 16];
+    getrandom::getrandom(&mut password).ok()?;
+    let password = STANDARD.encode(password);
+
+    match HashAlgorithm::detect(like)? {
+        HashAlgorithm::Bcrypt => {
+            let variant = match like.get(..4)? {
+                "$2a$" => BcryptVariant::V2a,
+                "$2b$" => BcryptVariant::V2b,
+                _ => BcryptVariant::V2y,
+            };
+            let setup = BcryptSetup {
+                salt: None,
+                cost: Some(like.get(4..6)?.parse().ok()?),
+                variant: Some(variant),
+            };
+            pwhash::bcrypt::hash_with(setup, password).ok()
+        }
+        HashAlgorithm::Sha256Crypt | HashAlgorithm::Sha512Crypt => {
+            let rounds = hash_setup(like)
+                .get(3..)?
+                .strip_prefix("rounds=")
+                .map(|r| r.trim_end_matches('$').parse())
+                .transpose()
+                .ok()?;
+            let setup = HashSetup { salt: None, rounds };
+            if like.starts_with("$5$") {
+                // Deprecated for new passwords, but the users' hashes already use it
+                #[allow(deprecated)]
+                pwhash::sha256_crypt::hash_with(setup, password).ok()
+            } else {
+                pwhash::sha512_crypt::hash_with(setup, password).ok()
+            }
+        }
+        HashAlgorithm::Argon2 => {
+            let parsed = PasswordHash::new(like).ok()?;
+            let algorithm = Algorithm::try_from(parsed.algorithm).ok()?;
+            let version = match parsed.version {
+                Some(version) => Version::try_from(version).ok()?,
+                None => Version::default(),
+            };
+            let params = Params::try_from(&parsed).ok()?;
+
+            let mut salt = [0u8; 16];
+            getrandom::getrandom(&mut salt).ok()?;
+            let salt = SaltString::encode_b64(&salt).ok()?;
+
+            Argon2::new(algorithm, version, params)
+                .hash_password(password.as_bytes(), &salt)
+                .ok()
+                .map(|hash| hash.to_string())
+        }
+    }
+}
+
+/// Check a password against a hash, every algorithm compares the result in constant time
+fn verify_hash(password: &str, hash: &str) -> bool {
+    match HashAlgorithm::detect(hash) {
+        Some(HashAlgorithm::Bcrypt) => pwhash::bcrypt::verify(password, hash),
+        Some(HashAlgorithm::Sha256Crypt) => pwhash::sha256_crypt::verify(password, hash),
+        Some(HashAlgorithm::Sha512Crypt) => pwhash::sha512_crypt::verify(password, hash),
+        Some(HashAlgorithm::Argon2) => match PasswordHash::new(hash) {
+            Ok(parsed) => Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok(),
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+/// The credentials sent in an Authorization header
+pub enum Authorization {
+    /// `Basic base64(user:password)`
+    Basic(String, String),
+    /// `Bearer TOKEN`
+    Bearer(String),
+}
+
+/// Parse a `Authorization: Basic ...` or `Authorization: Bearer ...` header
+///
+/// Returns `Ok(None)` if there's no Authorization header at all
+pub fn parse_authorization(request: &ByteRequest) -> Result<Option<Authorization>, AuthError> {
+    let value = match request.headers().get(header::AUTHORIZATION) {
+        Some(value) => value.to_str().map_err(|_| AuthError::MalformedHeader)?,
+        None => return Ok(None),
+    };
+
+    let (scheme, credentials) = value
+        .trim()
+        .split_once(' ')
+        .ok_or(AuthError::MalformedHeader)?;
+    let credentials = credentials.trim();
+
+    if scheme.eq_ignore_ascii_case("bearer") {
+        return Ok(Some(Authorization::Bearer(credentials.to_string())));
+    }
+
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return Err(AuthError::UnsupportedScheme);
+    }
+
+    let decoded = STANDARD
+        .decode(credentials)
+        .map_err(|_| AuthError::MalformedHeader)?;
+    let decoded = String::from_utf8(decoded).map_err(|_| AuthError::MalformedHeader)?;
+
+    let (user, password) = decoded.split_once(':').ok_or(AuthError::MalformedHeader)?;
+
+    Ok(Some(Authorization::Basic(
+        user.to_string(),
+        password.to_string(),
+    )))
+}
+
+/// Everything requests can authenticate against, Basic auth users and/or API tokens
+#[derive(Debug)]
+pub struct Authenticator {
+    pub credentials: Option<Arc<Credentials>>,
+    pub tokens: Option<TokenStore>,
+    /// Realm shown by browsers when asking for a password
+    pub realm: String,
+}
+
+impl Authenticator {
+    /// Check the request's credentials, if any
+    pub async fn authenticate(&self, request: &ByteRequest) -> Result<Principal, AuthError> {
+        match parse_authorization(request)? {
+            None => Ok(Principal::Anonymous),
+            Some(Authorization::Basic(user, password)) => {
+                let credentials = self
+                    .credentials
+                    .clone()
+                    .ok_or(AuthError::UnsupportedScheme)?;
+
+                let checked_user = user.clone();
+                let valid = tokio::task::spawn_blocking(move || {
+                    credentials.verify(&checked_user, &password)
+                })
+                .await
+                .unwrap_or(false);
+
+                if valid {
+                    Ok(Principal::User(user))
+                } else {
+                    Err(AuthError::InvalidCredentials)
+                }
+            }
+            Some(Authorization::Bearer(token)) => {
+                let tokens = self.tokens.as_ref().ok_or(AuthError::UnsupportedScheme)?;
+
+                tokens
+                    .verify(&token)
+                    .map(Principal::Token)
+                    .ok_or(AuthError::InvalidToken)
+            }
+        }
+    }
+
+    /// A 401 asking for whichever kinds of credentials are enabled
+    pub fn challenge(&self, error: Option<AuthError>) -> ByteResponse {
+        let realm = self.realm.replace('"', "");
+        let mut challenges = vec![];
+
+        if self.credentials.is_some() {
+            challenges.push(format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm));
+        }
+
+        if self.tokens.is_some() {
+            challenges.push(match error {
+                Some(AuthError::InvalidToken) => {
+                    format!("Bearer realm=\"{}\", error=\"invalid_token\"", realm)
+                }
+                _ => format!("Bearer realm=\"{}\"", realm),
+            });
+        }
+
+        create_401(challenges, error)
+    }
+}
+
+fn create_401(challenges: Vec<String>, error: Option<AuthError>) -> ByteResponse {
+    let message = match error {
+        Some(AuthError::InvalidCredentials) => "Invalid username or password",
+        Some(AuthError::InvalidToken) => "Invalid, expired or revoked token",
+        Some(AuthError::UnsupportedScheme) => "Unsupported Authorization scheme",
+        Some(AuthError::MalformedHeader) => "Malformed Authorization header",
+        None => "Authentication required",
+    };
+
+    let body: Vec<u8> = format!("401: {}", message).into();
+
+    let mut response = Response::builder().status(401);
+
+    for challenge in challenges {
+        response = response.header(header::WWW_AUTHENTICATE, challenge);
+    }
+
+    response
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::CONTENT_TYPE, "text/plain")
+        .header(header::CONNECTION, "close")
+        .body(Some(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use http::Request;
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    fn bcrypt(password: &str, cost: u32) -> String {
+        let setup = BcryptSetup {
+            salt: None,
+            cost: Some(cost),
+            variant: Some(BcryptVariant::V2y),
+        };
+        pwhash::bcrypt::hash_with(setup, password).unwrap()
+    }
+
+    fn sha512_crypt(password: &str, rounds: u32) -> String {
+        let setup = HashSetup {
+            salt: None,
+            rounds: Some(rounds),
+        };
+        pwhash::sha512_crypt::hash_with(setup, password).unwrap()
+    }
+
+    fn argon2(password: &str) -> String {
+        let params = Params::new(1024, 1, 1, None).unwrap();
+        let salt = SaltString::encode_b64(b"0123456789abcdef").unwrap();
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(password.as_bytes(), &salt)
+            .unwrap()
+            .to_string()
+    }
+
+    fn credentials_file(content: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    fn request(authorization: Option<&str>) -> ByteRequest {
+        let mut request = Request::builder().uri("/");
+        if let Some(value) = authorization {
+            request = request.header(header::AUTHORIZATION, value);
+        }
+        request.body(None).unwrap()
+    }
+
+    #[test]
+    fn verifies_every_hash_scheme() {
+        #[allow(deprecated)]
+        let sha256 = pwhash::sha256_crypt::hash("secret").unwrap();
+        let hashes = [
+            bcrypt("secret", 4),
+            sha256,
+            sha512_crypt("secret", 1000),
+            argon2("secret"),
+        ];
+
+        for hash in &hashes {
+            assert!(verify_hash("secret", hash), "{}", hash);
+            assert!(!verify_hash("wrong", hash), "{}", hash);
+        }
+
+        assert!(!verify_hash("secret", "$1$md5$unsupported"));
+        assert!(!verify_hash("secret", "$argon2id$broken"));
+    }
+
+    #[test]
+    fn loads_users_from_a_file() {
+        let file = credentials_file(&format!(
+            "# users\n\nalice:{}\n  bob:{}  \ncarol:{}\n",
+            bcrypt("alice-password", 4),
+            sha512_crypt("bob-password", 1000),
+            argon2("carol-password"),
+        ));
+        let credentials = Credentials::load(file.path()).unwrap();
+
+        assert!(credentials.verify("alice", "alice-password"));
+        assert!(credentials.verify("bob", "bob-password"));
+        assert!(credentials.verify("carol", "carol-password"));
+        assert!(!credentials.verify("alice", "bob-password"));
+        assert!(!credentials.verify("dave", "alice-password"));
+    }
+
+    #[test]
+    fn refuses_broken_credential_files() {
+        let hash = bcrypt("secret", 4);
+
+        for content in [
+            "alice".to_string(),
+            format!(":{}", hash),
+            "alice:$1$md5$unsupported".to_string(),
+            "# nobody here\n".to_string(),
+        ] {
+            let file = credentials_file(&content);
+            let error = Credentials::load(file.path()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", content);
+        }
+
+        let error = Credentials::load("/nonexistent/httpfs-users").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn checks_unknown_users_against_the_most_common_setup() {
+        let file = credentials_file(&format!(
+            "alice:{}\nbob:{}\ncarol:{}\n",
+            sha512_crypt("secret", 1000),
+            bcrypt("secret", 5),
+            bcrypt("secret", 5),
+        ));
+        let credentials = Credentials::load(file.path()).unwrap();
+
+        assert!(credentials.dummy_hash.starts_with("$2y$05$"));
+        assert!(!credentials
+            .users
+            .values()
+            .any(|h| *h == credentials.dummy_hash));
+        assert!(!credentials.verify("dave", "secret"));
+    }
+
+    #[test]
+    fn derives_dummy_hashes_for_every_scheme() {
+        let sha = sha512_crypt("secret", 1000);
+        let dummy = dummy_hash(&sha).unwrap();
+        assert!(dummy.starts_with("$6$rounds=1000$"), "{}", dummy);
+        assert_ne!(hash_setup(&sha), sha);
+        assert_eq!(hash_setup(&dummy), hash_setup(&sha));
+
+        #[allow(deprecated)]
+        let sha256 = pwhash::sha256_crypt::hash("secret").unwrap();
+        let dummy = dummy_hash(&sha256).unwrap();
+        assert_eq!(hash_setup(&dummy), "$5$");
+
+        let argon = argon2("secret");
+        let dummy = dummy_hash(&argon).unwrap();
+        assert_eq!(hash_setup(&dummy), "$argon2id$v=19$m=1024,t=1,p=1");
+        assert_ne!(dummy, argon);
+    }
+
+    #[test]
+    fn parses_authorization_headers() {
+        assert!(matches!(parse_authorization(&request(None)), Ok(None)));
+
+        let basic = format!("Basic {}", STANDARD.encode("alice:pass:word"));
+        match parse_authorization(&request(Some(&basic))) {
+            Ok(Some(Authorization::Basic(user, password))) => {
+                assert_eq!(user, "alice");
+                assert_eq!(password, "pass:word");
+            }
+            _ => panic!("expected Basic credentials"),
+        }
+
+        let lowercase = format!("basic  {} ", STANDARD.encode("bob:secret"));
+        assert!(matches!(
+            parse_authorization(&request(Some(&lowercase))),
+            Ok(Some(Authorization::Basic(..)))
+        ));
+
+        match parse_authorization(&request(Some("Bearer httpfs_aaaa_secret"))) {
+            Ok(Some(Authorization::Bearer(token))) => assert_eq!(token, "httpfs_aaaa_secret"),
+            _ => panic!("expected a Bearer token"),
+        }
+
+        let no_colon = format!("Basic {}", STANDARD.encode("alice"));
+        for (value, expected) in [
+            ("Digest username=\"alice\"", AuthError::UnsupportedScheme),
+            ("Basic", AuthError::MalformedHeader),
+            ("Basic not*base64", AuthError::MalformedHeader),
+            (no_colon.as_str(), AuthError::MalformedHeader),
+        ] {
+            assert_eq!(
+                parse_authorization(&request(Some(value))).err(),
+                Some(expected),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn authenticates_requests() {
+        let file = credentials_file(&format!("alice:{}\n", bcrypt("secret", 4)));
+        let authenticator = Authenticator {
+            credentials: Some(Arc::new(Credentials::load(file.path()).unwrap())),
+            tokens: None,
+            realm: "httpfs".to_string(),
+        };
+
+        let basic = |credentials: &str| format!("Basic {}", STANDARD.encode(credentials));
+
+        assert_eq!(
+            authenticator.authenticate(&request(None)).await,
+            Ok(Principal::Anonymous)
+        );
+        assert_eq!(
+            authenticator
+                .authenticate(&request(Some(&basic("alice:secret"))))
+                .await,
+            Ok(Principal::User("alice".to_string()))
+        );
+        assert_eq!(
+            authenticator
+                .authenticate(&request(Some(&basic("alice:wrong"))))
+                .await,
+            Err(AuthError::InvalidCredentials)
+        );
+        assert_eq!(
+            authenticator
+                .authenticate(&request(Some("Bearer httpfs_aaaa_secret")))
+                .await,
+            Err(AuthError::UnsupportedScheme)
+        );
+    }
+
+    #[test]
+    fn challenges_for_enabled_schemes() {
+        let users = credentials_file(&format!("alice:{}\n", bcrypt("secret", 4)));
+        let tokens = NamedTempFile::new().unwrap();
+        let authenticator = Authenticator {
+            credentials: Some(Arc::new(Credentials::load(users.path()).unwrap())),
+            tokens: Some(TokenStore::load(tokens.path()).unwrap()),
+            realm: "my \"files\"".to_string(),
+        };
+
+        let challenges = |response: &ByteResponse| {
+            response
+                .headers()
+                .get_all(header::WWW_AUTHENTICATE)
+                .iter()
+                .map(|v| v.to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let response = authenticator.challenge(None);
+        assert_eq!(response.status(), 401);
+        assert_eq!(
+            challenges(&response),
+            [
+                "Basic realm=\"my files\", charset=\"UTF-8\"",
+                "Bearer realm=\"my files\""
+            ]
+        );
+        assert_eq!(
+            response.body().as_deref(),
+            Some(&b"401: Authentication required"[..])
+        );
+
+        let response = authenticator.challenge(Some(AuthError::InvalidToken));
+        assert_eq!(
+            challenges(&response)[1],
+            "Bearer realm=\"my files\", error=\"invalid_token\""
+        );
+        assert_eq!(
+            response.body().as_deref(),
+            Some(&b"401: Invalid, expired or revoked token"[..])
+        );
+
+        let basic_only = Authenticator {
+            tokens: None,
+            ..authenticator
+        };
+        assert_eq!(
+            challenges(&basic_only.challenge(Some(AuthError::InvalidCredentials))),
+            ["Basic realm=\"my files\", charset=\"UTF-8\""]
+        );
+    }
+}
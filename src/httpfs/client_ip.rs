@@ -0,0 +1,379 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
+
+use clap::ValueEnum;
+use http::{header, HeaderMap};
+use ipnet::IpNet;
+
+use crate::httpfs::{acl::parse_ip_range, listener::PeerAddr};
+
+/// `--allow-ip` and `--deny-ip` ranges
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    /// If not empty, only these ranges may connect
+    pub allow: Vec<IpNet>,
+    /// These ranges may never connect, even if they're allowed
+    pub deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Which header a trusted proxy puts the client's address in
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For: client, proxy1, proxy2`
+    XForwardedFor,
+    /// `Forwarded: for=client, for=proxy1`, RFC 7239
+    Forwarded,
+}
+
+/// A `--trusted-proxy`, either an IP range or every Unix socket connection
+#[derive(Debug, Clone)]
+pub enum TrustedProxy {
+    Range(IpNet),
+    Unix,
+}
+
+/// Parse `10.0.0.0/8`, `10.1.2.3` or `unix`
+pub fn parse_trusted_proxy(arg: &str) -> Result<TrustedProxy, String> {
+    match arg {
+        "unix" => Ok(TrustedProxy::Unix),
+        _ => parse_ip_range(arg).map(TrustedProxy::Range).ok_or_else(|| {
+            format!(
+                "Expected an IP range like 10.0.0.0/8 or unix, got '{}'",
+                arg
+            )
+        }),
+    }
+}
+
+/// Proxies whose forwarding header is believed
+///
+/// Only the addresses proxies we trust added can be believed, anything before them could have
+/// been sent by the client. So the header is read right to left, skipping trusted proxies, and
+/// the first address that isn't one is the client
+#[derive(Debug)]
+pub struct TrustedProxies {
+    pub proxies: Vec<TrustedProxy>,
+    pub header: ForwardedHeader,
+}
+
+impl TrustedProxies {
+    fn trusts_peer(&self, peer: &PeerAddr) -> bool {
+        match peer.ip() {
+            Some(ip) => self.trusts(ip),
+            None => self
+                .proxies
+                .iter()
+                .any(|proxy| matches!(proxy, TrustedProxy::Unix)),
+        }
+    }
+
+    pub fn trusts(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+        self.proxies
+            .iter()
+            .any(|proxy| matches!(proxy, TrustedProxy::Range(net) if net.contains(&ip)))
+    }
+
+    /// The client address from the forwarding header, if there is one we can believe
+    fn forwarded_ip(&self, headers: &HeaderMap) -> Option<IpAddr> {
+        let hops = match self.header {
+            ForwardedHeader::XForwardedFor => header_list(headers, "x-forwarded-for")
+                .map(|hop| parse_hop(&hop))
+                .collect::<Vec<_>>(),
+            ForwardedHeader::Forwarded => header_list(headers, header::FORWARDED.as_str())
+                .map(|element| forwarded_for(&element).and_then(|hop| parse_hop(&hop)))
+                .collect(),
+        };
+
+        let mut client = None;
+
+        for hop in hops.into_iter().rev() {
+            match hop {
+                // Keep going past our own proxies, the one before them is the client
+                Some(ip) if self.trusts(ip) => client = Some(ip),
+                Some(ip) => return Some(ip),
+                // Can't tell who added this, so only the proxy after it can be believed
+                None => break,
+            }
+        }
+
+        client
+    }
+}
+
+/// The address a request came from, which is the peer's unless a trusted proxy forwarded it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp {
+    /// Unknown for Unix socket connections that weren't forwarded
+    pub ip: Option<IpAddr>,
+    /// Whether the address came from a proxy's forwarding header
+    pub forwarded: bool,
+}
+
+impl ClientIp {
+    pub fn resolve(peer: &PeerAddr, headers: &HeaderMap, proxies: Option<&TrustedProxies>) -> Self {
+        let forwarded = proxies
+            .filter(|proxies| proxies.trusts_peer(peer))
+            .and_then(|proxies| proxies.forwarded_ip(headers));
+
+        match forwarded {
+            Some(ip) => ClientIp {
+                ip: Some(canonical(ip)),
+                forwarded: true,
+            },
+            None => ClientIp {
+                ip: peer.ip().map(canonical),
+                forwarded: false,
+            },
+        }
+    }
+}
+
+impl fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ip {
+            Some(ip) => write!(f, "{}", ip),
+            None => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Whether a connection from `peer` may be served at all
+///
+/// Trusted proxies always may, the client they forward for is checked once the request is read
+pub fn accepts_peer(peer: &PeerAddr, filter: &IpFilter, proxies: Option<&TrustedProxies>) -> bool {
+    match peer.ip() {
+        Some(ip) => filter.permits(ip) || proxies.is_some_and(|proxies| proxies.trusts(ip)),
+        // Unix sockets are limited by their file permissions instead
+        None => true,
+    }
+}
+
+/// Every comma separated element of every `name` header, in order
+fn header_list<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = String> + 'a {
+    headers
+        .get_all(name)
+        .into_iter()
+        .flat_map(|value| {
+            // A value that isn't text can't be believed, keep it as a hop that won't parse
+            let value = value.to_str().unwrap_or("?");
+            value
+                .split(',')
+                .map(|v| v.trim().to_string())
+                .collect::<Vec<_>>()
+        })
+        .filter(|v| !v.is_empty())
+}
+
+/// The `for` parameter of a `Forwarded` element, like `for="[2001:db8::1]:4711";proto=https`
+fn forwarded_for(element: &str) -> Option<String> {
+    element.split(';').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("for")
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+/// A hop's address, with or without a port
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    hop.parse::<IpAddr>()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| hop.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()
+}
+
+/// IPv4 clients on a dual stack socket show up as `::ffff:1.2.3.4`, match them as IPv4
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header::HeaderName, HeaderValue};
+
+    use super::*;
+
+    fn proxies(header: ForwardedHeader) -> TrustedProxies {
+        TrustedProxies {
+            proxies: vec![
+                TrustedProxy::Range("10.0.0.0/8".parse().unwrap()),
+                TrustedProxy::Unix,
+            ],
+            header,
+        }
+    }
+
+    fn headers(name: &str, values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    fn resolve(peer: &str, headers: &HeaderMap, proxies: &TrustedProxies) -> Option<IpAddr> {
+        let peer = PeerAddr::Tcp(peer.parse().unwrap());
+        ClientIp::resolve(&peer, headers, Some(proxies)).ip
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_hops() {
+        assert_eq!(parse_hop("203.0.113.9"), ip("203.0.113.9"));
+        assert_eq!(parse_hop("203.0.113.9:4711"), ip("203.0.113.9"));
+        assert_eq!(parse_hop("[2001:db8::1]"), ip("2001:db8::1"));
+        assert_eq!(parse_hop("[2001:db8::1]:4711"), ip("2001:db8::1"));
+        assert_eq!(parse_hop("unknown"), None);
+        assert_eq!(parse_hop("_hidden"), None);
+    }
+
+    #[test]
+    fn takes_the_rightmost_untrusted_hop() {
+        let proxies = proxies(ForwardedHeader::XForwardedFor);
+
+        // The client made up the first entry, the proxy appended the address it really saw
+        let spoofed = headers("x-forwarded-for", &["1.1.1.1, 203.0.113.9"]);
+        assert_eq!(
+            resolve("10.0.0.1:80", &spoofed, &proxies),
+            ip("203.0.113.9")
+        );
+
+        // Through two of our proxies, and over several headers
+        let chained = headers("x-forwarded-for", &["1.1.1.1, 203.0.113.9", "10.0.0.2"]);
+        assert_eq!(
+            resolve("10.0.0.1:80", &chained, &proxies),
+            ip("203.0.113.9")
+        );
+
+        // Nothing but our own proxies, the first of them is as far back as we can see
+        let internal = headers("x-forwarded-for", &["10.0.0.3, 10.0.0.2"]);
+        assert_eq!(resolve("10.0.0.1:80", &internal, &proxies), ip("10.0.0.3"));
+
+        // An address that doesn't parse could have come from anyone
+        let garbage = headers("x-forwarded-for", &["203.0.113.9, nonsense, 10.0.0.2"]);
+        assert_eq!(resolve("10.0.0.1:80", &garbage, &proxies), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let proxies = proxies(ForwardedHeader::XForwardedFor);
+        let spoofed = headers("x-forwarded-for", &["203.0.113.9"]);
+
+        let client = ClientIp::resolve(
+            &PeerAddr::Tcp("198.51.100.7:80".parse().unwrap()),
+            &spoofed,
+            Some(&proxies),
+        );
+        assert_eq!(
+            client,
+            ClientIp {
+                ip: ip("198.51.100.7"),
+                forwarded: false
+            }
+        );
+
+        // Without --trusted-proxy nothing is believed
+        let peer = PeerAddr::Tcp("10.0.0.1:80".parse().unwrap());
+        assert_eq!(ClientIp::resolve(&peer, &spoofed, None).ip, ip("10.0.0.1"));
+
+        // The other header isn't looked at
+        let forwarded = headers("forwarded", &["for=203.0.113.9"]);
+        assert_eq!(resolve("10.0.0.1:80", &forwarded, &proxies), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn reads_forwarded_headers() {
+        let proxies = proxies(ForwardedHeader::Forwarded);
+
+        let quoted = headers(
+            "forwarded",
+            &["for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.2"],
+        );
+        assert_eq!(resolve("10.0.0.1:80", &quoted, &proxies), ip("2001:db8::1"));
+
+        let case = headers("forwarded", &["proto=http;For=203.0.113.9"]);
+        assert_eq!(resolve("10.0.0.1:80", &case, &proxies), ip("203.0.113.9"));
+
+        // Unknown and obfuscated nodes hide who is behind them, so stop at the proxy after them
+        for hidden in ["for=unknown", "for=_hidden", "for=\"_hidden:_port\""] {
+            let forwarded = headers("forwarded", &[&format!("{}, for=10.0.0.2", hidden)]);
+            assert_eq!(
+                resolve("10.0.0.1:80", &forwarded, &proxies),
+                ip("10.0.0.2"),
+                "{}",
+                hidden
+            );
+
+            let forwarded = headers("forwarded", &[hidden]);
+            assert_eq!(
+                resolve("10.0.0.1:80", &forwarded, &proxies),
+                ip("10.0.0.1"),
+                "{}",
+                hidden
+            );
+        }
+    }
+
+    #[test]
+    fn matches_v4_mapped_addresses_as_ipv4() {
+        let filter = IpFilter {
+            allow: vec!["192.0.2.0/24".parse().unwrap()],
+            deny: vec!["192.0.2.66/32".parse().unwrap()],
+        };
+
+        assert!(filter.permits("::ffff:192.0.2.1".parse().unwrap()));
+        assert!(!filter.permits("::ffff:192.0.2.66".parse().unwrap()));
+        assert!(!filter.permits("::ffff:198.51.100.1".parse().unwrap()));
+
+        let proxies = proxies(ForwardedHeader::XForwardedFor);
+        assert!(proxies.trusts("::ffff:10.1.2.3".parse().unwrap()));
+
+        // And report them as IPv4, so logs and rate limits agree
+        let spoofed = headers("x-forwarded-for", &["::ffff:203.0.113.9"]);
+        assert_eq!(
+            resolve("[::ffff:10.0.0.1]:80", &spoofed, &proxies),
+            ip("203.0.113.9")
+        );
+        assert_eq!(
+            resolve("[::ffff:198.51.100.7]:80", &HeaderMap::new(), &proxies),
+            ip("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn filters_ips() {
+        let open = IpFilter::default();
+        assert!(open.permits("203.0.113.9".parse().unwrap()));
+
+        let deny_only = IpFilter {
+            allow: vec![],
+            deny: vec!["203.0.113.0/24".parse().unwrap()],
+        };
+        assert!(!deny_only.permits("203.0.113.9".parse().unwrap()));
+        assert!(deny_only.permits("2001:db8::1".parse().unwrap()));
+    }
+}
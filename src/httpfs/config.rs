@@ -0,0 +1,216 @@
+use std::{io, os::unix::io::RawFd, path::PathBuf, sync::Arc, time::Duration};
+
+use http::Method;
+
+use crate::{
+    cli::Cli,
+    filesystem::{is_under, SymlinkPolicy},
+    httpfs::{
+        acl::AccessRules,
+        auth::{Authenticator, Credentials},
+        client_ip::{IpFilter, TrustedProxies},
+        cors::CorsPolicy,
+        csrf::CsrfTokens,
+        hidden::HiddenFiles,
+        limits::{ConnectionLimits, RateLimiter},
+        listener::{BindAddress, UnixSocketOptions},
+        parse::ParseLimits,
+        security::SecurityHeaders,
+        share::ShareLinks,
+        template::Templates,
+        tokens::TokenStore,
+        vhost::{VirtualHost, VirtualHosts},
+    },
+};
+
+/// Certificate and listeners for serving HTTPS
+#[derive(Debug)]
+pub struct TlsOptions {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Addresses that serve HTTPS, if empty every TCP listener serves HTTPS
+    pub binds: Vec<BindAddress>,
+}
+
+/// Everything a connection needs to know about how the server was configured
+#[derive(Debug)]
+pub struct ServerConfig {
+    pub binds: Vec<BindAddress>,
+    pub port: u16,
+    pub unix_sockets: Vec<PathBuf>,
+    pub unix_options: UnixSocketOptions,
+    pub listen_fds: Vec<RawFd>,
+    pub tls: Option<TlsOptions>,
+    /// Connections start with a PROXY protocol header from a load balancer
+    pub proxy_protocol: bool,
+    pub hosts: VirtualHosts,
+    /// URL paths uploads are limited to, if empty uploads are allowed anywhere
+    pub upload_dirs: Vec<String>,
+    pub symlinks: SymlinkPolicy,
+    pub hidden: HiddenFiles,
+    /// Basic auth users and API tokens, if set every request must be authenticated
+    pub auth: Option<Authenticator>,
+    /// Checks signed share links, which skip authentication and access rules
+    pub share_links: Option<ShareLinks>,
+    /// Who may use which methods on which paths, if unset everyone (who logged in) may do anything
+    pub access_rules: Option<AccessRules>,
+    /// Which client addresses may connect
+    pub ip_filter: IpFilter,
+    /// Proxies whose forwarding header tells us the real client address
+    pub trusted_proxies: Option<TrustedProxies>,
+    /// Which other origins' scripts may read responses, if unset none may
+    pub cors: Option<CorsPolicy>,
+    pub security: SecurityHeaders,
+    /// Pages for listings and errors
+    pub templates: Templates,
+    /// Tokens for the forms on listings
+    pub csrf: CsrfTokens,
+    pub connection_limits: ConnectionLimits,
+    pub rate_limiter: Option<RateLimiter>,
+    pub parse_limits: ParseLimits,
+    pub verbosity: u8,
+    /// How long to wait for active connections to finish when shutting down
+    pub grace_period: Duration,
+}
+
+impl TryFrom<&Cli> for ServerConfig {
+    type Error = io::Error;
+
+    /// Build the config from the command line, loading any files it points to
+    fn try_from(args: &Cli) -> Result<Self, Self::Error> {
+        let mut default_host = VirtualHost::new(&args.dir);
+        default_host.listing = !args.no_listing;
+        default_host.read_only = args.read_only;
+
+        let mut vhosts = args.vhosts.clone();
+        // --read-only is for every host, even ones that didn't ask for it
+        for spec in &mut vhosts {
+            spec.host.read_only |= args.read_only;
+        }
+
+        let credentials = match &args.auth_file {
+            Some(path) => Some(Arc::new(Credentials::load(path)?)),
+            None => None,
+        };
+
+        let tokens = match &args.token_file {
+            Some(path) => Some(TokenStore::load(path)?),
+            None => None,
+        };
+
+        let auth = match (credentials, tokens) {
+            (None, None) => None,
+            (credentials, tokens) => Some(Authenticator {
+                credentials,
+                tokens,
+                realm: args.auth_realm.clone(),
+            }),
+        };
+
+        let share_links = match &args.share_secret {
+            Some(path) => Some(ShareLinks::load(path)?),
+            None => None,
+        };
+
+        let access_rules = match &args.acl_file {
+            Some(path) => Some(AccessRules::load(path, args.acl_dry_run)?),
+            None => None,
+        };
+
+        let cors = if args.cors_origins.is_empty() {
+            None
+        } else {
+            Some(CorsPolicy::new(
+                &args.cors_origins,
+                args.cors_methods.clone(),
+                args.cors_headers.clone(),
+                args.cors_credentials,
+                args.cors_max_age,
+            )?)
+        };
+
+        Ok(Self {
+            binds: args.binds.clone(),
+            port: args.port,
+            unix_sockets: args.unix_sockets.clone(),
+            unix_options: UnixSocketOptions {
+                mode: args.unix_mode,
+                owner: args.unix_owner.clone(),
+            },
+            listen_fds: args.listen_fds.clone(),
+            tls: match (&args.tls_cert, &args.tls_key) {
+                (Some(cert), Some(key)) => Some(TlsOptions {
+                    cert: cert.clone(),
+                    key: key.clone(),
+                    binds: args.tls_binds.clone(),
+                }),
+                _ => None,
+            },
+            proxy_protocol: args.proxy_protocol,
+            hosts: VirtualHosts::new(default_host, vhosts),
+            upload_dirs: args.upload_dirs.clone(),
+            symlinks: args.symlinks,
+            hidden: HiddenFiles::new(!args.show_hidden, &args.hide, args.ignore_files)?,
+            auth,
+            share_links,
+            access_rules,
+            ip_filter: IpFilter {
+                allow: args.allow_ips.clone(),
+                deny: args.deny_ips.clone(),
+            },
+            trusted_proxies: (!args.trusted_proxies.is_empty()).then(|| TrustedProxies {
+                proxies: args.trusted_proxies.clone(),
+                header: args.client_ip_header,
+            }),
+            cors,
+            security: SecurityHeaders::new(
+                !args.no_security_headers,
+                args.csp.as_deref(),
+                &args.referrer_policy,
+                args.frame_options,
+                args.active_content,
+            )?,
+            templates: Templates::load(args.template_dir.as_deref())?,
+            csrf: CsrfTokens::new()?,
+            connection_limits: ConnectionLimits {
+                total: args.max_connections,
+                per_ip: args.max_connections_per_ip,
+            },
+            rate_limiter: args
+                .rate_limit
+                .map(|rate| RateLimiter::new(rate, args.rate_burst)),
+            parse_limits: ParseLimits {
+                max_request_line: args.max_request_line,
+                max_headers: args.max_headers,
+                max_header_bytes: args.max_header_bytes,
+                max_body: args.max_body,
+                body_limits: args.body_limits.clone(),
+                max_chunk: args.max_chunk,
+                read_timeout: Duration::from_secs_f64(args.read_timeout),
+                idle_timeout: Duration::from_secs_f64(args.idle_timeout),
+                strict: !args.lenient_parsing,
+            },
+            verbosity: args.verbosity,
+            grace_period: Duration::from_secs_f64(args.grace_period),
+        })
+    }
+}
+
+impl ServerConfig {
+    /// The methods that can be used on `path` of `host`, for `Allow` headers
+    ///
+    /// `path` is the decoded and flattened request path, ie. `/incoming/a.zip`
+    pub fn allowed_methods(&self, host: &VirtualHost, path: &str) -> Vec<Method> {
+        let mut methods = vec![Method::GET, Method::HEAD, Method::OPTIONS];
+
+        let can_upload = !host.read_only
+            && (self.upload_dirs.is_empty()
+                || self.upload_dirs.iter().any(|dir| is_under(path, dir)));
+
+        if can_upload {
+            methods.push(Method::POST);
+        }
+
+        methods
+    }
+}
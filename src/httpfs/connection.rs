@@ -1,36 +1,65 @@
-use std::path::{Path, PathBuf};
+use std::{
+    ffi::OsStr,
+    net::IpAddr,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use http::{header, Method, Response, StatusCode};
-use tokio::{io::AsyncWriteExt, net::TcpStream};
-use urlencoding::decode;
+use http::{header, Method, Response, StatusCode, Version};
+use owo_colors::OwoColorize;
+use tokio::{
+    io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::timeout,
+};
+use urlencoding::decode_binary;
 
 use crate::{
-    cli::VERY_VERBOSE,
-    filesystem::flatten_path,
-    httpfs::get::handle_get,
+    cli::{VERBOSE, VERY_VERBOSE},
+    colorize::MColorize,
+    filesystem::{flatten_path, is_directory, symlinks_allowed},
+    httpfs::acl::{create_403, Decision},
+    httpfs::auth::Principal,
+    httpfs::client_ip::ClientIp,
+    httpfs::config::ServerConfig,
+    httpfs::form::handle_form,
+    httpfs::get::{create_404, handle_get},
     httpfs::head::handle_head,
+    httpfs::limits::{create_429, retry_after_secs},
+    httpfs::listener::PeerAddr,
     httpfs::log::{log_request, log_request_response_short, log_response},
-    httpfs::message::{ByteResponse, ResponseMessage, ResponseStyles},
-    httpfs::parse::parse_request,
+    httpfs::message::{ByteRequest, ByteResponse, ResponseMessage, ResponseStyles},
+    httpfs::multipart::request_boundary,
+    httpfs::parse::{parse_query, parse_request_head, request_reader, BodyReader},
+    httpfs::parse_error::HttpParseError,
     httpfs::post::handle_post,
     httpfs::server::UnrecoverableError,
+    httpfs::vhost::VirtualHost,
 };
 
-pub async fn handle_connection(mut stream: TcpStream, directory: &str, verbosity: u8) {
+pub async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    peer: PeerAddr,
+    config: &ServerConfig,
+) {
     let mut response: Option<ByteResponse> = None;
 
-    if let Err(e) = handle_request(&mut stream, directory, verbosity).await {
+    if let Err(e) = handle_request(&mut stream, peer, config).await {
         eprintln!("Error: {}", e);
         let body: Vec<u8> = format!("Error: {}", e).into_bytes();
-        response = Some(
-            Response::builder()
-                .status(500)
-                .header(header::CONTENT_LENGTH, body.len())
-                .header(header::CONTENT_TYPE, "text/plain")
-                .header(header::CONNECTION, "close")
-                .body(Some(body))
-                .unwrap(),
-        );
+        let mut error = Response::builder()
+            .status(500)
+            .header(header::CONTENT_LENGTH, body.len())
+            .header(header::CONTENT_TYPE, "text/plain")
+            .header(header::CONNECTION, "close")
+            .body(Some(body))
+            .unwrap();
+
+        if let Some(cors) = &config.cors {
+            cors.apply(None, &mut error);
+        }
+        config.security.apply(&mut error);
+        response = Some(error);
     };
 
     // std::error::Error isn't Send so we can't just nest it in the above if
@@ -40,67 +69,340 @@ pub async fn handle_connection(mut stream: TcpStream, directory: &str, verbosity
             eprintln!("Error writing Error Response: {}", e2);
         };
     }
+
+    // TLS clients can only tell the response is complete, rather than cut off, by the close_notify
+    let _ = stream.shutdown().await;
+}
+
+/// Answer a connection we won't serve with `response`, without reading the request
+pub async fn reject_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    response: ByteResponse,
+) {
+    if write_response(&ResponseMessage::from(&response), &mut stream)
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    let _ = stream.shutdown().await;
+    drain(&mut stream).await;
+}
+
+/// Read and throw away whatever the client is still sending, for a moment
+///
+/// Closing a socket with unread data can reset the connection before the client reads the response
+async fn drain<S: AsyncRead + Unpin>(stream: &mut S) {
+    let mut buf = [0; 1024];
+    let drain = async { while matches!(stream.read(&mut buf).await, Ok(n) if n > 0) {} };
+    let _ = timeout(Duration::from_secs(1), drain).await;
 }
 
-async fn handle_request(
-    stream: &mut TcpStream,
-    directory: &str,
-    verbosity: u8,
+async fn handle_request<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    peer: PeerAddr,
+    config: &ServerConfig,
 ) -> Result<(), UnrecoverableError> {
-    let request = match parse_request(stream).await {
-        Ok(request) => request,
-        Err(e) => {
-            let body = format!("Request parse error: {}", e);
-            eprintln!("{}", body);
-            let response: ByteResponse = Response::builder()
-                .status(StatusCode::from(e))
-                .header(header::CONTENT_LENGTH, body.len())
-                .header(header::CONTENT_TYPE, "text/plain")
-                .header(header::CONNECTION, "close")
-                .body(Some(body.into_bytes()))
-                .unwrap();
-
-            let response = ResponseMessage::from(&response);
-            write_response(&response, stream).await?;
-            return Ok(());
-        }
+    let mut reader = request_reader(stream, &config.parse_limits);
+
+    let head = match parse_request_head(&mut reader, &config.parse_limits).await {
+        Ok(head) => head,
+        Err(e) => return write_parse_error(e, config, reader.get_mut().get_mut()).await,
     };
 
-    if verbosity >= VERY_VERBOSE {
+    // Uploads from a listing are saved as they arrive, each file with its own size limit
+    let streamed =
+        head.request().method() == Method::POST && request_boundary(head.request()).is_some();
+
+    let (mut request, mut body) = head.into_parts(&mut reader, &config.parse_limits);
+
+    let client = ClientIp::resolve(&peer, request.headers(), config.trusted_proxies.as_ref());
+    request.extensions_mut().insert(peer);
+    request.extensions_mut().insert(client);
+
+    // Clients we won't serve don't get to make us read their bodies
+    let refused = admit_client(client.ip, config);
+
+    if refused.is_none() && !streamed {
+        match body.read_to_end().await {
+            Ok(content) => *request.body_mut() = content,
+            Err(e) => return write_parse_error(e, config, reader.get_mut().get_mut()).await,
+        }
+    }
+
+    if config.verbosity >= VERY_VERBOSE {
         log_request(&request)?;
     }
 
+    let mut response = match refused {
+        Some(response) => response,
+        None => route_request(&mut request, streamed.then_some(&mut body), config).await?,
+    };
+    let unread = !body.is_done();
+    config.templates.render_error_page(&request, &mut response);
+
+    // Every response gets CORS headers, otherwise scripts can't even see why a request failed
+    if let Some(cors) = &config.cors {
+        cors.apply(Some(&request), &mut response);
+    }
+    config.security.apply(&mut response);
+
+    log_request_response_short(&request, &response);
+
+    let http_message = ResponseMessage::from(&response);
+
+    if config.verbosity >= VERY_VERBOSE {
+        log_response(&http_message)?;
+    }
+
+    write_response(&http_message, reader.get_mut().get_mut()).await?;
+
+    // An upload refused before all of it was read
+    if unread {
+        let _ = reader.get_mut().get_mut().shutdown().await;
+        drain(&mut reader).await;
+    }
+
+    Ok(())
+}
+
+/// Answer a request that couldn't be parsed
+async fn write_parse_error<S: AsyncWrite + Unpin>(
+    e: HttpParseError,
+    config: &ServerConfig,
+    stream: &mut S,
+) -> Result<(), UnrecoverableError> {
+    eprintln!("Request parse error: {}", e);
+    let mut response = create_parse_error(e);
+
+    if let Some(cors) = &config.cors {
+        cors.apply(None, &mut response);
+    }
+    config.security.apply(&mut response);
+
+    write_response(&ResponseMessage::from(&response), stream).await
+}
+
+fn create_parse_error(e: HttpParseError) -> ByteResponse {
+    let body = format!("Request parse error: {}", e);
+
+    Response::builder()
+        .status(StatusCode::from(e))
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::CONTENT_TYPE, "text/plain")
+        .header(header::CONNECTION, "close")
+        .body(Some(body.into_bytes()))
+        .unwrap()
+}
+
+/// Check the client may make a request at all, returns the response to send instead if not
+fn admit_client(ip: Option<IpAddr>, config: &ServerConfig) -> Option<ByteResponse> {
+    let ip = ip?;
+
+    // Trusted proxies get past the check when the connection is accepted, the client is checked here
+    if !config.ip_filter.permits(ip) {
+        return Some(create_403("your address isn't allowed to connect"));
+    }
+
+    if let Some(limiter) = &config.rate_limiter {
+        if let Err(retry_after) = limiter.check(ip) {
+            if config.verbosity >= VERBOSE {
+                println!(
+                    "Rate limited {}, retry in {}s ({} IPs tracked)",
+                    ip.out_color(|t| t.bright_yellow()),
+                    retry_after_secs(retry_after),
+                    limiter.tracked(),
+                );
+            }
+
+            return Some(create_429(retry_after));
+        }
+    }
+
+    None
+}
+
+/// Figure out who the request is for and who made it, then hand it off to the right method handler
+///
+/// `body` is there when the request's body hasn't been read yet, because it's an upload
+async fn route_request<R: AsyncBufRead + Unpin>(
+    request: &mut ByteRequest,
+    body: Option<&mut BodyReader<'_, R>>,
+    config: &ServerConfig,
+) -> Result<ByteResponse, UnrecoverableError> {
+    let client = request.extensions().get::<ClientIp>().copied();
+    let ip = client.and_then(|client| client.ip);
+
+    let host = match request_host(request) {
+        Ok(host) => config.hosts.resolve(host),
+        Err(_) => return Ok(handle_bad_host()),
+    };
+
     // Decode the path so encoded entities like "%20" are turned into " "
-    // Filesystem paths dont use entities
-    let client_path = PathBuf::from(decode(request.uri().path())?.to_string());
+    // Filesystem paths dont use entities, or have to be UTF-8
+    let client_path = PathBuf::from(OsStr::from_bytes(&decode_binary(
+        request.uri().path().as_bytes(),
+    )));
     // Flatten the client's request so `/../Cargo.toml` becomes `/Cargo.toml`
     // This prevents escaping the data directory using `..`
     let client_path = flatten_path(client_path);
+    // The path as rules, tokens and share links see it, ie. `/releases/v1.zip`
+    let rule_path = format!("/{}", client_path.to_string_lossy());
+
+    // Preflights never carry credentials, so OPTIONS is answered before authentication
+    if request.method() == Method::OPTIONS {
+        return Ok(handle_options(&config.allowed_methods(host, &rule_path)));
+    }
+
     // Join the path to the data directory
-    let path = Path::new(directory).join(Path::new(&client_path));
+    let path = Path::new(&host.root).join(Path::new(&client_path));
+
+    let is_dir = is_directory(&path).await;
 
-    let response = match *request.method() {
-        Method::GET => handle_get(&request, path).await?,
-        Method::HEAD => handle_head(&request, path).await?,
-        Method::POST => handle_post(&request, path).await?,
-        _ => handle_unknown(),
+    // Forms posted to a directory act on the files in it, which are checked one by one
+    let is_form = request.method() == Method::POST && is_dir;
+
+    let principal = match authenticate(request, config, host, &rule_path).await {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response),
     };
 
-    log_request_response_short(&request, &response);
+    request.extensions_mut().insert(principal.clone());
 
-    let http_message = ResponseMessage::from(&response);
+    if !is_form {
+        if let Some(response) =
+            check_permission(config, &principal, request.method(), &rule_path, ip)
+        {
+            return Ok(response);
+        }
+    }
 
-    if verbosity >= VERY_VERBOSE {
-        log_response(&http_message)?;
+    // flatten_path only handles `..`, a symlink could still lead out of the data directory
+    if !symlinks_allowed(&host.root, &client_path, config.symlinks).await {
+        return Ok(create_403(
+            "the path goes through a symlink that isn't allowed",
+        ));
     }
 
-    write_response(&http_message, stream).await?;
-    Ok(())
+    // Hidden files get a 404 rather than a 403, so it's not obvious they exist
+    if config
+        .hidden
+        .is_hidden(&host.root, &client_path, is_dir)
+        .await
+    {
+        return create_404(request.uri().path());
+    }
+
+    let allowed = config.allowed_methods(host, &rule_path);
+
+    let mut response = match *request.method() {
+        Method::POST if !allowed.contains(&Method::POST) => handle_not_allowed(&allowed),
+        Method::GET => handle_get(request, config, host, path).await?,
+        Method::HEAD => handle_head(request, config, host, path).await?,
+        Method::POST if is_form => {
+            handle_form(request, body, config, host, &client_path, &principal, ip).await?
+        }
+        Method::POST => {
+            // A multipart body posted to a file is saved as it is, like any other body
+            if let Some(body) = body {
+                match body.read_to_end().await {
+                    Ok(content) => *request.body_mut() = content,
+                    Err(e) => return Ok(create_parse_error(e)),
+                }
+            }
+
+            handle_post(request, path).await?
+        }
+        _ => handle_unknown(&allowed),
+    };
+
+    // Anyone who can upload here could have put HTML with scripts in this file
+    if !is_dir && allowed.contains(&Method::POST) {
+        config.security.restrict_active_content(&mut response);
+    }
+
+    Ok(response)
+}
+
+/// Work out who made the request
+///
+/// Returns the response to send instead if they have to log in, or sent credentials that don't work
+async fn authenticate(
+    request: &ByteRequest,
+    config: &ServerConfig,
+    host: &VirtualHost,
+    rule_path: &str,
+) -> Result<Principal, ByteResponse> {
+    // A valid share link is all the permission a request needs
+    if let (Some(links), Some(query)) = (&config.share_links, request.uri().query()) {
+        match links.check(request.method(), &host.name, rule_path, &parse_query(query)) {
+            Some(Ok(())) => return Ok(Principal::ShareLink),
+            Some(Err(reason)) => return Err(create_403(&reason)),
+            None => {}
+        }
+    }
+
+    match &config.auth {
+        Some(auth) => match auth.authenticate(request).await {
+            // With access rules, they decide what anonymous users can do
+            Ok(Principal::Anonymous) if config.access_rules.is_none() => Err(auth.challenge(None)),
+            Ok(principal) => Ok(principal),
+            Err(e) => Err(auth.challenge(Some(e))),
+        },
+        None => Ok(Principal::Anonymous),
+    }
 }
 
-async fn write_response(
+/// Check `principal`, connecting from `ip`, may use `method` on `rule_path`
+///
+/// Returns the response to send instead, if they may not
+pub fn check_permission(
+    config: &ServerConfig,
+    principal: &Principal,
+    method: &Method,
+    rule_path: &str,
+    ip: Option<IpAddr>,
+) -> Option<ByteResponse> {
+    // Share links were checked against the path and method they were made for
+    if *principal == Principal::ShareLink {
+        return None;
+    }
+
+    if let Some(rules) = &config.access_rules {
+        if let Decision::Deny(reason) = rules.check(method, rule_path, principal, ip) {
+            if rules.dry_run {
+                println!(
+                    "{} {} {} for {}: {}",
+                    "ACL dry run, would deny".out_color(|t| t.yellow()),
+                    method,
+                    rule_path,
+                    principal,
+                    reason
+                );
+            } else {
+                // Give anonymous users the chance to log in before telling them no
+                return Some(match &config.auth {
+                    Some(auth) if *principal == Principal::Anonymous => auth.challenge(None),
+                    _ => create_403(&reason),
+                });
+            }
+        }
+    }
+
+    // Tokens are limited by their own scopes, on top of whatever the rules allow
+    if let Principal::Token(grant) = principal {
+        if let Err(reason) = grant.permits(method, rule_path) {
+            return Some(create_403(&reason));
+        }
+    }
+
+    None
+}
+
+async fn write_response<S: AsyncWrite + Unpin>(
     message: &ResponseMessage,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), UnrecoverableError> {
     let response_styles = ResponseStyles::default();
     let (res_message, res_body) = message.to_parts(&response_styles)?;
@@ -111,10 +413,66 @@ async fn write_response(
     Ok(())
 }
 
-fn handle_unknown() -> ByteResponse {
+fn handle_unknown(allowed: &[Method]) -> ByteResponse {
     let body: Vec<u8> = "Unknown method!".into();
     Response::builder()
         .status(501)
+        .header(header::ALLOW, format_allow(allowed))
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::CONTENT_TYPE, "text/plain")
+        .header(header::CONNECTION, "close")
+        .body(Some(body))
+        .unwrap()
+}
+
+/// Tell the client which methods `path` allows, CORS preflight headers are added on the way out
+fn handle_options(allowed: &[Method]) -> ByteResponse {
+    Response::builder()
+        .status(204)
+        .header(header::ALLOW, format_allow(allowed))
+        .body(None)
+        .unwrap()
+}
+
+/// A method we know, that isn't allowed here, ie. uploads on a read-only host
+pub fn handle_not_allowed(allowed: &[Method]) -> ByteResponse {
+    let body: Vec<u8> = format!("405: Method not allowed, use {}", format_allow(allowed)).into();
+    Response::builder()
+        .status(405)
+        .header(header::ALLOW, format_allow(allowed))
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::CONTENT_TYPE, "text/plain")
+        .header(header::CONNECTION, "close")
+        .body(Some(body))
+        .unwrap()
+}
+
+fn format_allow(allowed: &[Method]) -> String {
+    allowed
+        .iter()
+        .map(Method::as_str)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Get the value of the Host header, if one is required and present
+/// HTTP/1.1 requires exactly one Host header, HTTP/1.0 clients may leave it out
+/// https://www.rfc-editor.org/rfc/rfc9112#section-3.2
+fn request_host(request: &ByteRequest) -> Result<Option<&str>, ()> {
+    let mut hosts = request.headers().get_all(header::HOST).iter();
+
+    match (hosts.next(), hosts.next()) {
+        (None, _) if request.version() == Version::HTTP_11 => Err(()),
+        (None, _) => Ok(None),
+        (Some(host), None) => host.to_str().map(Some).map_err(|_| ()),
+        (Some(_), Some(_)) => Err(()),
+    }
+}
+
+fn handle_bad_host() -> ByteResponse {
+    let body: Vec<u8> = "Missing or invalid Host header".into();
+    Response::builder()
+        .status(400)
         .header(header::CONTENT_LENGTH, body.len())
         .header(header::CONTENT_TYPE, "text/plain")
         .header(header::CONNECTION, "close")
@@ -0,0 +1,395 @@
+use std::io;
+
+use http::{header, HeaderMap, HeaderValue, Method};
+
+use crate::httpfs::message::{ByteRequest, ByteResponse};
+
+/// Which origins other sites' scripts may read responses from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CorsOrigins {
+    /// `*`, any origin, but never with credentials
+    Any,
+    /// Any origin, by echoing it back, which works with credentials
+    Reflect,
+    /// Only these, like `https://dashboard.example.com`
+    List(Vec<String>),
+}
+
+/// Cross-origin resource sharing, so scripts on other origins can fetch listings and upload
+/// https://fetch.spec.whatwg.org/#http-cors-protocol
+#[derive(Debug)]
+pub struct CorsPolicy {
+    pub origins: CorsOrigins,
+    /// Methods preflights allow
+    pub methods: Vec<Method>,
+    /// Request headers preflights allow, besides the ones browsers always allow
+    pub headers: Vec<String>,
+    /// Let requests include cookies and `Authorization`
+    pub credentials: bool,
+    /// Seconds browsers may cache a preflight for
+    pub max_age: Option<u64>,
+}
+
+impl CorsPolicy {
+    /// Build the policy from `--cors-origin` values, where `*` allows any origin and `reflect`
+    /// echoes back whichever origin asked
+    pub fn new(
+        origins: &[String],
+        methods: Vec<Method>,
+        headers: Vec<String>,
+        credentials: bool,
+        max_age: Option<u64>,
+    ) -> io::Result<Self> {
+        let origins = if origins.iter().any(|o| o == "reflect") {
+            CorsOrigins::Reflect
+        } else if origins.iter().any(|o| o == "*") {
+            CorsOrigins::Any
+        } else {
+            CorsOrigins::List(
+                origins
+                    .iter()
+                    .map(|o| o.trim_end_matches('/').to_ascii_lowercase())
+                    .collect(),
+            )
+        };
+
+        // Browsers refuse credentialed responses with `Access-Control-Allow-Origin: *`
+        if credentials && origins == CorsOrigins::Any {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--cors-credentials can't be used with --cors-origin '*', use 'reflect' or list the origins",
+            ));
+        }
+
+        Ok(Self {
+            origins,
+            methods,
+            headers,
+            credentials,
+            max_age,
+        })
+    }
+
+    /// Add the CORS headers for `request` to `response`
+    ///
+    /// `request` is `None` if it couldn't be parsed, then only `*` can be allowed
+    pub fn apply(&self, request: Option<&ByteRequest>, response: &mut ByteResponse) {
+        let origin = request.and_then(|r| r.headers().get(header::ORIGIN));
+        let headers = response.headers_mut();
+
+        // Caches must not give a response allowing one origin to another
+        if self.origins != CorsOrigins::Any {
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+
+        let allowed = match (&self.origins, origin) {
+            (CorsOrigins::Any, _) => HeaderValue::from_static("*"),
+            (_, None) => return,
+            (CorsOrigins::Reflect, Some(origin)) => origin.clone(),
+            (CorsOrigins::List(origins), Some(origin)) => {
+                let listed = origin.to_str().is_ok_and(|origin| {
+                    origins.contains(&origin.trim_end_matches('/').to_ascii_lowercase())
+                });
+
+                if !listed {
+                    return;
+                }
+                origin.clone()
+            }
+        };
+
+        // Responses are written out as text, an origin that isn't can't be echoed back
+        if allowed.to_str().is_err() {
+            return;
+        }
+
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allowed);
+
+        if self.credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+
+        if request.is_some_and(|r| is_preflight(r.method(), r.headers())) {
+            self.apply_preflight(headers);
+        }
+    }
+
+    fn apply_preflight(&self, headers: &mut HeaderMap) {
+        let methods = self
+            .methods
+            .iter()
+            .map(Method::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        if let Ok(methods) = HeaderValue::from_str(&methods) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+
+        if !self.headers.is_empty() {
+            if let Ok(allowed) = HeaderValue::from_str(&self.headers.join(", ")) {
+                headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed);
+            }
+        }
+
+        if let Some(max_age) = self.max_age {
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, max_age.into());
+        }
+    }
+}
+
+/// A browser asking whether it may send a cross-origin request, before sending it
+fn is_preflight(method: &Method, headers: &HeaderMap) -> bool {
+    method == Method::OPTIONS
+        && headers.contains_key(header::ORIGIN)
+        && headers.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{Request, Response};
+
+    use super::*;
+
+    fn policy(origins: &[&str], credentials: bool) -> CorsPolicy {
+        let origins = origins.iter().map(|o| o.to_string()).collect::<Vec<_>>();
+        CorsPolicy::new(
+            &origins,
+            vec![Method::GET, Method::PUT],
+            vec!["Content-Type".to_string(), "X-Requested-With".to_string()],
+            credentials,
+            Some(600),
+        )
+        .unwrap()
+    }
+
+    fn request(method: Method, origin: Option<&str>) -> ByteRequest {
+        let mut request = Request::builder().method(method).uri("/files/");
+        if let Some(origin) = origin {
+            request = request.header(header::ORIGIN, origin);
+        }
+        request.body(None).unwrap()
+    }
+
+    fn preflight(origin: &str) -> ByteRequest {
+        let mut request = request(Method::OPTIONS, Some(origin));
+        request.headers_mut().insert(
+            header::ACCESS_CONTROL_REQUEST_METHOD,
+            HeaderValue::from_static("PUT"),
+        );
+        request
+    }
+
+    fn answer(policy: &CorsPolicy, request: Option<&ByteRequest>) -> ByteResponse {
+        let mut response = Response::builder().status(200).body(None).unwrap();
+        policy.apply(request, &mut response);
+        response
+    }
+
+    fn value(response: &ByteResponse, name: header::HeaderName) -> Option<&str> {
+        response.headers().get(name).map(|v| v.to_str().unwrap())
+    }
+
+    #[test]
+    fn matches_listed_origins() {
+        let policy = policy(
+            &["https://Dash.example.com/", "http://localhost:3000"],
+            false,
+        );
+
+        for origin in [
+            "https://dash.example.com",
+            "https://DASH.example.com/",
+            "http://localhost:3000",
+        ] {
+            let response = answer(&policy, Some(&request(Method::GET, Some(origin))));
+            assert_eq!(
+                value(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+                Some(origin),
+                "{}",
+                origin
+            );
+        }
+
+        for origin in [
+            "https://evil.example.com",
+            "http://dash.example.com",
+            "https://dash.example.com.evil.com",
+            "http://localhost:3001",
+            "null",
+        ] {
+            let response = answer(&policy, Some(&request(Method::GET, Some(origin))));
+            assert_eq!(
+                value(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+                None,
+                "{}",
+                origin
+            );
+        }
+    }
+
+    #[test]
+    fn allows_any_or_reflected_origins() {
+        let any = policy(&["https://a.example", "*"], false);
+        assert_eq!(any.origins, CorsOrigins::Any);
+
+        let response = answer(&any, Some(&request(Method::GET, Some("https://b.example"))));
+        assert_eq!(
+            value(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("*")
+        );
+        let response = answer(&any, None);
+        assert_eq!(
+            value(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("*")
+        );
+
+        let reflect = policy(&["reflect", "*"], true);
+        assert_eq!(reflect.origins, CorsOrigins::Reflect);
+
+        let response = answer(
+            &reflect,
+            Some(&request(Method::GET, Some("https://b.example"))),
+        );
+        assert_eq!(
+            value(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://b.example")
+        );
+        let response = answer(&reflect, Some(&request(Method::GET, None)));
+        assert_eq!(value(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN), None);
+        let response = answer(&reflect, None);
+        assert_eq!(value(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN), None);
+    }
+
+    #[test]
+    fn varies_on_origin_unless_any_is_allowed() {
+        let listed = policy(&["https://a.example"], false);
+        for request in [
+            request(Method::GET, Some("https://a.example")),
+            request(Method::GET, Some("https://b.example")),
+            request(Method::GET, None),
+        ] {
+            let response = answer(&listed, Some(&request));
+            assert_eq!(value(&response, header::VARY), Some("Origin"));
+        }
+
+        let reflect = policy(&["reflect"], false);
+        let response = answer(&reflect, Some(&request(Method::GET, None)));
+        assert_eq!(value(&response, header::VARY), Some("Origin"));
+
+        let any = policy(&["*"], false);
+        let response = answer(&any, Some(&request(Method::GET, Some("https://a.example"))));
+        assert_eq!(value(&response, header::VARY), None);
+    }
+
+    #[test]
+    fn keeps_existing_vary_values() {
+        let policy = policy(&["https://a.example"], false);
+        let mut response = Response::builder()
+            .header(header::VARY, "Accept")
+            .body(None)
+            .unwrap();
+        policy.apply(
+            Some(&request(Method::GET, Some("https://a.example"))),
+            &mut response,
+        );
+
+        let vary = response
+            .headers()
+            .get_all(header::VARY)
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vary, ["Accept", "Origin"]);
+    }
+
+    #[test]
+    fn allows_credentials_only_for_specific_origins() {
+        let error = CorsPolicy::new(&["*".to_string()], vec![], vec![], true, None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        let credentialed = policy(&["https://a.example"], true);
+        let response = answer(
+            &credentialed,
+            Some(&request(Method::GET, Some("https://a.example"))),
+        );
+        assert_eq!(
+            value(&response, header::ACCESS_CONTROL_ALLOW_CREDENTIALS),
+            Some("true")
+        );
+
+        let response = answer(
+            &credentialed,
+            Some(&request(Method::GET, Some("https://b.example"))),
+        );
+        assert_eq!(
+            value(&response, header::ACCESS_CONTROL_ALLOW_CREDENTIALS),
+            None
+        );
+
+        let without = policy(&["https://a.example"], false);
+        let response = answer(
+            &without,
+            Some(&request(Method::GET, Some("https://a.example"))),
+        );
+        assert_eq!(
+            value(&response, header::ACCESS_CONTROL_ALLOW_CREDENTIALS),
+            None
+        );
+    }
+
+    #[test]
+    fn answers_preflights() {
+        let policy = policy(&["https://a.example"], false);
+
+        let response = answer(&policy, Some(&preflight("https://a.example")));
+        assert_eq!(
+            value(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://a.example")
+        );
+        assert_eq!(
+            value(&response, header::ACCESS_CONTROL_ALLOW_METHODS),
+            Some("GET, PUT")
+        );
+        assert_eq!(
+            value(&response, header::ACCESS_CONTROL_ALLOW_HEADERS),
+            Some("Content-Type, X-Requested-With")
+        );
+        assert_eq!(
+            value(&response, header::ACCESS_CONTROL_MAX_AGE),
+            Some("600")
+        );
+
+        // Disallowed origins learn nothing about what's allowed
+        let response = answer(&policy, Some(&preflight("https://b.example")));
+        assert_eq!(value(&response, header::ACCESS_CONTROL_ALLOW_METHODS), None);
+        assert_eq!(value(&response, header::ACCESS_CONTROL_ALLOW_HEADERS), None);
+
+        // OPTIONS without Access-Control-Request-Method isn't a preflight
+        let response = answer(
+            &policy,
+            Some(&request(Method::OPTIONS, Some("https://a.example"))),
+        );
+        assert_eq!(value(&response, header::ACCESS_CONTROL_ALLOW_METHODS), None);
+        assert_eq!(value(&response, header::ACCESS_CONTROL_MAX_AGE), None);
+
+        let minimal = CorsPolicy::new(
+            &["https://a.example".to_string()],
+            vec![Method::GET],
+            vec![],
+            false,
+            None,
+        )
+        .unwrap();
+        let response = answer(&minimal, Some(&preflight("https://a.example")));
+        assert_eq!(
+            value(&response, header::ACCESS_CONTROL_ALLOW_METHODS),
+            Some("GET")
+        );
+        assert_eq!(value(&response, header::ACCESS_CONTROL_ALLOW_HEADERS), None);
+        assert_eq!(value(&response, header::ACCESS_CONTROL_MAX_AGE), None);
+    }
+}
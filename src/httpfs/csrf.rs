@@ -0,0 +1,180 @@
+use std::{io, time::Duration};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::httpfs::{
+    auth::Principal,
+    tokens::{decode_hex, random_hex, unix_now},
+};
+
+/// How long the forms on a listing keep working after it was loaded
+const CSRF_LIFETIME: Duration = Duration::from_secs(8 * 60 * 60);
+
+/// Issues and checks the tokens that listing forms send back, `EXPIRES.HMAC`
+///
+/// A token is a HMAC-SHA256 over who it was issued to, the virtual host, the directory and the
+/// expiry, keyed with a secret made when the server starts. Another site can make a browser post a form, but it can't
+/// read the listing to get a token for it. Tokens stop working when the server restarts
+#[derive(Debug)]
+pub struct CsrfTokens {
+    secret: Vec<u8>,
+}
+
+impl CsrfTokens {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            secret: random_hex(32)?.into_bytes(),
+        })
+    }
+
+    /// A token for `principal` to use on the forms of the listing of `directory`, on the virtual
+    /// host named `host`
+    ///
+    /// `directory` is the decoded and flattened path, ie. `/incoming`
+    pub fn issue(&self, principal: &Principal, host: &str, directory: &str) -> String {
+        let expires = unix_now() + CSRF_LIFETIME.as_secs();
+        let signature = self
+            .mac(principal, host, directory, expires)
+            .finalize()
+            .into_bytes();
+
+        format!(
+            "{}.{}",
+            expires,
+            signature
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        )
+    }
+
+    /// Check the token sent with a form posted to `directory` on the virtual host named `host`, with
+    /// the reason if it isn't valid
+    ///
+    /// Browsers send Basic auth along with forms from any site, so those need a token. Bearer
+    /// tokens they never send by themselves, so clients using one don't
+    pub fn check_form(
+        &self,
+        token: Option<&str>,
+        principal: &Principal,
+        host: &str,
+        directory: &str,
+    ) -> Result<(), String> {
+        if matches!(principal, Principal::Token(_)) {
+            return Ok(());
+        }
+
+        self.check(token.unwrap_or_default(), principal, host, directory)
+    }
+
+    fn check(
+        &self,
+        token: &str,
+        principal: &Principal,
+        host: &str,
+        directory: &str,
+    ) -> Result<(), String> {
+        let invalid = || "the form's CSRF token is invalid, reload the page".to_string();
+
+        let (expires, sig) = token.split_once('.').ok_or_else(invalid)?;
+        let expires: u64 = expires.parse().map_err(|_| invalid())?;
+        let sig = decode_hex(sig).ok_or_else(invalid)?;
+
+        // verify_slice compares in constant time
+        self.mac(principal, host, directory, expires)
+            .verify_slice(&sig)
+            .map_err(|_| invalid())?;
+
+        if unix_now() >= expires {
+            return Err("the form has expired, reload the page".to_string());
+        }
+
+        Ok(())
+    }
+
+    fn mac(
+        &self,
+        principal: &Principal,
+        host: &str,
+        directory: &str,
+        expires: u64,
+    ) -> Hmac<Sha256> {
+        // Tagged, so a user can't be confused with anonymous or a token by their name
+        let identity = match principal {
+            Principal::Anonymous => "anonymous".to_string(),
+            Principal::User(name) => format!("user:{}", name),
+            Principal::Token(grant) => format!("token:{}", grant.name),
+            Principal::ShareLink => "share-link".to_string(),
+        };
+
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        // Virtual hosts can have the same directories, a token is only good on the one it's from
+        mac.update(format!("{}\n{}\n{}\n{}", identity, host, directory, expires).as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_tokens_for_the_same_form() {
+        let tokens = CsrfTokens::new().unwrap();
+        let alice = Principal::User("alice".to_string());
+        let token = tokens.issue(&alice, "*", "/incoming");
+
+        assert_eq!(
+            tokens.check_form(Some(&token), &alice, "*", "/incoming"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn refuses_tokens_for_anything_else() {
+        let tokens = CsrfTokens::new().unwrap();
+        let alice = Principal::User("alice".to_string());
+        let token = tokens.issue(&alice, "*", "/incoming");
+
+        let bob = Principal::User("bob".to_string());
+        assert!(tokens
+            .check_form(Some(&token), &bob, "*", "/incoming")
+            .is_err());
+        assert!(tokens
+            .check_form(Some(&token), &Principal::Anonymous, "*", "/incoming")
+            .is_err());
+        assert!(tokens
+            .check_form(Some(&token), &alice, "*", "/other")
+            .is_err());
+        assert!(tokens
+            .check_form(Some(&token), &alice, "docs.example.com", "/incoming")
+            .is_err());
+
+        // Another server's tokens, or the same one's before it restarted
+        let other = CsrfTokens::new().unwrap().issue(&alice, "*", "/incoming");
+        assert!(tokens
+            .check_form(Some(&other), &alice, "*", "/incoming")
+            .is_err());
+    }
+
+    #[test]
+    fn refuses_missing_and_malformed_tokens() {
+        let tokens = CsrfTokens::new().unwrap();
+        let alice = Principal::User("alice".to_string());
+        let token = tokens.issue(&alice, "*", "/incoming");
+        let (_, signature) = token.split_once('.').unwrap();
+
+        for bad in ["", "abc", "1.zz", &format!("99999999999.{}", signature)] {
+            assert!(
+                tokens
+                    .check_form(Some(bad), &alice, "*", "/incoming")
+                    .is_err(),
+                "{}",
+                bad
+            );
+        }
+        assert!(tokens.check_form(None, &alice, "*", "/incoming").is_err());
+    }
+}
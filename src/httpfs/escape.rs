@@ -0,0 +1,154 @@
+use std::{ffi::OsStr, fmt::Write, os::unix::ffi::OsStrExt};
+
+/// Escape text for HTML, safe both as element content and inside a quoted attribute
+///
+/// Hostile file names come out as harmless text
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Percent-encode one path segment, so it can't end the path or start a query or fragment
+///
+/// Works on the raw bytes, so names that aren't valid UTF-8 still get a link that leads to them
+pub fn encode_segment(segment: &OsStr) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+
+    for &byte in segment.as_bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(char::from(byte));
+        } else {
+            // Writing to a String can't fail
+            let _ = write!(encoded, "%{:02X}", byte);
+        }
+    }
+
+    encoded
+}
+
+/// A file name as text, for showing to people
+///
+/// Bytes that aren't UTF-8 become `�`, and control characters like newlines are escaped so a name
+/// can't break a line based listing into several entries
+pub fn display_name(name: &OsStr) -> String {
+    name.to_string_lossy()
+        .chars()
+        .map(|c| {
+            if c.is_control() {
+                c.escape_default().to_string()
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    use mime_guess::mime;
+
+    use super::*;
+    use crate::filesystem::DirEntry;
+
+    fn file(name: &[u8]) -> DirEntry {
+        DirEntry {
+            name: OsString::from(OsStr::from_bytes(name)),
+            is_directory: false,
+            is_symlink: false,
+            mime: mime::TEXT_PLAIN,
+            size: Some(1),
+        }
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(escape_html("<script>"), "&lt;script&gt;");
+        assert_eq!(
+            escape_html("a\"onmouseover=\"alert(1)"),
+            "a&quot;onmouseover=&quot;alert(1)"
+        );
+        assert_eq!(escape_html("it's"), "it&#39;s");
+        assert_eq!(escape_html("&amp;"), "&amp;amp;");
+    }
+
+    #[test]
+    fn encodes_segments() {
+        assert_eq!(encode_segment("x#y?.txt".as_ref()), "x%23y%3F.txt");
+        assert_eq!(encode_segment("100%.txt".as_ref()), "100%25.txt");
+        assert_eq!(encode_segment("a/b".as_ref()), "a%2Fb");
+        assert_eq!(encode_segment("line\nbreak".as_ref()), "line%0Abreak");
+        assert_eq!(encode_segment("a\"b".as_ref()), "a%22b");
+        assert_eq!(encode_segment(OsStr::from_bytes(b"caf\xe9")), "caf%E9");
+        assert_eq!(encode_segment("café".as_ref()), "caf%C3%A9");
+        assert_eq!(encode_segment("safe-name_1.0~".as_ref()), "safe-name_1.0~");
+    }
+
+    #[test]
+    fn display_names() {
+        assert_eq!(display_name("notes.txt".as_ref()), "notes.txt");
+        assert_eq!(display_name("line\nbreak".as_ref()), "line\\nbreak");
+        assert_eq!(display_name("tab\there".as_ref()), "tab\\there");
+        assert_eq!(display_name(OsStr::from_bytes(b"caf\xe9")), "caf\u{FFFD}");
+    }
+
+    #[test]
+    fn html_rows_escape_names_and_encode_links() {
+        let cases: [(&[u8], &str, &str); 7] = [
+            (b"a\"b.txt", "a&quot;b.txt", "a%22b.txt"),
+            (b"x#y.txt", "x#y.txt", "x%23y.txt"),
+            (b"what?.txt", "what?.txt", "what%3F.txt"),
+            (b"100%.txt", "100%.txt", "100%25.txt"),
+            (b"caf\xe9.txt", "caf\u{FFFD}.txt", "caf%E9.txt"),
+            (b"line\nbreak", "line\\nbreak", "line%0Abreak"),
+            (
+                b"<img src=x onerror=alert(1)>",
+                "&lt;img src=x onerror=alert(1)&gt;",
+                "%3Cimg%20src%3Dx%20onerror%3Dalert%281%29%3E",
+            ),
+        ];
+
+        for (name, shown, href) in cases {
+            let row = file(name).html_format(None);
+            assert!(
+                row.contains(&format!("<a href=\"{}\">{}</a>", href, shown)),
+                "{}",
+                row
+            );
+            assert!(row.contains(&format!("data-name=\"{}\"", shown)), "{}", row);
+        }
+    }
+
+    #[test]
+    fn html_rows_never_contain_raw_markup() {
+        let row = file(b"\"><script>alert('x')</script>").html_format(Some("token"));
+        assert!(!row.contains("<script>"));
+        assert!(!row.contains("'x'"));
+    }
+
+    #[test]
+    fn plaintext_lines_stay_one_line() {
+        assert_eq!(
+            file(b"line\nbreak").plaintext_format(),
+            "line\\nbreak [text/plain]"
+        );
+        assert_eq!(
+            file(b"caf\xe9 #?%\"").plaintext_format(),
+            "caf\u{FFFD} #?%\" [text/plain]"
+        );
+        assert!(!file(b"a\r\nb").plaintext_format().contains('\n'));
+    }
+}
@@ -0,0 +1,401 @@
+use std::{
+    ffi::OsStr,
+    io,
+    net::IpAddr,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use http::{header, Method, Response, StatusCode};
+use tokio::io::AsyncBufRead;
+use urlencoding::decode_binary;
+
+use crate::{
+    filesystem::{is_directory, symlinks_allowed, AtomicFile},
+    httpfs::acl::create_403,
+    httpfs::actions::handle_action,
+    httpfs::auth::Principal,
+    httpfs::config::ServerConfig,
+    httpfs::connection::check_permission,
+    httpfs::escape::{display_name, escape_html},
+    httpfs::formatting::format_size,
+    httpfs::message::{ByteRequest, ByteResponse},
+    httpfs::multipart::{request_boundary, Multipart},
+    httpfs::parse::BodyReader,
+    httpfs::parse_error::HttpParseError,
+    httpfs::redirect::local_location,
+    httpfs::server::UnrecoverableError,
+    httpfs::vhost::VirtualHost,
+};
+
+/// What happened to one file of an upload form
+struct Outcome {
+    name: String,
+    result: Result<usize, String>,
+}
+
+/// Most bytes of a field that isn't a file, like the CSRF token
+const MAX_FIELD_BYTES: usize = 4 * 1024;
+
+/// Handle a form posted to the directory at `client_path`, from the forms on its listing
+///
+/// `multipart/form-data` uploads files, every one checked on its own with the same rules as
+/// uploading it directly, and one being refused doesn't stop the others from being saved. Its
+/// `body` is read as it arrives, so each file has its own size limit rather than the whole form.
+/// `application/x-www-form-urlencoded` is an action like making a directory, see `handle_action`
+pub async fn handle_form<R: AsyncBufRead + Unpin>(
+    request: &ByteRequest,
+    body: Option<&mut BodyReader<'_, R>>,
+    config: &ServerConfig,
+    host: &VirtualHost,
+    client_path: &Path,
+    principal: &Principal,
+    ip: Option<IpAddr>,
+) -> Result<ByteResponse, UnrecoverableError> {
+    // A share link is only good for the one path it was made for
+    if *principal == Principal::ShareLink {
+        return Ok(create_403("share links can't be used with forms"));
+    }
+
+    let content_type = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default();
+
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    if essence.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+        return handle_action(request, config, host, client_path, principal, ip).await;
+    }
+
+    let (boundary, body) = match (request_boundary(request), body) {
+        (Some(boundary), Some(body)) => (boundary, body),
+        _ => {
+            return Ok(create_form_error(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "forms posted to a directory must be multipart/form-data or \
+                application/x-www-form-urlencoded",
+            ))
+        }
+    };
+
+    let mut multipart = Multipart::new(body, &boundary);
+
+    // Another site can make a browser post this form too, but it can't get a token for it. It's
+    // checked before the first file, browsers send the fields in the order of the form
+    let directory = format!("/{}", client_path.to_string_lossy());
+    let check_token = |token: Option<&str>| {
+        config
+            .csrf
+            .check_form(token, principal, &host.name, &directory)
+    };
+    let mut token: Option<String> = None;
+    let mut token_checked = false;
+
+    let mut outcomes = vec![];
+
+    loop {
+        let part = match multipart.next_part().await {
+            Ok(Some(part)) => part,
+            Ok(None) => break,
+            Err(e) => return Ok(create_body_error(e)),
+        };
+
+        let filename = match part.filename {
+            Some(filename) => filename,
+            None => {
+                if part.name.as_deref() == Some("csrf") {
+                    match multipart.read_field(MAX_FIELD_BYTES).await {
+                        Ok(value) => token = Some(String::from_utf8_lossy(&value).into_owned()),
+                        Err(e) => return Ok(create_body_error(e)),
+                    }
+                }
+                continue;
+            }
+        };
+
+        if !token_checked {
+            if let Err(reason) = check_token(token.as_deref()) {
+                return Ok(create_403(&reason));
+            }
+            token_checked = true;
+        }
+
+        // Browsers send a file part with an empty name when no file was picked
+        if filename.is_empty() {
+            continue;
+        }
+
+        let sanitized = sanitize_filename(&filename);
+
+        let result = match &sanitized {
+            Some(name) => {
+                let relative = client_path.join(name);
+                match save_file(config, host, &relative, principal, ip, &mut multipart).await {
+                    Ok(size) => Ok(size),
+                    // Let anonymous users log in rather than refusing every file
+                    Err(Refused::Challenge(response)) => return Ok(response),
+                    Err(Refused::Body(e)) => return Ok(create_body_error(e)),
+                    Err(Refused::Reason(reason)) => Err(reason),
+                }
+            }
+            None => Err("that isn't a usable file name".to_string()),
+        };
+
+        // Report files under the name they were saved as, where there is one
+        let name = sanitized
+            .as_deref()
+            .map_or(OsStr::new(&filename), |name| name.as_os_str());
+        outcomes.push(Outcome {
+            name: display_name(name),
+            result,
+        });
+    }
+
+    if !token_checked {
+        if let Err(reason) = check_token(token.as_deref()) {
+            return Ok(create_403(&reason));
+        }
+    }
+
+    if outcomes.is_empty() {
+        return Ok(create_form_error(
+            StatusCode::BAD_REQUEST,
+            "the form has no files in it",
+        ));
+    }
+
+    Ok(create_upload_result(request, config, &outcomes))
+}
+
+/// Why a file wasn't saved
+enum Refused {
+    /// The client isn't logged in, and might be allowed once they are
+    Challenge(ByteResponse),
+    /// The body couldn't be read, so neither can the rest of the form
+    Body(HttpParseError),
+    Reason(String),
+}
+
+/// Save the data of the current part of `multipart` as `relative`, returning its size
+async fn save_file<R: AsyncBufRead + Unpin>(
+    config: &ServerConfig,
+    host: &VirtualHost,
+    relative: &Path,
+    principal: &Principal,
+    ip: Option<IpAddr>,
+    multipart: &mut Multipart<'_, '_, R>,
+) -> Result<usize, Refused> {
+    let rule_path = format!("/{}", relative.to_string_lossy());
+    let path = host.root.join(relative);
+
+    if !config
+        .allowed_methods(host, &rule_path)
+        .contains(&Method::POST)
+    {
+        return Err(Refused::Reason("uploads aren't allowed here".to_string()));
+    }
+
+    if let Some(response) = check_permission(config, principal, &Method::POST, &rule_path, ip) {
+        return Err(if response.status() == StatusCode::UNAUTHORIZED {
+            Refused::Challenge(response)
+        } else {
+            Refused::Reason("you aren't allowed to upload it".to_string())
+        });
+    }
+
+    if config.hidden.is_hidden(&host.root, relative, false).await {
+        return Err(Refused::Reason("that name isn't allowed".to_string()));
+    }
+
+    if !symlinks_allowed(&host.root, relative, config.symlinks).await {
+        return Err(Refused::Reason(
+            "the path goes through a symlink that isn't allowed".to_string(),
+        ));
+    }
+
+    if is_directory(&path).await {
+        return Err(Refused::Reason(
+            "there's already a directory with that name".to_string(),
+        ));
+    }
+
+    let not_saved = |e: io::Error| {
+        eprintln!("Error: saving {}: {}", path.display(), e);
+        Refused::Reason("it couldn't be saved".to_string())
+    };
+
+    // Dropping the file before it's committed removes what was written of it
+    let mut file = AtomicFile::create(&path).await.map_err(not_saved)?;
+    let limit = config.parse_limits.max_body_for(&rule_path);
+    let mut size = 0;
+
+    while let Some(data) = multipart.next_data().await.map_err(Refused::Body)? {
+        size += data.len();
+        if size > limit {
+            return Err(Refused::Reason(format!(
+                "it's bigger than the {} limit",
+                format_size(limit as u64)
+            )));
+        }
+
+        file.write_all(&data).await.map_err(not_saved)?;
+    }
+
+    file.commit().await.map_err(not_saved)?;
+
+    Ok(size)
+}
+
+/// The name to save an uploaded file as, `None` if there's nothing usable left
+///
+/// Only the last component of a path is kept, so a name can't point anywhere but the directory
+/// it was posted to
+pub fn sanitize_filename(filename: &str) -> Option<PathBuf> {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim();
+
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+
+    Some(PathBuf::from(name))
+}
+
+fn create_upload_result(
+    request: &ByteRequest,
+    config: &ServerConfig,
+    outcomes: &[Outcome],
+) -> ByteResponse {
+    let saved = outcomes.iter().filter(|o| o.result.is_ok()).count();
+    let summary = format!("{} of {} files saved", saved, outcomes.len());
+
+    // 201 only when everything was saved, otherwise the results say what went wrong
+    let status = if saved == outcomes.len() {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+
+    let wants_html = request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+
+    let (body, content_type): (Vec<u8>, _) = if wants_html {
+        let results = outcomes
+            .iter()
+            .map(|o| match &o.result {
+                Ok(size) => format!(
+                    "<li class=\"saved\">{}: saved, {}</li>",
+                    escape_html(&o.name),
+                    format_size(*size as u64)
+                ),
+                Err(reason) => format!(
+                    "<li class=\"failed\">{}: not saved, {}</li>",
+                    escape_html(&o.name),
+                    escape_html(reason)
+                ),
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let path = request.uri().path();
+        let directory = display_name(OsStr::from_bytes(&decode_binary(path.as_bytes())));
+
+        let page = config.templates.upload.render(&[
+            ("path", &directory),
+            ("href", &local_location(path, None)),
+            ("results", &results),
+            ("summary", &summary),
+        ]);
+        (page.into(), "text/html; charset=utf-8")
+    } else {
+        let mut lines = vec![format!("{}: {}", status.as_u16(), summary)];
+        lines.extend(outcomes.iter().map(|o| match &o.result {
+            Ok(size) => format!("'{}' saved, {} bytes", o.name, size),
+            Err(reason) => format!("'{}' not saved, {}", o.name, reason),
+        }));
+        (lines.join("\n").into(), "text/plain")
+    };
+
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONNECTION, "close")
+        .body(Some(body))
+        .unwrap()
+}
+
+/// The response for a form body that couldn't be read, ie. a broken multipart body or a timeout
+fn create_body_error(e: HttpParseError) -> ByteResponse {
+    let message = e.to_string();
+    create_form_error(StatusCode::from(e), &message)
+}
+
+pub fn create_form_error(status: StatusCode, message: &str) -> ByteResponse {
+    let body: Vec<u8> = format!(
+        "{}: {}, {}",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default(),
+        message
+    )
+    .into();
+
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::CONTENT_TYPE, "text/plain")
+        .header(header::CONNECTION, "close")
+        .body(Some(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitizes_filenames() {
+        assert_eq!(sanitize_filename("notes.txt"), Some("notes.txt".into()));
+        assert_eq!(
+            sanitize_filename(" spaced .txt "),
+            Some("spaced .txt".into())
+        );
+        assert_eq!(
+            sanitize_filename("x#y?100%.txt"),
+            Some("x#y?100%.txt".into())
+        );
+        assert_eq!(sanitize_filename("naïve.txt"), Some("naïve.txt".into()));
+    }
+
+    #[test]
+    fn keeps_only_the_last_component() {
+        assert_eq!(sanitize_filename("../../etc/passwd"), Some("passwd".into()));
+        assert_eq!(sanitize_filename("/etc/passwd"), Some("passwd".into()));
+        assert_eq!(
+            sanitize_filename("C:\\Users\\me\\a.txt"),
+            Some("a.txt".into())
+        );
+        assert_eq!(sanitize_filename("a/..\\b.txt"), Some("b.txt".into()));
+    }
+
+    #[test]
+    fn removes_control_characters() {
+        assert_eq!(
+            sanitize_filename("bad\nname\0.txt"),
+            Some("badname.txt".into())
+        );
+        assert_eq!(sanitize_filename("\r\n"), None);
+    }
+
+    #[test]
+    fn refuses_names_with_nothing_left() {
+        for name in ["", " ", ".", "..", "dir/", "a/..", "..\\", "/"] {
+            assert_eq!(sanitize_filename(name), None, "{:?}", name);
+        }
+    }
+}
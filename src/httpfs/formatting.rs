@@ -1,41 +1,147 @@
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
 use mime_guess::{mime, Mime};
+use urlencoding::decode_binary;
 
-use crate::filesystem::DirEntry;
+use crate::{
+    filesystem::DirEntry,
+    httpfs::escape::{display_name, encode_segment, escape_html},
+};
 
 impl DirEntry {
-    pub fn html_format(&self) -> String {
-        let name = self.name.replace('<', "&lt;").replace('>', "&gt;");
-        let mime = self.mime.essence_str();
+    /// A row of the listing table, with forms to rename, move and delete it when `csrf` is given
+    pub fn html_format(&self, csrf: Option<&str>) -> String {
+        let name = escape_html(&display_name(&self.name));
+        let href = encode_segment(&self.name);
 
-        let link = if self.is_directory {
-            format!("📁 <a href=\"{}/\">{}/</a>", name, name)
+        let (icon, slash, kind) = if self.is_directory {
+            ("📁".to_string(), "/", "Directory".to_string())
         } else {
-            format!(
-                "{} <a href=\"{}\">{}</a> <span>{}</span>",
+            (
                 get_mime_emoji(&self.mime),
-                name,
-                name,
-                mime
+                "",
+                escape_html(self.mime.essence_str()),
             )
         };
 
-        format!("<li>{}</li>", link)
+        format!(
+            "<tr data-name=\"{name}\"><td class=\"icon\">{icon}</td><td class=\"name\"><a href=\"{href}{slash}\">{name}{slash}</a></td><td class=\"size\">{size}</td><td class=\"type\">{kind}</td><td class=\"actions\">{actions}</td></tr>",
+            name = name,
+            icon = if self.is_symlink { "🔗" } else { &icon },
+            href = href,
+            slash = slash,
+            size = self.size.map_or_else(|| "—".to_string(), format_size),
+            kind = kind,
+            actions = csrf.map_or_else(String::new, |csrf| format_entry_actions(&href, &name, csrf)),
+        )
     }
 
     pub fn plaintext_format(&self) -> String {
         format!(
-            "{}{} [{}]",
-            self.name,
+            "{}{} [{}{}]",
+            display_name(&self.name),
             if self.is_directory { "/" } else { "" },
             if self.is_directory {
                 "dir"
             } else {
                 self.mime.essence_str()
             },
+            if self.is_symlink { ", symlink" } else { "" },
         )
     }
 }
 
+/// Links to every directory on the way to `path`, a request path like `/docs/2024/`
+pub fn format_breadcrumbs(path: &str) -> String {
+    let mut href = String::from("/");
+    let mut crumbs = vec!["<a href=\"/\">🏠</a>".to_string()];
+
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        href.push_str(segment);
+        href.push('/');
+
+        let name = display_name(OsStr::from_bytes(&decode_binary(segment.as_bytes())));
+        crumbs.push(format!(
+            "<a href=\"{}\">{}</a>",
+            escape_html(&href),
+            escape_html(&name)
+        ));
+    }
+
+    crumbs.join("<span>/</span>")
+}
+
+/// The form on listings of directories that allow uploads, which posts to the directory itself
+///
+/// The listing's script submits it as soon as files are picked or dropped on it
+pub fn format_upload_form(csrf: &str) -> String {
+    format!(
+        "<form id=\"upload\" class=\"upload\" method=\"post\" \
+        enctype=\"multipart/form-data\">{}<label>Drop files here or \
+        <input type=\"file\" name=\"files\" multiple></label>\
+        <noscript> <button type=\"submit\">Upload</button></noscript></form>",
+        format_hidden("csrf", csrf)
+    )
+}
+
+/// The form on listings of directories that allow changes, to make a directory in them
+pub fn format_mkdir_form(csrf: &str) -> String {
+    format!(
+        "<form class=\"mkdir\" method=\"post\">{}\
+        <input name=\"name\" placeholder=\"New folder\" aria-label=\"New folder\" required> \
+        <button name=\"action\" value=\"mkdir\">Create</button></form>",
+        format_hidden("csrf", csrf)
+    )
+}
+
+/// Forms to rename, move and delete one entry, `href` is its encoded name and `name` its escaped one
+fn format_entry_actions(href: &str, name: &str, csrf: &str) -> String {
+    let fields = format!(
+        "{}{}",
+        format_hidden("csrf", csrf),
+        format_hidden("entry", href)
+    );
+
+    format!(
+        "<details><summary>⋯</summary>\
+        <form method=\"post\">{fields}<input name=\"name\" value=\"{name}\" aria-label=\"New name\" required> \
+        <button name=\"action\" value=\"rename\">Rename</button></form>\
+        <form method=\"post\">{fields}<input name=\"to\" placeholder=\"/destination/\" aria-label=\"Move to\" required> \
+        <button name=\"action\" value=\"move\">Move</button></form>\
+        <form method=\"post\" class=\"delete\" data-name=\"{name}\">{fields}\
+        <button name=\"action\" value=\"delete\">Delete</button></form></details>",
+        fields = fields,
+        name = name,
+    )
+}
+
+fn format_hidden(name: &str, value: &str) -> String {
+    format!(
+        "<input type=\"hidden\" name=\"{}\" value=\"{}\">",
+        name,
+        escape_html(value)
+    )
+}
+
+/// A size for people to read, in powers of 1024
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
 fn get_mime_emoji(mime: &Mime) -> String {
     match mime.type_() {
         mime::TEXT => "📝".to_string(),
@@ -53,3 +159,33 @@ fn get_mime_emoji(mime: &Mime) -> String {
         _ => "📄".to_string(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+        assert_eq!(format_size(u64::MAX), "16384.0 PiB");
+    }
+
+    #[test]
+    fn breadcrumbs_link_every_parent() {
+        assert_eq!(format_breadcrumbs("/"), "<a href=\"/\">🏠</a>");
+        assert_eq!(
+            format_breadcrumbs("/docs/2024/"),
+            "<a href=\"/\">🏠</a><span>/</span><a href=\"/docs/\">docs</a>\
+            <span>/</span><a href=\"/docs/2024/\">2024</a>"
+        );
+    }
+
+    #[test]
+    fn breadcrumbs_escape_names() {
+        let crumbs = format_breadcrumbs("/%3Cb%3E%22/");
+        assert!(crumbs.contains("<a href=\"/%3Cb%3E%22/\">&lt;b&gt;&quot;</a>"));
+    }
+}
@@ -1,21 +1,41 @@
-use std::{collections::HashMap, path::Path};
+use std::{collections::HashMap, ffi::OsStr, os::unix::ffi::OsStrExt, path::Path};
 
-use http::{header, Response};
+use http::{header, Method, Response, StatusCode};
+use urlencoding::decode_binary;
 
 use crate::filesystem::{get_directory, get_file, is_directory, DirEntry};
 
 use super::{
+    auth::Principal,
+    config::ServerConfig,
+    escape::display_name,
+    formatting::{format_breadcrumbs, format_mkdir_form, format_upload_form},
     message::{ByteRequest, ByteResponse},
     parse::parse_query,
+    redirect::{create_redirect, local_location},
     server::UnrecoverableError,
+    vhost::VirtualHost,
 };
 
 pub async fn handle_get(
     request: &ByteRequest,
+    config: &ServerConfig,
+    host: &VirtualHost,
     path: impl AsRef<Path>,
 ) -> Result<ByteResponse, UnrecoverableError> {
     if is_directory(&path).await {
-        serve_directory(request, path).await
+        // Listings link relative to the directory, so `/docs` has to become `/docs/` first
+        if !request.uri().path().ends_with('/') {
+            let location =
+                local_location(&format!("{}/", request.uri().path()), request.uri().query());
+            return Ok(create_redirect(StatusCode::MOVED_PERMANENTLY, &location));
+        }
+
+        if !host.listing {
+            return create_403(request.uri().path());
+        }
+
+        serve_directory(request, config, host, path).await
     } else {
         serve_file(request, path).await
     }
@@ -23,13 +43,25 @@ pub async fn handle_get(
 
 async fn serve_directory(
     request: &ByteRequest,
+    config: &ServerConfig,
+    host: &VirtualHost,
     path: impl AsRef<Path>,
 ) -> Result<ByteResponse, UnrecoverableError> {
-    let mut entries = match get_directory(&path).await {
+    let directory = path
+        .as_ref()
+        .strip_prefix(&host.root)
+        .unwrap_or(Path::new(""));
+
+    let mut entries = match get_directory(&host.root, directory, config.symlinks).await {
         Some(entries) => entries,
         None => return create_404(request.uri().path()),
     };
 
+    entries = config
+        .hidden
+        .visible_entries(&host.root, directory, entries)
+        .await;
+
     let accept = request.headers().get(header::ACCEPT);
     let mut use_html = false;
 
@@ -45,18 +77,30 @@ async fn serve_directory(
     entries.sort_unstable();
 
     let body: Vec<u8> = if use_html {
-        let parent = path
-            .as_ref()
-            .file_name()
-            .map(|f| f.to_string_lossy().to_string())
-            .unwrap_or_else(|| String::from("/"));
-
-        format_directory_html(&parent, &entries)
+        let rule_path = format!("/{}", directory.to_string_lossy());
+
+        // Forms are only shown where they could work, the rules are checked when they're sent
+        let csrf = config
+            .allowed_methods(host, &rule_path)
+            .contains(&Method::POST)
+            .then(|| {
+                let principal = request
+                    .extensions()
+                    .get::<Principal>()
+                    .unwrap_or(&Principal::Anonymous);
+                config.csrf.issue(principal, &host.name, &rule_path)
+            });
+
+        format_directory_html(request, config, &entries, csrf.as_deref())
     } else {
         format_directory_plaintext(&entries)
     };
 
-    let content_type = if use_html { "text/html" } else { "text/plain" };
+    let content_type = if use_html {
+        "text/html; charset=utf-8"
+    } else {
+        "text/plain"
+    };
 
     let response: ByteResponse = Response::builder()
         .status(200)
@@ -68,19 +112,39 @@ async fn serve_directory(
     Ok(response)
 }
 
-fn format_directory_html(title: &str, entries: &[DirEntry]) -> Vec<u8> {
-    let entries = entries
+fn format_directory_html(
+    request: &ByteRequest,
+    config: &ServerConfig,
+    entries: &[DirEntry],
+    csrf: Option<&str>,
+) -> Vec<u8> {
+    let rows = entries
         .iter()
-        .map(|e| e.html_format())
+        .map(|e| e.html_format(csrf))
         .collect::<Vec<String>>()
         .join("\n");
 
-    format!(
-        "<!DOCTYPE html>\n<html lang=\"en\">\n<head><title>{}</title><meta charset=\"UTF-8\"><meta name=\"viewport\" content=\"width=device-width, initial-scale=1.0\"></head><body>\n<ul>\n{}\n</ul>\n</body></html>",
-        title,
-        entries,
-    )
-    .into()
+    let path = request.uri().path();
+    let title = display_name(OsStr::from_bytes(&decode_binary(path.as_bytes())));
+
+    config
+        .templates
+        .listing
+        .render(&[
+            ("title", &title),
+            ("breadcrumbs", &format_breadcrumbs(path)),
+            (
+                "upload_form",
+                &csrf.map(format_upload_form).unwrap_or_default(),
+            ),
+            (
+                "mkdir_form",
+                &csrf.map(format_mkdir_form).unwrap_or_default(),
+            ),
+            ("rows", &rows),
+            ("count", &entries.len().to_string()),
+        ])
+        .into()
 }
 
 fn format_directory_plaintext(entries: &[DirEntry]) -> Vec<u8> {
@@ -103,7 +167,7 @@ async fn serve_file(
     // maybe this should be a boolean like ?download=1 or ?download=0
     // but who cares, there isn't some kind of spec we need to follow here
     // and i don't want to have ["false", "0", "no", "FALSE", etc] as "falsy"
-    let disposition = if query.get("download").is_some() {
+    let disposition = if query.contains_key("download") {
         "attachment"
     } else {
         "inline"
@@ -123,7 +187,7 @@ async fn serve_file(
     Ok(response)
 }
 
-fn create_404(path: &str) -> Result<ByteResponse, UnrecoverableError> {
+pub fn create_404(path: &str) -> Result<ByteResponse, UnrecoverableError> {
     let body: Vec<u8> = format!("404: '{}' not found!", path).into();
 
     Ok(Response::builder()
@@ -133,3 +197,14 @@ fn create_404(path: &str) -> Result<ByteResponse, UnrecoverableError> {
         .header(header::CONNECTION, "close")
         .body(Some(body))?)
 }
+
+fn create_403(path: &str) -> Result<ByteResponse, UnrecoverableError> {
+    let body: Vec<u8> = format!("403: Listing '{}' is not allowed!", path).into();
+
+    Ok(Response::builder()
+        .status(403)
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::CONTENT_TYPE, "text/plain")
+        .header(header::CONNECTION, "close")
+        .body(Some(body))?)
+}
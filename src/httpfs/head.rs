@@ -1,19 +1,23 @@
 use std::path::Path;
 
 use super::{
+    config::ServerConfig,
     get::handle_get,
     message::{ByteRequest, ByteResponse},
     server::UnrecoverableError,
+    vhost::VirtualHost,
 };
 
 pub async fn handle_head(
     request: &ByteRequest,
+    config: &ServerConfig,
+    host: &VirtualHost,
     path: impl AsRef<Path>,
 ) -> Result<ByteResponse, UnrecoverableError> {
     // HEAD is GET but without body
     // could be optimized by not pulling the body in the first place
     // but this is easier and we dont really need optimization
-    let mut response = handle_get(request, path).await?;
+    let mut response = handle_get(request, config, host, path).await?;
     response.body_mut().take();
 
     Ok(response)
@@ -0,0 +1,344 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    time::SystemTime,
+};
+
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use tokio::task::spawn_blocking;
+
+use crate::filesystem::DirEntry;
+
+/// The ignore files read with `--ignore-files`, `.ignore` wins if they disagree
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+/// How many directories' ignore files are kept parsed, the cache starts over once it's full
+const MAX_CACHED_DIRECTORIES: usize = 4096;
+
+/// Files that are left out of listings and can't be read or written, as if they didn't exist
+///
+/// Checking reads the filesystem, so it's done on the blocking thread pool. Cloning is cheap and
+/// clones share the cache
+#[derive(Debug, Clone)]
+pub struct HiddenFiles {
+    /// Hide anything with a path segment starting with `.`, like `.git/` or `.env`
+    dotfiles: bool,
+    /// `--hide` patterns, in .gitignore syntax
+    patterns: Arc<Gitignore>,
+    /// Also hide what `.gitignore` and `.ignore` files in the served directories ignore
+    ignore_files: bool,
+    /// The ignore files of each directory, only read again once they change
+    cache: Arc<Mutex<HashMap<PathBuf, CachedIgnore>>>,
+}
+
+#[derive(Debug)]
+struct CachedIgnore {
+    /// When each of `IGNORE_FILES` was last modified, `None` if it isn't there
+    modified: [Option<SystemTime>; 2],
+    ignore: Arc<Gitignore>,
+}
+
+impl HiddenFiles {
+    pub fn new(dotfiles: bool, patterns: &[String], ignore_files: bool) -> io::Result<Self> {
+        // Rooted at "" since the patterns are matched against paths relative to the served directory
+        let mut builder = GitignoreBuilder::new("");
+
+        for pattern in patterns {
+            builder.add_line(None, pattern).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid --hide pattern '{}': {}", pattern, e),
+                )
+            })?;
+        }
+
+        let patterns = builder
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        Ok(Self {
+            dotfiles,
+            patterns: Arc::new(patterns),
+            ignore_files,
+            cache: Arc::default(),
+        })
+    }
+
+    /// Whether `relative`, a path inside `root`, is hidden, or is a symlink to something that is
+    pub async fn is_hidden(&self, root: &Path, relative: &Path, is_dir: bool) -> bool {
+        let (hidden, root, relative) = (self.clone(), root.to_owned(), relative.to_owned());
+
+        spawn_blocking(move || {
+            let parent = relative.parent().unwrap_or_else(|| Path::new(""));
+            hidden
+                .in_directory(&root, parent)
+                .is_hidden(&relative, is_dir)
+        })
+        .await
+        // Only a panic gets here, better to hide a file by mistake than to show it
+        .unwrap_or(true)
+    }
+
+    /// Leave the hidden ones out of `entries`, the listing of `directory`, a path inside `root`
+    pub async fn visible_entries(
+        &self,
+        root: &Path,
+        directory: &Path,
+        entries: Vec<DirEntry>,
+    ) -> Vec<DirEntry> {
+        let (hidden, root, directory) = (self.clone(), root.to_owned(), directory.to_owned());
+
+        spawn_blocking(move || {
+            let filter = hidden.in_directory(&root, &directory);
+            entries
+                .into_iter()
+                .filter(|e| !filter.is_hidden(&directory.join(&e.name), e.is_directory))
+                .collect()
+        })
+        .await
+        .unwrap_or_default()
+    }
+
+    /// Get a filter for the entries of `directory`, a path inside `root`
+    ///
+    /// This looks up the ignore files once, instead of once per entry
+    fn in_directory<'a>(&'a self, root: &'a Path, directory: &Path) -> DirectoryFilter<'a> {
+        let mut ignore_files = vec![];
+
+        if self.ignore_files {
+            let mut current = root.to_path_buf();
+            ignore_files.push(self.load_ignore_files(&current));
+
+            for component in directory.components() {
+                current.push(component);
+                ignore_files.push(self.load_ignore_files(&current));
+            }
+
+            // Deeper ignore files override the ones above them
+            ignore_files.reverse();
+        }
+
+        DirectoryFilter {
+            hidden: self,
+            root,
+            real_root: root.canonicalize().ok(),
+            ignore_files,
+        }
+    }
+
+    /// The ignore files in `directory`, from the cache unless they changed since they were read
+    fn load_ignore_files(&self, directory: &Path) -> Arc<Gitignore> {
+        let modified = IGNORE_FILES.map(|name| {
+            fs::metadata(directory.join(name))
+                .ok()
+                .filter(|m| m.is_file())
+                .and_then(|m| m.modified().ok())
+        });
+
+        let lock = || self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(cached) = lock().get(directory) {
+            if cached.modified == modified {
+                return cached.ignore.clone();
+            }
+        }
+
+        let ignore = Arc::new(read_ignore_files(directory));
+
+        let mut cache = lock();
+        if cache.len() >= MAX_CACHED_DIRECTORIES {
+            cache.clear();
+        }
+        cache.insert(
+            directory.to_path_buf(),
+            CachedIgnore {
+                modified,
+                ignore: ignore.clone(),
+            },
+        );
+
+        ignore
+    }
+}
+
+/// Decides which entries of a single directory are hidden
+struct DirectoryFilter<'a> {
+    hidden: &'a HiddenFiles,
+    root: &'a Path,
+    /// `root` with every symlink followed, `None` if it doesn't exist
+    real_root: Option<PathBuf>,
+    /// Ignore files from the directory up to the root, deepest first
+    ignore_files: Vec<Arc<Gitignore>>,
+}
+
+impl DirectoryFilter<'_> {
+    /// Whether `relative`, a path inside the root, is hidden
+    ///
+    /// Where it really is after following symlinks is checked too, so `public -> .git` doesn't
+    /// show what's in `.git`
+    fn is_hidden(&self, relative: &Path, is_dir: bool) -> bool {
+        if self.is_hidden_here(relative, is_dir) {
+            return true;
+        }
+
+        match self.resolve(relative) {
+            Some(target) if target != relative => {
+                let parent = target.parent().unwrap_or_else(|| Path::new(""));
+                self.hidden
+                    .in_directory(self.root, parent)
+                    .is_hidden_here(&target, is_dir)
+            }
+            _ => false,
+        }
+    }
+
+    /// Where `relative` really is inside the root after following symlinks, `None` if that's
+    /// outside it, which the symlink policy decides about
+    fn resolve(&self, relative: &Path) -> Option<PathBuf> {
+        let real_root = self.real_root.as_ref()?;
+        let path = self.root.join(relative);
+
+        let real = match path.canonicalize() {
+            Ok(real) => real,
+            // Something about to be made, like an upload, is wherever its directory leads
+            Err(_) => path.parent()?.canonicalize().ok()?.join(path.file_name()?),
+        };
+
+        real.strip_prefix(real_root).ok().map(Path::to_path_buf)
+    }
+
+    /// Whether `relative` is hidden going by its path alone
+    fn is_hidden_here(&self, relative: &Path, is_dir: bool) -> bool {
+        // The root itself is never hidden
+        if relative.as_os_str().is_empty() {
+            return false;
+        }
+
+        if self.hidden.dotfiles && relative.components().any(is_dotfile) {
+            return true;
+        }
+
+        if self
+            .hidden
+            .patterns
+            .matched_path_or_any_parents(relative, is_dir)
+            .is_ignore()
+        {
+            return true;
+        }
+
+        let path = self.root.join(relative);
+
+        for ignore in &self.ignore_files {
+            match ignore.matched_path_or_any_parents(&path, is_dir) {
+                Match::Ignore(_) => return true,
+                // `!pattern` un-ignores a file an ignore file further up ignored
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+
+        false
+    }
+}
+
+/// `.gitignore` and `.ignore` in a directory, with `.ignore` winning if they disagree
+fn read_ignore_files(directory: &Path) -> Gitignore {
+    let mut builder = GitignoreBuilder::new(directory);
+
+    for name in IGNORE_FILES {
+        let path = directory.join(name);
+        if path.is_file() {
+            if let Some(e) = builder.add(&path) {
+                eprintln!("Problem in {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    builder.build().unwrap_or_else(|_| Gitignore::empty())
+}
+
+fn is_dotfile(component: Component) -> bool {
+    matches!(component, Component::Normal(name) if name.to_string_lossy().starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_dotfiles() {
+        assert!(is_dotfile(Component::Normal(".git".as_ref())));
+        assert!(!is_dotfile(Component::Normal("src".as_ref())));
+    }
+
+    #[tokio::test]
+    async fn hides_symlinks_to_hidden_files() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        std::fs::create_dir_all(root.join(".git/objects")).unwrap();
+        std::fs::create_dir(root.join("docs")).unwrap();
+        std::os::unix::fs::symlink(".git", root.join("public")).unwrap();
+        std::os::unix::fs::symlink("docs", root.join("manual")).unwrap();
+
+        let hidden = HiddenFiles::new(true, &[], false).unwrap();
+
+        assert!(hidden.is_hidden(root, Path::new("public"), true).await);
+        assert!(
+            hidden
+                .is_hidden(root, Path::new("public/objects"), true)
+                .await
+        );
+        // An upload into it
+        assert!(
+            hidden
+                .is_hidden(root, Path::new("public/new.txt"), false)
+                .await
+        );
+        assert!(!hidden.is_hidden(root, Path::new("manual"), true).await);
+        assert!(
+            !hidden
+                .is_hidden(root, Path::new("manual/new.txt"), false)
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_ignore_files_again_once_they_change() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        std::fs::create_dir(root.join("logs")).unwrap();
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(root.join("logs/.ignore"), "!keep.*\n").unwrap();
+
+        let hidden = HiddenFiles::new(false, &[], true).unwrap();
+        let is_hidden = |path: &'static str| hidden.is_hidden(root, Path::new(path), false);
+
+        assert!(is_hidden("a.log").await);
+        assert!(is_hidden("logs/a.log").await);
+        assert!(!is_hidden("logs/keep.log").await);
+        assert!(!is_hidden("a.txt").await);
+
+        // Later than it could be by the clock, so it's changed even on coarse timestamps
+        std::fs::write(root.join(".gitignore"), "*.txt\n").unwrap();
+        let file = std::fs::File::options()
+            .write(true)
+            .open(root.join(".gitignore"))
+            .unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(10))
+            .unwrap();
+
+        assert!(!is_hidden("a.log").await);
+        assert!(is_hidden("a.txt").await);
+
+        // Un-ignored by logs/.ignore until it's gone
+        assert!(!is_hidden("logs/keep.txt").await);
+        std::fs::remove_file(root.join("logs/.ignore")).unwrap();
+        assert!(is_hidden("logs/keep.txt").await);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use http::{header, Response};
+
+use crate::httpfs::message::ByteResponse;
+
+/// Caps on how many connections are served at once
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConnectionLimits {
+    /// For the whole server, connections past this get a 503
+    pub total: Option<usize>,
+    /// For each client IP, connections past this get a 429
+    pub per_ip: Option<usize>,
+}
+
+/// How long clients that hit a connection limit are told to wait
+pub const CONNECTION_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Per-IP token bucket request rate limits
+///
+/// Every IP gets a bucket of `burst` tokens that refills at `rate` tokens per second,
+/// and each request takes one token. Requests when the bucket is empty are throttled
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Stop tracking buckets that refilled completely, once there are this many
+const PRUNE_AFTER: usize = 1024;
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token for a request from `ip`
+    ///
+    /// Returns how long to wait before retrying if there are none left
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        if buckets.len() >= PRUNE_AFTER {
+            buckets.retain(|_, bucket| self.refilled(bucket, now) < self.burst);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// How many IPs currently have a bucket
+    pub fn tracked(&self) -> usize {
+        self.buckets.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
+/// A client is making too many requests or connections
+pub fn create_429(retry_after: Duration) -> ByteResponse {
+    create_throttled(429, "Too many requests", retry_after)
+}
+
+/// The server is serving as many connections as it's allowed to
+pub fn create_503(retry_after: Duration) -> ByteResponse {
+    create_throttled(503, "Server is busy", retry_after)
+}
+
+fn create_throttled(status: u16, message: &str, retry_after: Duration) -> ByteResponse {
+    let body: Vec<u8> = format!("{}: {}, try again later", status, message).into();
+
+    Response::builder()
+        .status(status)
+        .header(header::RETRY_AFTER, retry_after_secs(retry_after))
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::CONTENT_TYPE, "text/plain")
+        .header(header::CONNECTION, "close")
+        .body(Some(body))
+        .unwrap()
+}
+
+/// Retry-After is in whole seconds, round up so clients don't come back too early
+pub fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs_f64().ceil().max(1.0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(n: u8) -> IpAddr {
+        [192, 0, 2, n].into()
+    }
+
+    #[test]
+    fn allows_bursts_then_throttles() {
+        let limiter = RateLimiter::new(2.0, 3);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(ip(1), start), Ok(()));
+        }
+        assert_eq!(
+            limiter.check_at(ip(1), start),
+            Err(Duration::from_millis(500))
+        );
+
+        // Other clients have their own bucket
+        assert_eq!(limiter.check_at(ip(2), start), Ok(()));
+    }
+
+    #[test]
+    fn refills_at_the_rate() {
+        let limiter = RateLimiter::new(2.0, 3);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            limiter.check_at(ip(1), start).unwrap();
+        }
+
+        // Half a token back, so a quarter second more for the rest of one
+        let later = start + Duration::from_millis(250);
+        assert_eq!(
+            limiter.check_at(ip(1), later),
+            Err(Duration::from_millis(250))
+        );
+        assert_eq!(
+            limiter.check_at(ip(1), start + Duration::from_millis(500)),
+            Ok(())
+        );
+
+        // Never more than the burst, however long the client waited
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(ip(1), much_later), Ok(()));
+        }
+        assert!(limiter.check_at(ip(1), much_later).is_err());
+    }
+
+    #[test]
+    fn prunes_full_buckets() {
+        let limiter = RateLimiter::new(1.0, 2);
+        let start = Instant::now();
+
+        for n in 0..PRUNE_AFTER {
+            let ip = IpAddr::from([10, 0, (n / 256) as u8, (n % 256) as u8]);
+            limiter.check_at(ip, start).unwrap();
+        }
+        // Still waiting on its second token
+        limiter.check_at(ip(1), start).unwrap();
+        limiter.check_at(ip(1), start).unwrap();
+        assert_eq!(limiter.tracked(), PRUNE_AFTER + 1);
+
+        // Everyone else refilled, and the new client gets a bucket
+        let later = start + Duration::from_millis(1500);
+        limiter.check_at(ip(2), later).unwrap();
+        assert_eq!(limiter.tracked(), 2);
+    }
+
+    #[test]
+    fn rounds_retry_after_up() {
+        assert_eq!(retry_after_secs(Duration::ZERO), 1);
+        assert_eq!(retry_after_secs(Duration::from_millis(1)), 1);
+        assert_eq!(retry_after_secs(Duration::from_secs(2)), 2);
+        assert_eq!(retry_after_secs(Duration::from_millis(2500)), 3);
+    }
+}
@@ -0,0 +1,376 @@
+use std::{
+    ffi::CString,
+    fmt,
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UnixListener},
+};
+
+use crate::filesystem::rename_no_replace_blocking;
+
+/// Any stream a connection can be served over, TCP or Unix socket
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+/// Who is on the other end of a connection
+#[derive(Debug, Clone)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// Unix socket peers are almost always unnamed, so we only know which socket they used
+    Unix(PathBuf),
+    /// A client a load balancer passed on with a PROXY protocol header
+    Proxied {
+        client: SocketAddr,
+        proxy: Box<PeerAddr>,
+    },
+}
+
+impl PeerAddr {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PeerAddr::Tcp(addr) | PeerAddr::Proxied { client: addr, .. } => Some(addr.ip()),
+            PeerAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            PeerAddr::Proxied { client, proxy } => write!(f, "{} via {}", client, proxy),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub async fn accept(&self) -> io::Result<(Box<dyn AsyncStream>, PeerAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::new(stream), PeerAddr::Tcp(addr)))
+            }
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), PeerAddr::Unix(path.clone())))
+            }
+        }
+    }
+
+    /// The address we're actually listening on, which for TCP includes the port picked for port 0
+    pub fn local_addr(&self) -> io::Result<String> {
+        match self {
+            Listener::Tcp(listener) => Ok(listener.local_addr()?.to_string()),
+            Listener::Unix(_, path) => Ok(format!("unix:{}", path.display())),
+        }
+    }
+}
+
+/// Settings for `--unix` sockets
+#[derive(Debug, Clone, Default)]
+pub struct UnixSocketOptions {
+    /// File mode to set on the socket, ie. `0o660`
+    pub mode: Option<u32>,
+    /// Owner to set on the socket, as `USER[:GROUP]`
+    pub owner: Option<String>,
+}
+
+/// Parse an octal file mode like `660` or `0o660`
+pub fn parse_mode(arg: &str) -> Result<u32, String> {
+    let digits = arg.trim_start_matches("0o");
+    u32::from_str_radix(digits, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("Expected an octal file mode like 660, got '{}'", arg))
+}
+
+/// An address given to `--bind`, optionally without a port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindAddress {
+    /// `127.0.0.1:8080`, `[::]:8080`, `localhost:8080`
+    WithPort(String),
+    /// `0.0.0.0`, `::`, uses the port from `--port`
+    WithoutPort(IpAddr),
+}
+
+impl BindAddress {
+    fn with_default_port(&self, port: u16) -> String {
+        match self {
+            BindAddress::WithPort(addr) => addr.clone(),
+            BindAddress::WithoutPort(ip) => SocketAddr::new(*ip, port).to_string(),
+        }
+    }
+}
+
+/// Parse a `--bind` argument
+/// ```
+/// assert_eq!(parse_bind("0.0.0.0"), Ok(BindAddress::WithoutPort("0.0.0.0".parse().unwrap())));
+/// assert_eq!(parse_bind("::"), Ok(BindAddress::WithoutPort("::".parse().unwrap())));
+/// assert_eq!(parse_bind("[::]:8080"), Ok(BindAddress::WithPort("[::]:8080".to_string())));
+/// assert_eq!(parse_bind("localhost:0"), Ok(BindAddress::WithPort("localhost:0".to_string())));
+/// ```
+pub fn parse_bind(arg: &str) -> Result<BindAddress, String> {
+    if let Ok(ip) = arg.trim_start_matches('[').trim_end_matches(']').parse() {
+        return Ok(BindAddress::WithoutPort(ip));
+    }
+
+    if let Ok(addr) = arg.parse::<SocketAddr>() {
+        return Ok(BindAddress::WithPort(addr.to_string()));
+    }
+
+    // Anything else needs to be resolved, like `localhost:8080`
+    match arg.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+            Ok(BindAddress::WithPort(arg.to_string()))
+        }
+        _ => Err(format!(
+            "Expected an IP address or ADDR:PORT, got '{}'",
+            arg
+        )),
+    }
+}
+
+/// The address we listen on when no other listeners are given
+pub fn default_bind() -> BindAddress {
+    BindAddress::WithoutPort(IpAddr::from([127, 0, 0, 1]))
+}
+
+pub async fn bind_tcp(bind: &BindAddress, port: u16) -> io::Result<Listener> {
+    let addr = bind.with_default_port(port);
+    let listener = TcpListener::bind(&addr)
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to bind {}: {}", addr, e)))?;
+
+    Ok(Listener::Tcp(listener))
+}
+
+pub fn bind_unix(path: &Path, options: &UnixSocketOptions) -> io::Result<Listener> {
+    let listener = bind_unix_socket(path, options).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to bind unix:{}: {}", path.display(), e),
+        )
+    })?;
+
+    Ok(Listener::Unix(listener, path.to_path_buf()))
+}
+
+fn bind_unix_socket(path: &Path, options: &UnixSocketOptions) -> io::Result<UnixListener> {
+    remove_stale_socket(path)?;
+
+    // Bind in a directory only we can enter and move the socket into place once its mode and
+    // owner are set, so nobody can connect while it's still open to everyone
+    let private = PrivateDir::create(path)?;
+    let bound = private.0.join("s");
+    let listener = UnixListener::bind(&bound)?;
+
+    if let Some(mode) = options.mode {
+        std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(mode))?;
+    }
+
+    if let Some(owner) = &options.owner {
+        let (uid, gid) = lookup_owner(owner)?;
+        std::os::unix::fs::chown(&bound, uid, gid)?;
+    }
+
+    rename_no_replace_blocking(&bound, path)?;
+    Ok(listener)
+}
+
+/// A directory next to a socket's path that only we can use, removed with whatever is left in it
+struct PrivateDir(PathBuf);
+
+impl PrivateDir {
+    fn create(socket: &Path) -> io::Result<Self> {
+        let parent = match socket.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        static BIND_ID: AtomicU64 = AtomicU64::new(0);
+
+        let dir = parent.join(format!(
+            ".httpfs-{}-{}",
+            std::process::id(),
+            BIND_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        Ok(Self(dir))
+    }
+}
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A previous run that crashed (or was killed) leaves its socket file behind, which makes `bind` fail
+///
+/// We only remove the file if it's a socket nobody is listening on anymore, so we don't delete
+/// random files or steal the socket from a server that's still running
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            "path exists and is not a socket",
+        ));
+    }
+
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            ErrorKind::AddrInUse,
+            "another server is already listening on this socket",
+        )),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+/// Resolve `USER[:GROUP]` (names or numeric ids) into a uid and gid for `chown`
+/// ```
+/// lookup_owner("www-data:www-data")
+/// lookup_owner("1000")
+/// lookup_owner(":nginx")
+/// ```
+fn lookup_owner(owner: &str) -> io::Result<(Option<u32>, Option<u32>)> {
+    let (user, group) = match owner.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (owner, None),
+    };
+
+    let uid = match user {
+        "" => None,
+        user => Some(match user.parse() {
+            Ok(uid) => uid,
+            Err(_) => lookup_user(user)?,
+        }),
+    };
+
+    let gid = match group {
+        None | Some("") => None,
+        Some(group) => Some(match group.parse() {
+            Ok(gid) => gid,
+            Err(_) => lookup_group(group)?,
+        }),
+    };
+
+    Ok((uid, gid))
+}
+
+fn lookup_user(name: &str) -> io::Result<u32> {
+    let c_name = CString::new(name).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+
+    // SAFETY: all pointers are valid for the duration of the call and buf.len() is its real size
+    let status = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+
+    if result.is_null() {
+        return Err(not_found_or_os_error(status, "user", name));
+    }
+
+    Ok(passwd.pw_uid)
+}
+
+fn lookup_group(name: &str) -> io::Result<u32> {
+    let c_name = CString::new(name).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result: *mut libc::group = std::ptr::null_mut();
+
+    // SAFETY: all pointers are valid for the duration of the call and buf.len() is its real size
+    let status = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut group,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+
+    if result.is_null() {
+        return Err(not_found_or_os_error(status, "group", name));
+    }
+
+    Ok(group.gr_gid)
+}
+
+fn not_found_or_os_error(status: libc::c_int, kind: &str, name: &str) -> io::Error {
+    if status == 0 {
+        io::Error::new(ErrorKind::NotFound, format!("Unknown {} '{}'", kind, name))
+    } else {
+        io::Error::from_raw_os_error(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn binds_sockets_with_their_mode_already_set() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("httpfs.sock");
+        let options = UnixSocketOptions {
+            mode: Some(0o600),
+            ..Default::default()
+        };
+
+        let listener = bind_unix_socket(&path, &options).unwrap();
+
+        let metadata = std::fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+        let entries = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(entries, 1, "the private directory was left behind");
+
+        let client = tokio::net::UnixStream::connect(&path);
+        let (accepted, connected) = tokio::join!(listener.accept(), client);
+        accepted.unwrap();
+        connected.unwrap();
+    }
+
+    #[tokio::test]
+    async fn never_replaces_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("httpfs.sock");
+        std::fs::write(&path, "not a socket").unwrap();
+
+        let error = bind_unix_socket(&path, &UnixSocketOptions::default()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&path).unwrap(), b"not a socket");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -5,6 +5,8 @@ use owo_colors::{OwoColorize, Style};
 
 use crate::{
     colorize::MColorize,
+    httpfs::auth::Principal,
+    httpfs::client_ip::ClientIp,
     httpfs::message::{
         ByteRequest, ByteResponse, RequestMessage, RequestStyles, ResponseMessage, ResponseStyles,
     },
@@ -12,8 +14,20 @@ use crate::{
 };
 
 pub fn log_request_response_short(request: &ByteRequest, response: &ByteResponse) {
+    // Only requests from logged in users get a username, everything else is anonymous
+    let user = match request.extensions().get::<Principal>() {
+        Some(Principal::Anonymous) | None => String::new(),
+        Some(principal) => format!(" [{}]", principal),
+    };
+
+    // Requests through a trusted proxy would all look like they came from it otherwise
+    let client = match request.extensions().get::<ClientIp>() {
+        Some(client) if client.forwarded => format!(" for {}", client),
+        _ => String::new(),
+    };
+
     println!(
-        "{} {} → {} {}",
+        "{} {} → {} {}{}{}",
         request
             .method()
             .out_color(|t| t.style(method_style(request.method()))),
@@ -27,7 +41,9 @@ pub fn log_request_response_short(request: &ByteRequest, response: &ByteResponse
             .unwrap_or(&"<unknown>".parse().unwrap())
             .to_str()
             .unwrap_or("<unknown>")
-            .out_color(|t| t.bright_black())
+            .out_color(|t| t.bright_black()),
+        user.out_color(|t| t.magenta()),
+        client.out_color(|t| t.bright_yellow()),
     );
 }
 
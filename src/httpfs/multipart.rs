@@ -0,0 +1,535 @@
+use http::header;
+use tokio::io::AsyncBufRead;
+use urlencoding::decode;
+
+use crate::httpfs::{message::ByteRequest, parse::BodyReader, parse_error::HttpParseError};
+
+/// Most parts in one form, so a body of empty parts can't keep us busy forever
+const MAX_PARTS: usize = 1000;
+/// Most bytes of headers in one part
+const MAX_PART_HEADER_BYTES: usize = 8 * 1024;
+/// Most whitespace after a delimiter
+const MAX_DELIMITER_PADDING: usize = 1024;
+/// Most bytes read from the body at once
+const READ_SIZE: usize = 16 * 1024;
+
+/// The headers of one field of a `multipart/form-data` body
+#[derive(Debug, PartialEq, Eq)]
+pub struct PartHead {
+    pub name: Option<String>,
+    /// Only set for files, and exactly what the client sent, so it still needs sanitizing
+    pub filename: Option<String>,
+}
+
+/// The boundary of a request's `multipart/form-data` body, `None` for any other body
+pub fn request_boundary(request: &ByteRequest) -> Option<String> {
+    request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(boundary)
+}
+
+/// The boundary of a `multipart/form-data` content type, `None` for any other type
+fn boundary(content_type: &str) -> Option<String> {
+    let (essence, params) = content_type.split_once(';')?;
+
+    if !essence.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+
+    parse_params(params)
+        .into_iter()
+        .find(|(key, _)| key == "boundary")
+        .map(|(_, value)| value)
+        .filter(|boundary| (1..=70).contains(&boundary.len()))
+}
+
+/// Splits a `multipart/form-data` body into its parts as it arrives, so files never have to fit
+/// in memory
+///
+/// `next_part` moves on to the next part, and `next_data` reads the data of the current one
+pub struct Multipart<'a, 'b, R> {
+    body: &'a mut BodyReader<'b, R>,
+    /// Read but not handled yet, never more than a read and a delimiter while reading data
+    buffer: Vec<u8>,
+    /// `\r\n--boundary`, every delimiter after the first starts on a new line, which isn't part of
+    /// the data before it
+    delimiter: Vec<u8>,
+    /// Whether `buffer` starts with data, rather than right after a delimiter
+    in_data: bool,
+    done: bool,
+    parts: usize,
+}
+
+impl<'a, 'b, R: AsyncBufRead + Unpin> Multipart<'a, 'b, R> {
+    pub fn new(body: &'a mut BodyReader<'b, R>, boundary: &str) -> Self {
+        Self {
+            body,
+            // The first delimiter can start the body, the preamble before it is skipped like data
+            buffer: b"\r\n".to_vec(),
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            in_data: true,
+            done: false,
+            parts: 0,
+        }
+    }
+
+    /// Skip to the next part and read its headers, `None` after the last one
+    pub async fn next_part(&mut self) -> Result<Option<PartHead>, HttpParseError> {
+        while self.next_data().await?.is_some() {}
+
+        if self.done {
+            return Ok(None);
+        }
+
+        self.fill_to(2).await?;
+        if self.buffer.starts_with(b"--") {
+            // Nothing after the closing delimiter means anything, but it's read so the client
+            // isn't cut off while sending it
+            self.done = true;
+            self.buffer.clear();
+            let mut rest = vec![0; READ_SIZE];
+            while self.body.read(&mut rest).await? > 0 {}
+            return Ok(None);
+        }
+
+        if self.parts == MAX_PARTS {
+            return Err(invalid(&format!("more than {} parts", MAX_PARTS)));
+        }
+        self.parts += 1;
+
+        // Senders may pad the delimiter line with whitespace
+        let line_end = self
+            .find_filling(
+                b"\r\n",
+                MAX_DELIMITER_PADDING,
+                "a delimiter line doesn't end",
+            )
+            .await?;
+        if self.buffer[..line_end]
+            .iter()
+            .any(|b| !matches!(b, b' ' | b'\t'))
+        {
+            return Err(invalid("a delimiter is followed by more text"));
+        }
+        self.buffer.drain(..line_end + 2);
+
+        // A part with no headers starts straight away with the blank line
+        self.fill_to(2).await?;
+        let header_end = if self.buffer.starts_with(b"\r\n") {
+            0
+        } else {
+            let too_long = format!("a part's headers are over {} bytes", MAX_PART_HEADER_BYTES);
+            self.find_filling(b"\r\n\r\n", MAX_PART_HEADER_BYTES, &too_long)
+                .await?
+                + 2
+        };
+
+        let headers = String::from_utf8_lossy(&self.buffer[..header_end]).into_owned();
+        self.buffer.drain(..header_end + 2);
+        self.in_data = true;
+
+        parse_part_headers(&headers)
+            .map(Some)
+            .map_err(|e| invalid(&e))
+    }
+
+    /// The next piece of the current part's data, `None` once all of it was read
+    pub async fn next_data(&mut self) -> Result<Option<Vec<u8>>, HttpParseError> {
+        if !self.in_data {
+            return Ok(None);
+        }
+
+        loop {
+            match find(&self.buffer, &self.delimiter) {
+                Some(0) => {
+                    self.buffer.drain(..self.delimiter.len());
+                    self.in_data = false;
+                    return Ok(None);
+                }
+                Some(end) => return Ok(Some(self.buffer.drain(..end).collect())),
+                None => {
+                    // The end of the buffer could be the start of a delimiter, the rest is data
+                    let data_end = self.buffer.len().saturating_sub(self.delimiter.len() - 1);
+                    if data_end > 0 {
+                        return Ok(Some(self.buffer.drain(..data_end).collect()));
+                    }
+
+                    if !self.fill().await? {
+                        return Err(invalid("the closing delimiter is missing"));
+                    }
+                }
+            }
+        }
+    }
+
+    /// All of the current part's data, for fields that aren't files
+    pub async fn read_field(&mut self, max: usize) -> Result<Vec<u8>, HttpParseError> {
+        let mut field = vec![];
+
+        while let Some(data) = self.next_data().await? {
+            field.extend_from_slice(&data);
+
+            if field.len() > max {
+                return Err(invalid(&format!("a field is over {} bytes", max)));
+            }
+        }
+
+        Ok(field)
+    }
+
+    /// Read more of the body into the buffer, false at its end
+    async fn fill(&mut self) -> Result<bool, HttpParseError> {
+        let start = self.buffer.len();
+        self.buffer.resize(start + READ_SIZE, 0);
+        let read = self.body.read(&mut self.buffer[start..]).await?;
+        self.buffer.truncate(start + read);
+
+        Ok(read > 0)
+    }
+
+    async fn fill_to(&mut self, len: usize) -> Result<(), HttpParseError> {
+        while self.buffer.len() < len {
+            if !self.fill().await? {
+                return Err(invalid("the body ends in the middle of a part"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Where `needle` is in the buffer, reading more until it's found no further than `max` in
+    async fn find_filling(
+        &mut self,
+        needle: &[u8],
+        max: usize,
+        too_long: &str,
+    ) -> Result<usize, HttpParseError> {
+        loop {
+            match find(&self.buffer, needle) {
+                Some(position) if position <= max => return Ok(position),
+                Some(_) => return Err(invalid(too_long)),
+                None if self.buffer.len() >= max + needle.len() => return Err(invalid(too_long)),
+                None => self.fill_to(self.buffer.len() + 1).await?,
+            }
+        }
+    }
+}
+
+fn invalid(reason: &str) -> HttpParseError {
+    HttpParseError::MalformedRequest(format!("invalid multipart body, {}", reason))
+}
+
+fn parse_part_headers(headers: &str) -> Result<PartHead, String> {
+    let mut part = PartHead {
+        name: None,
+        filename: None,
+    };
+
+    for line in headers.split("\r\n").filter(|line| !line.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| format!("invalid part header '{}'", line))?;
+
+        if name.eq_ignore_ascii_case("content-disposition") {
+            let (kind, params) = value.split_once(';').unwrap_or((value, ""));
+
+            if !kind.trim().eq_ignore_ascii_case("form-data") {
+                return Err(format!("a part is '{}' rather than form-data", kind.trim()));
+            }
+
+            for (key, value) in parse_params(params) {
+                match key.as_str() {
+                    "name" => part.name = Some(value),
+                    // `filename*` is the RFC 5987 form for names that aren't ASCII, and wins
+                    "filename*" => part.filename = decode_ext_value(&value).or(part.filename),
+                    "filename" if part.filename.is_none() => part.filename = Some(value),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    Ok(part)
+}
+
+/// Parse `; key=value; key="quoted value"` parameters, with keys in lowercase
+fn parse_params(params: &str) -> Vec<(String, String)> {
+    let mut parsed = vec![];
+    let mut rest = params.trim_start_matches([';', ' ', '\t']);
+
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq].trim().to_ascii_lowercase();
+        rest = rest[eq + 1..].trim_start();
+
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    _ => value.push(c),
+                }
+            }
+
+            rest = &quoted[end..];
+            value
+        } else {
+            let end = rest.find(';').unwrap_or(rest.len());
+            let value = rest[..end].trim().to_string();
+            rest = &rest[end..];
+            value
+        };
+
+        parsed.push((key, value));
+        rest = rest.trim_start_matches([';', ' ', '\t']);
+    }
+
+    parsed
+}
+
+/// Decode an RFC 5987 value like `UTF-8''na%C3%AFve.txt`
+fn decode_ext_value(value: &str) -> Option<String> {
+    let (charset, rest) = value.split_once('\'')?;
+    let (_language, encoded) = rest.split_once('\'')?;
+
+    if !charset.eq_ignore_ascii_case("utf-8") {
+        return None;
+    }
+
+    decode(encoded).ok().map(|decoded| decoded.into_owned())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::httpfs::parse::{parse_request_head, ParseLimits};
+
+    fn limits() -> ParseLimits {
+        ParseLimits {
+            max_request_line: 8 * 1024,
+            max_headers: 100,
+            max_header_bytes: 64 * 1024,
+            max_body: 1024,
+            body_limits: vec![],
+            max_chunk: 1024,
+            read_timeout: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(5),
+            strict: true,
+        }
+    }
+
+    fn request(body: &[u8]) -> Vec<u8> {
+        let head = format!(
+            "POST /incoming/ HTTP/1.1\r\n\
+            Content-Type: multipart/form-data; boundary=xyz\r\n\
+            Content-Length: {}\r\n\r\n",
+            body.len()
+        );
+        [head.as_bytes(), body].concat()
+    }
+
+    /// The body sent in chunks of `size` bytes, so delimiters are split between reads
+    fn chunked_request(body: &[u8], size: usize) -> Vec<u8> {
+        let mut request = b"POST /incoming/ HTTP/1.1\r\n\
+            Content-Type: multipart/form-data; boundary=xyz\r\n\
+            Transfer-Encoding: chunked\r\n\r\n"
+            .to_vec();
+
+        for chunk in body.chunks(size) {
+            request.extend(format!("{:x}\r\n", chunk.len()).as_bytes());
+            request.extend(chunk);
+            request.extend(b"\r\n");
+        }
+        request.extend(b"0\r\n\r\n");
+        request
+    }
+
+    /// Every part of the body of `request` with its data
+    async fn parts(request: &[u8]) -> Result<Vec<(PartHead, Vec<u8>)>, HttpParseError> {
+        let limits = limits();
+        let mut reader = request;
+        let head = parse_request_head(&mut reader, &limits).await?;
+        let boundary = request_boundary(head.request()).unwrap();
+        let (_, mut body) = head.into_parts(&mut reader, &limits);
+        let mut multipart = Multipart::new(&mut body, &boundary);
+
+        let mut parts = vec![];
+        while let Some(part) = multipart.next_part().await? {
+            let mut data = vec![];
+            while let Some(piece) = multipart.next_data().await? {
+                data.extend(piece);
+            }
+            parts.push((part, data));
+        }
+
+        assert!(body.is_done());
+        Ok(parts)
+    }
+
+    fn head(name: &str, filename: Option<&str>) -> PartHead {
+        PartHead {
+            name: Some(name.to_string()),
+            filename: filename.map(str::to_string),
+        }
+    }
+
+    const FORM: &[u8] = b"--xyz\r\n\
+        Content-Disposition: form-data; name=\"csrf\"\r\n\r\n\
+        123.abc\r\n\
+        --xyz\r\n\
+        Content-Disposition: form-data; name=\"files\"; filename=\"a.txt\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        hello\r\n--xy\r\n\
+        --xyz--\r\n";
+
+    #[test]
+    fn finds_boundaries() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=xyz").as_deref(),
+            Some("xyz")
+        );
+        assert_eq!(
+            boundary("Multipart/Form-Data; boundary=\"a b\"").as_deref(),
+            Some("a b")
+        );
+        assert_eq!(boundary("multipart/form-data"), None);
+        assert_eq!(boundary("multipart/mixed; boundary=xyz"), None);
+        assert_eq!(boundary("application/x-www-form-urlencoded"), None);
+        assert_eq!(
+            boundary(&format!("multipart/form-data; boundary={}", "x".repeat(71))),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn splits_parts() {
+        let parts = parts(&request(FORM)).await.unwrap();
+
+        assert_eq!(
+            parts,
+            vec![
+                (head("csrf", None), b"123.abc".to_vec()),
+                (head("files", Some("a.txt")), b"hello\r\n--xy".to_vec()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn splits_parts_across_reads() {
+        for size in [1, 2, 3, 7, 64] {
+            let parts = parts(&chunked_request(FORM, size)).await.unwrap();
+
+            assert_eq!(parts.len(), 2, "chunks of {}", size);
+            assert_eq!(parts[1].1, b"hello\r\n--xy", "chunks of {}", size);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_files_bigger_than_the_buffer() {
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let body = [
+            b"--xyz\r\nContent-Disposition: form-data; name=\"f\"; filename=\"big\"\r\n\r\n"
+                .as_slice(),
+            &data,
+            b"\r\n--xyz--",
+        ]
+        .concat();
+
+        let parts = parts(&request(&body)).await.unwrap();
+        assert_eq!(parts[0].1, data);
+    }
+
+    #[tokio::test]
+    async fn skips_preamble_and_epilogue() {
+        let body = b"ignored\r\n--xyz\r\n\
+            Content-Disposition: form-data; name=\"a\"\r\n\r\n\
+            1\r\n--xyz--\r\nalso ignored";
+
+        let parts = parts(&request(body)).await.unwrap();
+        assert_eq!(parts, vec![(head("a", None), b"1".to_vec())]);
+    }
+
+    #[tokio::test]
+    async fn accepts_padding_and_parts_without_headers() {
+        let body = b"--xyz \t\r\n\r\nno headers\r\n--xyz--";
+
+        let parts = parts(&request(body)).await.unwrap();
+        assert_eq!(
+            parts,
+            vec![(
+                PartHead {
+                    name: None,
+                    filename: None
+                },
+                b"no headers".to_vec()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn prefers_extended_filenames() {
+        let body = b"--xyz\r\n\
+            Content-Disposition: form-data; name=\"f\"; filename=\"naive.txt\"; \
+            filename*=UTF-8''na%C3%AFve.txt\r\n\r\n\
+            \r\n--xyz--";
+
+        let parts = parts(&request(body)).await.unwrap();
+        assert_eq!(parts[0].0.filename.as_deref(), Some("naïve.txt"));
+    }
+
+    #[tokio::test]
+    async fn rejects_broken_bodies() {
+        let broken: [&[u8]; 5] = [
+            b"",
+            b"--xyz\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nnever closed",
+            b"--xyz trailing text\r\n\r\n\r\n--xyz--",
+            b"--xyz\r\nContent-Disposition: attachment\r\n\r\n\r\n--xyz--",
+            b"--xyz\r\nContent-Disposition: form-data\r\n",
+        ];
+
+        for body in broken {
+            assert!(
+                matches!(
+                    parts(&request(body)).await,
+                    Err(HttpParseError::MalformedRequest(_))
+                ),
+                "{}",
+                String::from_utf8_lossy(body)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn limits_headers_and_parts() {
+        let long_header = format!(
+            "--xyz\r\nX-Padding: {}\r\n\r\n\r\n--xyz--",
+            "a".repeat(MAX_PART_HEADER_BYTES)
+        );
+        assert!(parts(&request(long_header.as_bytes())).await.is_err());
+
+        let many = "--xyz\r\n\r\n\r\n".repeat(MAX_PARTS + 1) + "--xyz--";
+        assert!(parts(&request(many.as_bytes())).await.is_err());
+
+        let enough = "--xyz\r\n\r\n\r\n".repeat(MAX_PARTS) + "--xyz--";
+        assert_eq!(
+            parts(&request(enough.as_bytes())).await.unwrap().len(),
+            MAX_PARTS
+        );
+    }
+}
@@ -1,151 +1,603 @@
 use std::{
     collections::HashMap,
-    str::{from_utf8, FromStr},
+    ffi::OsStr,
+    future::Future,
+    io,
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+    time::Duration,
 };
 
-use http::{header, header::HeaderName, HeaderValue, Method, Request, Version};
+use http::{header, header::HeaderName, HeaderMap, HeaderValue, Method, Request, Uri, Version};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
-    net::TcpStream,
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader, ReadBuf},
+    time::{sleep, timeout, Instant, Sleep},
 };
+use urlencoding::decode_binary;
 
-use crate::{httpfs::message::ByteRequest, httpfs::parse_error::HttpParseError};
+use crate::{
+    filesystem::{flatten_path, is_under},
+    httpfs::message::ByteRequest,
+    httpfs::parse_error::HttpParseError,
+};
+
+/// How much a client may send, and how slowly
+#[derive(Debug, Clone)]
+pub struct ParseLimits {
+    /// Longest request line (`GET /path HTTP/1.1`), longer ones get a 414
+    pub max_request_line: usize,
+    /// Most headers in a request, more get a 431
+    pub max_headers: usize,
+    /// Most bytes of headers all together, more get a 431
+    pub max_header_bytes: usize,
+    /// Largest body, bigger ones get a 413
+    pub max_body: usize,
+    /// Body limits for specific paths as `(prefix, size)`, the longest matching prefix wins
+    pub body_limits: Vec<(String, usize)>,
+    /// Largest single chunk in a chunked body, bigger ones get a 413
+    pub max_chunk: usize,
+    /// How long the client has to send the request line and headers, slower ones get a 408
+    pub read_timeout: Duration,
+    /// How long the client may send nothing at all, even while sending the body
+    pub idle_timeout: Duration,
+    /// Reject bare LF line endings, obsolete line folding and ambiguous body lengths, instead of
+    /// working around them
+    pub strict: bool,
+}
+
+impl ParseLimits {
+    /// The body limit for a request path, ie. `/incoming/a.zip`
+    pub fn max_body_for(&self, path: &str) -> usize {
+        self.body_limits
+            .iter()
+            .filter(|(prefix, _)| is_under(path, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.max_body, |(_, size)| *size)
+    }
+}
+
+/// Longest chunk size line we accept, the size itself is never more than 16 hex digits
+const MAX_CHUNK_LINE: usize = 1024;
+
+/// Most bytes read from the body at once
+const READ_SIZE: usize = 16 * 1024;
+
+/// The stream a request is read from, buffered and failing once the client goes quiet
+pub type RequestReader<S> = BufReader<IdleTimeout<S>>;
+
+pub fn request_reader<S: AsyncRead + Unpin>(stream: S, limits: &ParseLimits) -> RequestReader<S> {
+    BufReader::new(IdleTimeout::new(stream, limits.idle_timeout))
+}
+
+/// Everything before the body, and how to read the body
+pub struct RequestHead {
+    request: ByteRequest,
+    framing: Framing,
+    max_body: usize,
+}
 
-pub async fn parse_request(stream: &mut TcpStream) -> Result<ByteRequest, HttpParseError> {
-    let mut reader = BufReader::new(stream);
+impl RequestHead {
+    /// The request so far, without a body
+    pub fn request(&self) -> &ByteRequest {
+        &self.request
+    }
+
+    /// Split into the request and a reader for its body, which hasn't been read yet
+    pub fn into_parts<'a, R>(
+        self,
+        reader: &'a mut R,
+        limits: &'a ParseLimits,
+    ) -> (ByteRequest, BodyReader<'a, R>) {
+        let body = BodyReader {
+            reader,
+            limits,
+            framing: self.framing,
+            chunk_left: 0,
+            max_body: self.max_body,
+        };
+
+        (self.request, body)
+    }
+}
+
+/// How the length of the body is known
+/// https://www.rfc-editor.org/rfc/rfc9112#section-6.3
+#[derive(Debug, PartialEq, Eq)]
+enum Framing {
+    None,
+    Length(usize),
+    Chunked,
+}
+
+/// Read the request line and headers, the body is left in `reader` for `RequestHead::into_parts`
+pub async fn parse_request_head<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    limits: &ParseLimits,
+) -> Result<RequestHead, HttpParseError> {
+    timeout(limits.read_timeout, read_head(reader, limits))
+        .await
+        .map_err(|_| HttpParseError::RequestTimeout)?
+}
+
+async fn read_head<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    limits: &ParseLimits,
+) -> Result<RequestHead, HttpParseError> {
     let mut request = Request::builder();
-    let mut content_length: usize = 0;
-    let mut chunked = false;
 
     // GET /path/to/file HTTP/1.1
-    let mut status_line = String::new();
-    reader.read_line(&mut status_line).await?;
-    let mut status_line = status_line.trim_end().split_ascii_whitespace();
+    let status_line =
+        read_line_limited(reader, limits.max_request_line, HttpParseError::UriTooLong).await?;
+    let status_line = strip_line_ending(&status_line, limits.strict)?;
 
-    let method = status_line
-        .next()
-        .ok_or_else(|| HttpParseError::MalformedRequest("Method missing".to_string()))?;
+    // Strictly, exactly one space between each part
+    let parts: Vec<&str> = if limits.strict {
+        status_line.split(' ').collect()
+    } else {
+        status_line.split_ascii_whitespace().collect()
+    };
 
-    let method = parse_method(method)?;
+    let (method, uri, version) = match parts[..] {
+        [method, uri, version] if !method.is_empty() && !uri.is_empty() => (method, uri, version),
+        [_] | [_, _] => {
+            return Err(HttpParseError::MalformedRequest(
+                "Method, URI or version missing".to_string(),
+            ))
+        }
+        _ => {
+            return Err(HttpParseError::MalformedRequest(format!(
+                "Invalid request line: '{}'",
+                status_line
+            )))
+        }
+    };
 
-    let uri = status_line
-        .next()
-        .ok_or_else(|| HttpParseError::MalformedRequest("URI missing".to_string()))?;
+    let method = parse_method(method)?;
 
-    let version = status_line
-        .next()
-        .ok_or_else(|| HttpParseError::MalformedRequest("Version missing".to_string()))?;
+    let uri = uri
+        .parse::<Uri>()
+        .map_err(|_| HttpParseError::MalformedRequest(format!("Invalid URI: '{}'", uri)))?;
 
     let version = parse_version(version)?;
 
+    let max_body = limits.max_body_for(&limit_path(&uri));
+
     // Header-Name: header-value
-    let headers = request.headers_mut().unwrap();
+    let mut header_lines: Vec<String> = vec![];
+    let mut header_bytes = 0;
 
     loop {
-        let mut header_line = String::new();
-        if let 0 = reader.read_line(&mut header_line).await? {
-            return Err(HttpParseError::EndOfStream);
-        };
+        let remaining = limits.max_header_bytes.saturating_sub(header_bytes);
+        let header_line =
+            read_line_limited(reader, remaining, HttpParseError::HeadersTooLarge).await?;
+
+        header_bytes += header_line.len();
+        let header_line = strip_line_ending(&header_line, limits.strict)?;
 
-        if header_line == "\r\n" {
+        if header_line.is_empty() {
             break;
         }
 
-        let (key, value) = parse_header(&header_line)?;
-
-        if key == header::CONTENT_LENGTH {
-            if method == Method::GET {
-                return Err(HttpParseError::BodyNotAllowed);
+        // Obsolete line folding, a header value continued on the next line
+        if header_line.starts_with([' ', '\t']) {
+            match header_lines.last_mut() {
+                Some(previous) if !limits.strict => {
+                    previous.push(' ');
+                    previous.push_str(header_line.trim());
+                    continue;
+                }
+                _ => {
+                    return Err(HttpParseError::MalformedRequest(
+                        "Obsolete line folding is not allowed".to_string(),
+                    ))
+                }
             }
-
-            content_length = value.to_str().unwrap().parse().map_err(|_| {
-                HttpParseError::MalformedRequest("Content-Length is not a number".to_string())
-            })?;
         }
 
-        if key == header::TRANSFER_ENCODING
-            && value.to_str().unwrap().to_lowercase().contains("chunked")
-        {
-            chunked = true;
+        if header_lines.len() >= limits.max_headers {
+            return Err(HttpParseError::HeadersTooLarge);
         }
 
+        header_lines.push(header_line.to_string());
+    }
+
+    let headers = request.headers_mut().unwrap();
+
+    for header_line in &header_lines {
+        let (key, value) = parse_header(header_line)?;
         headers.append(key, value);
     }
 
-    if method == Method::POST
-        && !headers.contains_key(header::CONTENT_LENGTH)
-        && !headers.contains_key(header::TRANSFER_ENCODING)
-    {
+    let framing = parse_framing(headers, version, limits.strict)?;
+
+    if method == Method::GET && headers.contains_key(header::CONTENT_LENGTH) {
+        return Err(HttpParseError::BodyNotAllowed);
+    }
+
+    if method == Method::POST && framing == Framing::None {
         return Err(HttpParseError::LengthRequired);
     }
 
-    // \r\n
-    // body is optional, either
-    //  - Content-Length: # body bytes...
-    //  - Transfer-Encoding: chunked body...
-    let body = if chunked {
-        Some(read_chunked(reader).await?)
-    } else if content_length > 0 {
-        let mut body = vec![0; content_length];
-        reader.read_exact(&mut body).await?;
-        Some(body)
-    } else {
-        None
+    Ok(RequestHead {
+        request: request
+            .method(method)
+            .uri(uri)
+            .version(version)
+            .body(None)?,
+        framing,
+        max_body,
+    })
+}
+
+/// Work out how long the body is, following the rules that stop request smuggling
+///
+/// A proxy and this server must never disagree on where a request ends, so anything ambiguous is
+/// rejected rather than guessed at. Leniently, `Transfer-Encoding` wins over `Content-Length` and
+/// repeated but identical lengths are accepted
+/// https://www.rfc-editor.org/rfc/rfc9112#section-6.3
+fn parse_framing(
+    headers: &mut HeaderMap,
+    version: Version,
+    strict: bool,
+) -> Result<Framing, HttpParseError> {
+    let malformed = |reason: &str| Err(HttpParseError::MalformedRequest(reason.to_string()));
+
+    let codings = list_values(headers, header::TRANSFER_ENCODING)?;
+
+    if !codings.is_empty() {
+        if version == Version::HTTP_10 {
+            return malformed("Transfer-Encoding is not allowed in HTTP/1.0");
+        }
+
+        if codings.last().map(String::as_str) != Some("chunked") {
+            return malformed("chunked must be the final transfer coding");
+        }
+
+        if let Some(coding) = codings[..codings.len() - 1].iter().next() {
+            return match coding.as_str() {
+                "chunked" => malformed("chunked can only be applied once"),
+                _ => Err(HttpParseError::UnsupportedTransferCoding(coding.clone())),
+            };
+        }
+
+        if headers.contains_key(header::CONTENT_LENGTH) {
+            if strict {
+                return malformed("Content-Length and Transfer-Encoding can't both be used");
+            }
+
+            headers.remove(header::CONTENT_LENGTH);
+        }
+
+        return Ok(Framing::Chunked);
+    }
+
+    let lengths = list_values(headers, header::CONTENT_LENGTH)?;
+
+    let length = match lengths.first() {
+        Some(length) => length,
+        None => return Ok(Framing::None),
     };
 
-    let request = request
-        .method(method)
-        .uri(uri)
-        .version(version)
-        .body(body)?;
+    if lengths.iter().any(|other| other != length) {
+        return malformed("Conflicting Content-Length values");
+    }
+
+    if lengths.len() > 1 && strict {
+        return malformed("Content-Length can only be given once");
+    }
+
+    // parse() would also take a leading +
+    if !length.bytes().all(|b| b.is_ascii_digit()) {
+        return malformed("Content-Length is not a number");
+    }
 
-    Ok(request)
+    length
+        .parse()
+        .map(Framing::Length)
+        .or_else(|_| malformed("Content-Length is too big"))
 }
 
-async fn read_chunked(mut reader: BufReader<&mut TcpStream>) -> Result<Vec<u8>, HttpParseError> {
-    let mut body = Vec::new();
+/// All the comma separated values of every `name` header, lowercased
+fn list_values(headers: &HeaderMap, name: HeaderName) -> Result<Vec<String>, HttpParseError> {
+    let mut values = vec![];
+
+    for value in headers.get_all(&name) {
+        let value = value
+            .to_str()
+            .map_err(|_| HttpParseError::MalformedRequest(format!("Invalid {} header", name)))?;
+
+        values.extend(
+            value
+                .split(',')
+                .map(|v| v.trim().to_ascii_lowercase())
+                .filter(|v| !v.is_empty()),
+        );
+    }
+
+    Ok(values)
+}
+
+/// Remove the line ending from a line
+///
+/// Lines must end in CRLF, a bare LF is only accepted when not strict. A line without any line
+/// ending means the stream ended
+fn strip_line_ending(line: &str, strict: bool) -> Result<&str, HttpParseError> {
+    let stripped = match line.strip_suffix("\r\n") {
+        Some(stripped) => stripped,
+        None => match line.strip_suffix('\n') {
+            Some(stripped) if !strict => stripped,
+            Some(_) => {
+                return Err(HttpParseError::MalformedRequest(
+                    "Lines must end with CRLF, not a bare LF".to_string(),
+                ))
+            }
+            None => return Err(HttpParseError::EndOfStream),
+        },
+    };
+
+    // A CR anywhere else could be read as a line ending by something in front of us
+    if stripped.contains('\r') {
+        return Err(HttpParseError::MalformedRequest(
+            "Bare CR in request".to_string(),
+        ));
+    }
+
+    Ok(stripped)
+}
+
+/// The path as body limits see it, decoded and flattened like the server will, ie. `/incoming/a.zip`
+fn limit_path(uri: &Uri) -> String {
+    let path = PathBuf::from(OsStr::from_bytes(&decode_binary(uri.path().as_bytes())));
+    format!("/{}", flatten_path(path).to_string_lossy())
+}
+
+/// Read a line including its line ending, or an empty string at the end of the stream
+///
+/// Fails with `too_long` instead of buffering more than `max` bytes
+async fn read_line_limited<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max: usize,
+    too_long: HttpParseError,
+) -> Result<String, HttpParseError> {
+    let mut line = Vec::new();
+
     loop {
-        // Read the chunk "head"
-        // [hex octets]*(;ext-name=ext-val)\r\n
-        // We need the num of octects in the chunk, but can ignore the chunk-ext
-        // We don't recognize any chunk extensions, so we MUST ignore them
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            break;
+        }
+
+        let (used, done) = match available.iter().position(|&b| b == b'\n') {
+            Some(end) => (end + 1, true),
+            None => (available.len(), false),
+        };
+
+        if line.len() + used > max {
+            return Err(too_long);
+        }
+
+        line.extend_from_slice(&available[..used]);
+        reader.consume(used);
+
+        if done {
+            break;
+        }
+    }
+
+    Ok(String::from_utf8(line)?)
+}
+
+/// Reads the body of a request as it arrives, without its chunked transfer coding
+pub struct BodyReader<'a, R> {
+    reader: &'a mut R,
+    limits: &'a ParseLimits,
+    /// What's left to read, a chunked body becomes `None` once its last chunk was read
+    framing: Framing,
+    /// What's left of the current chunk, 0 between chunks
+    chunk_left: usize,
+    /// The body limit for the request's path
+    max_body: usize,
+}
+
+impl<R: AsyncBufRead + Unpin> BodyReader<'_, R> {
+    /// Whether the whole body has been read
+    pub fn is_done(&self) -> bool {
+        matches!(self.framing, Framing::None | Framing::Length(0))
+    }
+
+    /// Read the next bytes of the body into `buf`, 0 once it has all been read
+    ///
+    /// There's no limit on the size of the whole body, only on each chunk
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, HttpParseError> {
+        let available = match self.framing {
+            Framing::None => return Ok(0),
+            Framing::Length(left) => left,
+            Framing::Chunked => {
+                if self.chunk_left == 0 {
+                    self.chunk_left = self.read_chunk_size().await?;
+                }
+
+                if self.chunk_left == 0 {
+                    self.framing = Framing::None;
+                }
+
+                self.chunk_left
+            }
+        };
+
+        let wanted = available.min(buf.len());
+        if wanted == 0 {
+            return Ok(0);
+        }
+
+        let read = self.reader.read(&mut buf[..wanted]).await?;
+        if read == 0 {
+            return Err(HttpParseError::EndOfStream);
+        }
+
+        match &mut self.framing {
+            Framing::Length(left) => *left -= read,
+            _ => {
+                self.chunk_left -= read;
+                if self.chunk_left == 0 {
+                    self.read_chunk_end().await?;
+                }
+            }
+        }
+
+        Ok(read)
+    }
+
+    /// Read the rest of the body, `None` if the request doesn't have one
+    ///
+    /// Fails with `PayloadTooLarge` once it's bigger than the body limit for the request's path
+    pub async fn read_to_end(&mut self) -> Result<Option<Vec<u8>>, HttpParseError> {
+        let mut body = match self.framing {
+            Framing::None | Framing::Length(0) => return Ok(None),
+            // Don't even start reading a body we won't accept
+            Framing::Length(length) if length > self.max_body => {
+                return Err(HttpParseError::PayloadTooLarge)
+            }
+            Framing::Length(length) => Vec::with_capacity(length),
+            Framing::Chunked => Vec::new(),
+        };
 
-        // Read octets
-        let mut octets: Vec<u8> = vec![];
         loop {
-            let byte = reader.read_u8().await?;
-            if byte == b';' || byte == b'\r' {
-                break;
+            let start = body.len();
+            body.resize(start + READ_SIZE, 0);
+            let read = self.read(&mut body[start..]).await?;
+            body.truncate(start + read);
+
+            if read == 0 {
+                return Ok(Some(body));
+            }
+
+            if body.len() > self.max_body {
+                return Err(HttpParseError::PayloadTooLarge);
             }
-            octets.push(byte);
         }
+    }
 
-        // Read until end of line
-        {
-            let mut discard = vec![];
-            reader.read_until(b'\n', &mut discard).await?;
+    /// Read the "head" of the next chunk for its size, and the trailers after the last one
+    async fn read_chunk_size(&mut self) -> Result<usize, HttpParseError> {
+        // [hex octets]*(;ext-name=ext-val)\r\n
+        // We need the num of octects in the chunk, but can ignore the chunk-ext
+        // We don't recognize any chunk extensions, so we MUST ignore them
+        let line = read_line_limited(self.reader, MAX_CHUNK_LINE, chunk_line_too_long()).await?;
+        let line = strip_line_ending(&line, self.limits.strict)?;
+
+        let size = line
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim_end_matches([' ', '\t']);
+
+        // from_str_radix would also take a leading +
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(HttpParseError::MalformedRequest(format!(
+                "Invalid chunk size: '{}'",
+                size
+            )));
         }
 
-        let octets = usize::from_str_radix(from_utf8(&octets).unwrap(), 16)?;
+        let octets = usize::from_str_radix(size, 16)?;
+
+        if octets > self.limits.max_chunk {
+            return Err(HttpParseError::PayloadTooLarge);
+        }
 
         if octets == 0 {
-            // We've reached the end of the chunked body
-            break;
+            // We've reached the end of the chunked body, skip any trailers up to the final empty line
+            loop {
+                let trailer = read_line_limited(
+                    self.reader,
+                    self.limits.max_header_bytes,
+                    HttpParseError::HeadersTooLarge,
+                )
+                .await?;
+
+                if strip_line_ending(&trailer, self.limits.strict)?.is_empty() {
+                    break;
+                }
+            }
         }
 
-        // Read the chunk
-        for _ in 0..octets {
-            body.push(reader.read_u8().await?);
+        Ok(octets)
+    }
+
+    /// The chunk must end right where its size said it would
+    async fn read_chunk_end(&mut self) -> Result<(), HttpParseError> {
+        let end = read_line_limited(self.reader, MAX_CHUNK_LINE, chunk_line_too_long()).await?;
+        if !strip_line_ending(&end, self.limits.strict)?.is_empty() {
+            return Err(HttpParseError::MalformedRequest(
+                "Chunk is longer than its size".to_string(),
+            ));
         }
 
-        // Read the chunk end
-        {
-            let mut discard = vec![];
-            reader.read_until(b'\n', &mut discard).await?;
+        Ok(())
+    }
+}
+
+fn chunk_line_too_long() -> HttpParseError {
+    HttpParseError::MalformedRequest("Chunk size line too long".to_string())
+}
+
+/// Fails reads with `TimedOut` once the stream has sent nothing for `timeout`
+pub struct IdleTimeout<S> {
+    inner: S,
+    timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+    /// Whether a read is waiting on the client, the deadline only counts down while one is
+    waiting: bool,
+}
+
+impl<S> IdleTimeout<S> {
+    fn new(inner: S, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            deadline: Box::pin(sleep(timeout)),
+            waiting: false,
         }
     }
 
-    Ok(body)
+    /// The stream, ie. to write the response to
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeout<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        // Time we spent between reads, ie. writing an upload to disk, isn't the client's
+        if !this.waiting {
+            this.deadline.as_mut().reset(Instant::now() + this.timeout);
+            this.waiting = true;
+        }
+
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.waiting = false;
+                Poll::Ready(result)
+            }
+            Poll::Pending => match this.deadline.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Client sent nothing for too long",
+                ))),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
 }
 
 fn parse_method(str: &str) -> Result<Method, HttpParseError> {
@@ -156,6 +608,7 @@ fn parse_method(str: &str) -> Result<Method, HttpParseError> {
             Method::POST => Ok(method),
             Method::PUT => Ok(method),
             Method::DELETE => Ok(method),
+            Method::OPTIONS => Ok(method),
             _ => Err(HttpParseError::UnsupportedMethod(str.to_string())),
         },
         Err(_) => Err(HttpParseError::UnsupportedMethod(str.to_string())),
@@ -170,25 +623,20 @@ fn parse_version(str: &str) -> Result<Version, HttpParseError> {
     }
 }
 
+/// Parse a `Name: value` header line, without its line ending
 fn parse_header(header_line: &str) -> Result<(HeaderName, HeaderValue), HttpParseError> {
-    let header_line = header_line.trim_end();
-    let mut header_parts = header_line.splitn(2, ": ");
-
-    let key = header_parts
-        .next()
-        .ok_or_else(|| {
-            HttpParseError::MalformedRequest(format!("Header name missing: '{}'", header_line))
-        })?
-        .parse::<HeaderName>()
-        .map_err(|_| {
-            HttpParseError::MalformedRequest(format!("Invalid header name: '{}'", header_line))
-        })?;
-
-    let value = header_parts
-        .next()
-        .ok_or_else(|| {
-            HttpParseError::MalformedRequest(format!("Header value missing: '{}'", header_line))
-        })?
+    let (key, value) = header_line.split_once(':').ok_or_else(|| {
+        HttpParseError::MalformedRequest(format!("Header value missing: '{}'", header_line))
+    })?;
+
+    // Whitespace before the colon MUST be rejected, HeaderName doesn't allow any either
+    // https://www.rfc-editor.org/rfc/rfc9112#section-5.1
+    let key = key.parse::<HeaderName>().map_err(|_| {
+        HttpParseError::MalformedRequest(format!("Invalid header name: '{}'", header_line))
+    })?;
+
+    let value = value
+        .trim_matches([' ', '\t'])
         .parse::<HeaderValue>()
         .map_err(|_| {
             HttpParseError::MalformedRequest(format!("Invalid header value: '{}'", header_line))
@@ -197,7 +645,7 @@ fn parse_header(header_line: &str) -> Result<(HeaderName, HeaderValue), HttpPars
     Ok((key, value))
 }
 
-type QueryMap = HashMap<String, Option<String>>;
+pub type QueryMap = HashMap<String, Option<String>>;
 
 pub fn parse_query(query: &str) -> QueryMap {
     let mut map = HashMap::new();
@@ -210,3 +658,333 @@ pub fn parse_query(query: &str) -> QueryMap {
     }
     map
 }
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    fn limits() -> ParseLimits {
+        ParseLimits {
+            max_request_line: 8 * 1024,
+            max_headers: 100,
+            max_header_bytes: 64 * 1024,
+            max_body: 16,
+            body_limits: vec![("/big".to_string(), 64)],
+            max_chunk: 8,
+            read_timeout: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(5),
+            strict: true,
+        }
+    }
+
+    async fn read_body(request: &[u8]) -> Result<Option<Vec<u8>>, HttpParseError> {
+        let limits = limits();
+        let mut reader = request;
+        let head = parse_request_head(&mut reader, &limits).await?;
+        let (_, mut body) = head.into_parts(&mut reader, &limits);
+
+        let content = body.read_to_end().await?;
+        assert!(body.is_done());
+        Ok(content)
+    }
+
+    #[tokio::test]
+    async fn reads_bodies() {
+        let body = read_body(b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello")
+            .await
+            .unwrap();
+        assert_eq!(body.as_deref(), Some(b"hello".as_slice()));
+
+        let body = read_body(b"GET /a HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(body, None);
+    }
+
+    #[tokio::test]
+    async fn decodes_chunked_bodies() {
+        let request = b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;ext=1\r\nhello\r\n1\r\n \r\n5\r\nworld\r\n0\r\nTrailer: x\r\n\r\n";
+
+        let body = read_body(request).await.unwrap();
+        assert_eq!(body.as_deref(), Some(b"hello world".as_slice()));
+    }
+
+    #[tokio::test]
+    async fn limits_bodies_by_path() {
+        let too_big = format!(
+            "POST /a HTTP/1.1\r\nContent-Length: 17\r\n\r\n{}",
+            "a".repeat(17)
+        );
+        assert!(matches!(
+            read_body(too_big.as_bytes()).await,
+            Err(HttpParseError::PayloadTooLarge)
+        ));
+
+        let allowed = format!(
+            "POST /big/a HTTP/1.1\r\nContent-Length: 17\r\n\r\n{}",
+            "a".repeat(17)
+        );
+        assert!(read_body(allowed.as_bytes()).await.is_ok());
+
+        let chunks = "4\r\naaaa\r\n".repeat(5);
+        let too_big = format!(
+            "POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{}0\r\n\r\n",
+            chunks
+        );
+        assert!(matches!(
+            read_body(too_big.as_bytes()).await,
+            Err(HttpParseError::PayloadTooLarge)
+        ));
+
+        let big_chunk = b"POST /big/a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n9\r\naaaaaaaaa\r\n0\r\n\r\n";
+        assert!(matches!(
+            read_body(big_chunk).await,
+            Err(HttpParseError::PayloadTooLarge)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_broken_chunks() {
+        let broken: [&[u8]; 4] = [
+            b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabcd\r\n0\r\n\r\n",
+            b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n+3\r\nabc\r\n0\r\n\r\n",
+            b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nab",
+            b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nab",
+        ];
+
+        for request in broken {
+            assert!(
+                read_body(request).await.is_err(),
+                "{}",
+                String::from_utf8_lossy(request)
+            );
+        }
+    }
+
+    /// Parse the head of `request`, with the status a parse error gets
+    async fn parse_head(request: &[u8], strict: bool) -> Result<RequestHead, StatusCode> {
+        let limits = ParseLimits { strict, ..limits() };
+        let mut reader = request;
+        parse_request_head(&mut reader, &limits)
+            .await
+            .map_err(StatusCode::from)
+    }
+
+    #[tokio::test]
+    async fn refuses_content_length_with_transfer_encoding() {
+        let request =
+            b"POST /a HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+        assert_eq!(
+            parse_head(request, true).await.err(),
+            Some(StatusCode::BAD_REQUEST)
+        );
+
+        // Leniently the chunks decide, and nothing after us sees the length
+        let head = parse_head(request, false).await.unwrap();
+        assert_eq!(head.framing, Framing::Chunked);
+        assert!(!head
+            .request()
+            .headers()
+            .contains_key(header::CONTENT_LENGTH));
+    }
+
+    #[tokio::test]
+    async fn refuses_conflicting_content_lengths() {
+        for lengths in [
+            "Content-Length: 5\r\nContent-Length: 6",
+            "Content-Length: 5, 6",
+        ] {
+            let request = format!("POST /a HTTP/1.1\r\n{}\r\n\r\n", lengths);
+            for strict in [true, false] {
+                assert_eq!(
+                    parse_head(request.as_bytes(), strict).await.err(),
+                    Some(StatusCode::BAD_REQUEST),
+                    "{}",
+                    lengths
+                );
+            }
+        }
+
+        // The same length twice is only allowed leniently
+        for lengths in [
+            "Content-Length: 5\r\nContent-Length: 5",
+            "Content-Length: 5, 5",
+        ] {
+            let request = format!("POST /a HTTP/1.1\r\n{}\r\n\r\n", lengths);
+            assert_eq!(
+                parse_head(request.as_bytes(), true).await.err(),
+                Some(StatusCode::BAD_REQUEST),
+                "{}",
+                lengths
+            );
+
+            let head = parse_head(request.as_bytes(), false).await.unwrap();
+            assert_eq!(head.framing, Framing::Length(5));
+        }
+
+        let signed = b"POST /a HTTP/1.1\r\nContent-Length: +5\r\n\r\n";
+        assert_eq!(
+            parse_head(signed, false).await.err(),
+            Some(StatusCode::BAD_REQUEST)
+        );
+    }
+
+    #[tokio::test]
+    async fn requires_chunked_to_be_the_last_coding() {
+        let cases = [
+            ("chunked, gzip", StatusCode::BAD_REQUEST),
+            ("chunked, chunked", StatusCode::BAD_REQUEST),
+            ("gzip", StatusCode::BAD_REQUEST),
+            ("gzip, chunked", StatusCode::NOT_IMPLEMENTED),
+        ];
+
+        for (codings, status) in cases {
+            let request = format!("POST /a HTTP/1.1\r\nTransfer-Encoding: {}\r\n\r\n", codings);
+            for strict in [true, false] {
+                assert_eq!(
+                    parse_head(request.as_bytes(), strict).await.err(),
+                    Some(status),
+                    "{}",
+                    codings
+                );
+            }
+        }
+
+        let http_10 = b"POST /a HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(
+            parse_head(http_10, false).await.err(),
+            Some(StatusCode::BAD_REQUEST)
+        );
+    }
+
+    #[tokio::test]
+    async fn only_accepts_bare_lf_and_folding_leniently() {
+        let bare_lf = b"GET /a HTTP/1.1\nHost: a\n\n";
+        assert_eq!(
+            parse_head(bare_lf, true).await.err(),
+            Some(StatusCode::BAD_REQUEST)
+        );
+        assert!(parse_head(bare_lf, false).await.is_ok());
+
+        let folded = b"GET /a HTTP/1.1\r\nX-Note: one\r\n\ttwo\r\n\r\n";
+        assert_eq!(
+            parse_head(folded, true).await.err(),
+            Some(StatusCode::BAD_REQUEST)
+        );
+        let head = parse_head(folded, false).await.unwrap();
+        assert_eq!(head.request().headers()["x-note"], "one two");
+
+        // Folding with nothing to continue
+        let first = b"GET /a HTTP/1.1\r\n two\r\n\r\n";
+        assert_eq!(
+            parse_head(first, false).await.err(),
+            Some(StatusCode::BAD_REQUEST)
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_bare_cr() {
+        let requests: [&[u8]; 2] = [
+            b"GET /a HTTP/1.1\r\nX-Note: a\rb\r\n\r\n",
+            b"GET /a\r HTTP/1.1\r\n\r\n",
+        ];
+
+        for request in requests {
+            for strict in [true, false] {
+                assert_eq!(
+                    parse_head(request, strict).await.err(),
+                    Some(StatusCode::BAD_REQUEST),
+                    "{}",
+                    String::from_utf8_lossy(request)
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn limits_bodies_by_decoded_paths() {
+        // `/big/` with an encoded letter and a file name that isn't UTF-8
+        let request = format!(
+            "POST /%62ig/%FF.bin HTTP/1.1\r\nContent-Length: 17\r\n\r\n{}",
+            "a".repeat(17)
+        );
+        assert!(read_body(request.as_bytes()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn limits_request_lines_and_headers() {
+        let long_uri = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(8 * 1024));
+        assert_eq!(
+            parse_head(long_uri.as_bytes(), true).await.err(),
+            Some(StatusCode::URI_TOO_LONG)
+        );
+
+        let many_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: a\r\n".repeat(101));
+        assert_eq!(
+            parse_head(many_headers.as_bytes(), true).await.err(),
+            Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        );
+        let just_enough = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: a\r\n".repeat(100));
+        assert!(parse_head(just_enough.as_bytes(), true).await.is_ok());
+
+        let big_header = format!("GET / HTTP/1.1\r\nX-A: {}\r\n\r\n", "a".repeat(64 * 1024));
+        assert_eq!(
+            parse_head(big_header.as_bytes(), true).await.err(),
+            Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        );
+    }
+
+    #[tokio::test]
+    async fn times_out_slow_heads() {
+        let limits = ParseLimits {
+            read_timeout: Duration::from_millis(100),
+            ..limits()
+        };
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut reader = request_reader(server, &limits);
+
+        // Never finishes the headers
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: a\r\n")
+            .await
+            .unwrap();
+
+        let result = parse_request_head(&mut reader, &limits).await;
+        assert_eq!(
+            result.map(|_| ()).map_err(StatusCode::from),
+            Err(StatusCode::REQUEST_TIMEOUT)
+        );
+    }
+
+    #[tokio::test]
+    async fn times_out_idle_clients() {
+        let (_client, server) = tokio::io::duplex(1024);
+        let mut stream = IdleTimeout::new(server, Duration::from_millis(100));
+
+        let error = stream.read(&mut [0; 16]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn only_counts_time_spent_waiting_on_the_client() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut stream = IdleTimeout::new(server, Duration::from_millis(100));
+        let mut buf = [0; 16];
+
+        client.write_all(b"a").await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 1);
+
+        // Busy with something else, like writing what was read to disk
+        sleep(Duration::from_millis(300)).await;
+
+        let send = async {
+            sleep(Duration::from_millis(50)).await;
+            client.write_all(b"b").await.unwrap();
+        };
+        let (read, ()) = tokio::join!(stream.read(&mut buf), send);
+        assert_eq!(read.unwrap(), 1);
+    }
+}
@@ -11,6 +11,14 @@ pub enum HttpParseError {
     EndOfStream,
     LengthRequired,
     PayloadTooLarge,
+    /// The request line is longer than allowed, almost always because of the URI
+    UriTooLong,
+    /// There are too many headers, or they're too big all together
+    HeadersTooLarge,
+    /// A transfer coding other than chunked, like gzip
+    UnsupportedTransferCoding(String),
+    /// The client took too long to send the request, or went quiet halfway through
+    RequestTimeout,
 }
 
 impl std::fmt::Display for HttpParseError {
@@ -37,13 +45,28 @@ impl std::fmt::Display for HttpParseError {
             HttpParseError::PayloadTooLarge => {
                 write!(f, "Payload too large")
             }
+            HttpParseError::UnsupportedTransferCoding(ref s) => {
+                write!(f, "Transfer coding not supported: '{}'", s)
+            }
+            HttpParseError::UriTooLong => {
+                write!(f, "Request line too long")
+            }
+            HttpParseError::HeadersTooLarge => {
+                write!(f, "Request headers too large")
+            }
+            HttpParseError::RequestTimeout => {
+                write!(f, "Timed out reading the request")
+            }
         }
     }
 }
 
 impl From<std::io::Error> for HttpParseError {
     fn from(e: std::io::Error) -> Self {
-        HttpParseError::MalformedRequest(e.to_string())
+        match e.kind() {
+            std::io::ErrorKind::TimedOut => HttpParseError::RequestTimeout,
+            _ => HttpParseError::MalformedRequest(e.to_string()),
+        }
     }
 }
 
@@ -75,6 +98,10 @@ impl From<HttpParseError> for StatusCode {
             HttpParseError::EndOfStream => StatusCode::BAD_REQUEST,
             HttpParseError::LengthRequired => StatusCode::LENGTH_REQUIRED,
             HttpParseError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            HttpParseError::UnsupportedTransferCoding(_) => StatusCode::NOT_IMPLEMENTED,
+            HttpParseError::UriTooLong => StatusCode::URI_TOO_LONG,
+            HttpParseError::HeadersTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            HttpParseError::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
         }
     }
 }
@@ -3,6 +3,8 @@ use std::path::Path;
 use http::{header, Response};
 use tokio::fs;
 
+use crate::filesystem::write_file_atomic;
+
 use super::{
     message::{ByteRequest, ByteResponse},
     server::UnrecoverableError,
@@ -26,7 +28,7 @@ pub async fn handle_post(
     // than just a ByteRequest, which would be annoying to work with
     let empty: Vec<u8> = Vec::new();
     let content = request.body().as_ref().unwrap_or_else(|| empty.as_ref());
-    fs::write(path, content).await?;
+    write_file_atomic(path, content).await?;
 
     let body: Vec<u8> = format!("201: '{}' created!", request.uri().path()).into();
     let response: ByteResponse = Response::builder()
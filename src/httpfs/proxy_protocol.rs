@@ -0,0 +1,189 @@
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Starts every version 2 header, chosen so it can't be mistaken for anything else
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest version 1 header, including the CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+/// Read the PROXY protocol header a load balancer like HAProxy sends before anything else
+///
+/// Returns the address of the client the load balancer is passing on, or `None` if it's
+/// connecting on its own behalf, ie. for health checks. Exactly the header is read, so whatever
+/// follows (a TLS handshake or a request) is left in the stream
+/// https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+pub async fn read_proxy_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> io::Result<Option<SocketAddr>> {
+    // A version 1 header is at least 15 bytes, so this never reads past one
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid(
+            "connection didn't start with a PROXY protocol header",
+        ))
+    }
+}
+
+/// `PROXY TCP4 203.0.113.9 10.0.0.5 51234 443\r\n`
+async fn read_v1<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    start: &[u8],
+) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+
+    // Byte by byte, since anything after the line belongs to the connection
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY protocol v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol v1 header isn't text"))?;
+
+    parse_v1(line).ok_or_else(|| invalid(&format!("Invalid PROXY protocol v1 header '{}'", line)))
+}
+
+/// The client address of a version 1 line without its CRLF, `None` if the line is invalid
+fn parse_v1(line: &str) -> Option<Option<SocketAddr>> {
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    match parts[..] {
+        ["PROXY", "UNKNOWN", ..] => Some(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _destination, port, _] => {
+            let ip: IpAddr = source.parse().ok()?;
+
+            if ip.is_ipv4() != (family == "TCP4") {
+                return None;
+            }
+
+            Some(Some(SocketAddr::new(ip, port.parse().ok()?)))
+        }
+        _ => None,
+    }
+}
+
+/// A binary header: version and command, address family, length, then the addresses
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let length = stream.read_u16().await?;
+
+    let mut payload = vec![0; usize::from(length)];
+    stream.read_exact(&mut payload).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY protocol version"));
+    }
+
+    match version_command & 0x0f {
+        // LOCAL, the load balancer itself
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        _ => return Err(invalid("Unknown PROXY protocol v2 command")),
+    }
+
+    // Only the source address and port matter, the rest is the destination and extensions
+    let source = match family >> 4 {
+        // AF_INET
+        0x1 if payload.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&payload[0..4]).unwrap());
+            SocketAddr::new(ip.into(), u16::from_be_bytes([payload[8], payload[9]]))
+        }
+        // AF_INET6
+        0x2 if payload.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[0..16]).unwrap());
+            SocketAddr::new(ip.into(), u16::from_be_bytes([payload[32], payload[33]]))
+        }
+        // AF_UNSPEC or AF_UNIX, there's no IP to pass on
+        0x0 | 0x3 => return Ok(None),
+        _ => return Err(invalid("Invalid PROXY protocol v2 address")),
+    };
+
+    Ok(Some(source))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(header: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = header;
+        let result = read_proxy_header(&mut stream).await;
+        (result, stream.to_vec())
+    }
+
+    #[test]
+    fn parses_v1_lines() {
+        assert_eq!(
+            parse_v1("PROXY TCP4 203.0.113.9 10.0.0.5 51234 443"),
+            Some(Some("203.0.113.9:51234".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_v1("PROXY TCP6 2001:db8::1 2001:db8::2 51234 443"),
+            Some(Some("[2001:db8::1]:51234".parse().unwrap()))
+        );
+        assert_eq!(parse_v1("PROXY UNKNOWN"), Some(None));
+        assert_eq!(parse_v1("PROXY TCP6 203.0.113.9 10.0.0.5 51234 443"), None);
+        assert_eq!(parse_v1("PROXY TCP4 203.0.113.9 10.0.0.5 port 443"), None);
+        assert_eq!(parse_v1("PROXY TCP4  203.0.113.9 10.0.0.5 51234 443"), None);
+    }
+
+    #[tokio::test]
+    async fn reads_v1_and_leaves_the_request() {
+        let (result, rest) =
+            read(b"PROXY TCP4 203.0.113.9 10.0.0.5 51234 443\r\nGET / HTTP/1.1\r\n").await;
+        assert_eq!(result.unwrap(), Some("203.0.113.9:51234".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn rejects_long_v1_lines() {
+        let mut header = b"PROXY TCP4 ".to_vec();
+        header.extend([b'1'; 200]);
+        assert!(read(&header).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11, 0, 12]);
+        header.extend([203, 0, 113, 9, 10, 0, 0, 5]);
+        header.extend(51234u16.to_be_bytes());
+        header.extend(443u16.to_be_bytes());
+        header.extend(b"\x16\x03\x01");
+
+        let (result, rest) = read(&header).await;
+        assert_eq!(result.unwrap(), Some("203.0.113.9:51234".parse().unwrap()));
+        assert_eq!(rest, b"\x16\x03\x01");
+    }
+
+    #[tokio::test]
+    async fn reads_v2_local_as_no_client() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(read(&header).await.0.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_connections_without_a_header() {
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.0.is_err());
+    }
+}
@@ -0,0 +1,63 @@
+use http::{header, Response, StatusCode};
+
+use crate::httpfs::message::ByteResponse;
+
+/// Send the client to `location`, which must already be percent-encoded
+///
+/// `status` is one of the redirects, which differ in whether they're permanent and keep the
+/// method: 301 and 308 are permanent, 302 and 307 temporary, 307 and 308 keep the method and
+/// body, and 303 tells the client to GET the result of what it posted
+pub fn create_redirect(status: StatusCode, location: &str) -> ByteResponse {
+    let body: Vec<u8> = format!(
+        "{}: {}, see {}",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default(),
+        location
+    )
+    .into();
+
+    Response::builder()
+        .status(status)
+        .header(header::LOCATION, location)
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::CONTENT_TYPE, "text/plain")
+        .header(header::CONNECTION, "close")
+        .body(Some(body))
+        .unwrap()
+}
+
+/// Make a request path safe to redirect to, so it can only lead somewhere on this server
+///
+/// Browsers read `//evil.example/` and `/\evil.example/` as links to another site
+pub fn local_location(path: &str, query: Option<&str>) -> String {
+    let path = format!("/{}", path.trim_start_matches(['/', '\\']));
+
+    match query {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locations_stay_on_this_server() {
+        assert_eq!(local_location("/docs/", None), "/docs/");
+        assert_eq!(local_location("//evil.example/", None), "/evil.example/");
+        assert_eq!(
+            local_location("/\\evil.example/", Some("a=1")),
+            "/evil.example/?a=1"
+        );
+        assert_eq!(local_location("/\\/\\evil.example", None), "/evil.example");
+        assert_eq!(local_location("", None), "/");
+    }
+
+    #[test]
+    fn redirects_carry_the_location() {
+        let response = create_redirect(StatusCode::MOVED_PERMANENTLY, "/docs/");
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()[header::LOCATION], "/docs/");
+    }
+}
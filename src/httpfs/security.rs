@@ -0,0 +1,322 @@
+use std::io;
+
+use clap::ValueEnum;
+use http::{header, header::HeaderName, HeaderValue};
+
+use crate::httpfs::message::ByteResponse;
+
+/// `X-Frame-Options`, whether other pages may show ours in a frame
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameOptions {
+    Deny,
+    Sameorigin,
+    /// Don't send the header
+    Off,
+}
+
+/// What to do with files that can run scripts in the browser, like HTML and SVG
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ActiveContent {
+    /// Serve them like any other file
+    Allow,
+    /// Serve them with `Content-Security-Policy: sandbox`, so scripts can't act as our origin
+    Sandbox,
+    /// Make browsers download them instead of showing them
+    Attachment,
+}
+
+/// Types browsers run scripts in
+const ACTIVE_TYPES: [&str; 5] = [
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "text/xml",
+    "application/xml",
+];
+
+/// Headers that make browsers handle our responses more carefully
+#[derive(Debug)]
+pub struct SecurityHeaders {
+    /// Added to every response
+    headers: Vec<(HeaderName, HeaderValue)>,
+    /// For files in places anyone who can upload could have put them
+    active_content: ActiveContent,
+}
+
+impl SecurityHeaders {
+    /// `referrer_policy` of `off` leaves out `Referrer-Policy`, `csp` is only sent if given
+    pub fn new(
+        enabled: bool,
+        csp: Option<&str>,
+        referrer_policy: &str,
+        frame_options: FrameOptions,
+        active_content: ActiveContent,
+    ) -> io::Result<Self> {
+        let mut headers = vec![];
+
+        if enabled {
+            headers.push((
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            ));
+
+            if referrer_policy != "off" {
+                headers.push((
+                    header::REFERRER_POLICY,
+                    header_value("--referrer-policy", referrer_policy)?,
+                ));
+            }
+
+            match frame_options {
+                FrameOptions::Deny => {
+                    headers.push((header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY")))
+                }
+                FrameOptions::Sameorigin => headers.push((
+                    header::X_FRAME_OPTIONS,
+                    HeaderValue::from_static("SAMEORIGIN"),
+                )),
+                FrameOptions::Off => {}
+            }
+
+            if let Some(csp) = csp {
+                headers.push((header::CONTENT_SECURITY_POLICY, header_value("--csp", csp)?));
+            }
+        }
+
+        Ok(Self {
+            headers,
+            active_content,
+        })
+    }
+
+    /// Add the headers every response gets, unless a handler already set them
+    ///
+    /// The Content-Security-Policy is always added, since browsers enforce every policy they're given
+    pub fn apply(&self, response: &mut ByteResponse) {
+        let headers = response.headers_mut();
+
+        for (name, value) in &self.headers {
+            if name == header::CONTENT_SECURITY_POLICY {
+                headers.append(name, value.clone());
+            } else if !headers.contains_key(name) {
+                headers.insert(name, value.clone());
+            }
+        }
+    }
+
+    /// Stop an uploaded file from running scripts as our origin, if it's a type that can
+    pub fn restrict_active_content(&self, response: &mut ByteResponse) {
+        let is_active = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.split(';').next().unwrap_or_default().trim())
+            .is_some_and(|mime| {
+                ACTIVE_TYPES
+                    .iter()
+                    .any(|active| active.eq_ignore_ascii_case(mime))
+            });
+
+        if !is_active {
+            return;
+        }
+
+        let headers = response.headers_mut();
+
+        match self.active_content {
+            ActiveContent::Allow => {}
+            ActiveContent::Sandbox => {
+                headers.append(
+                    header::CONTENT_SECURITY_POLICY,
+                    HeaderValue::from_static("sandbox"),
+                );
+            }
+            ActiveContent::Attachment => {
+                headers.insert(
+                    header::CONTENT_DISPOSITION,
+                    HeaderValue::from_static("attachment"),
+                );
+            }
+        }
+    }
+}
+
+fn header_value(option: &str, value: &str) -> io::Result<HeaderValue> {
+    HeaderValue::from_str(value).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid {} value '{}'", option, value),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Response;
+
+    use super::*;
+
+    fn response(content_type: &str) -> ByteResponse {
+        Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .body(None)
+            .unwrap()
+    }
+
+    fn values(response: &ByteResponse, name: HeaderName) -> Vec<&str> {
+        response
+            .headers()
+            .get_all(name)
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn adds_headers_to_every_response() {
+        let security = SecurityHeaders::new(
+            true,
+            Some("default-src 'self'"),
+            "no-referrer",
+            FrameOptions::Deny,
+            ActiveContent::Sandbox,
+        )
+        .unwrap();
+
+        let mut served = response("text/plain");
+        security.apply(&mut served);
+
+        assert_eq!(values(&served, header::X_CONTENT_TYPE_OPTIONS), ["nosniff"]);
+        assert_eq!(values(&served, header::REFERRER_POLICY), ["no-referrer"]);
+        assert_eq!(values(&served, header::X_FRAME_OPTIONS), ["DENY"]);
+        assert_eq!(
+            values(&served, header::CONTENT_SECURITY_POLICY),
+            ["default-src 'self'"]
+        );
+    }
+
+    #[test]
+    fn keeps_headers_handlers_set() {
+        let security = SecurityHeaders::new(
+            true,
+            Some("default-src 'self'"),
+            "same-origin",
+            FrameOptions::Sameorigin,
+            ActiveContent::Sandbox,
+        )
+        .unwrap();
+
+        let mut served = response("text/html");
+        let headers = served.headers_mut();
+        headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+        headers.insert(
+            header::CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static("sandbox"),
+        );
+        security.apply(&mut served);
+
+        assert_eq!(values(&served, header::X_FRAME_OPTIONS), ["DENY"]);
+        assert_eq!(values(&served, header::REFERRER_POLICY), ["same-origin"]);
+        assert_eq!(
+            values(&served, header::CONTENT_SECURITY_POLICY),
+            ["sandbox", "default-src 'self'"]
+        );
+    }
+
+    #[test]
+    fn leaves_out_disabled_headers() {
+        let security =
+            SecurityHeaders::new(true, None, "off", FrameOptions::Off, ActiveContent::Sandbox)
+                .unwrap();
+
+        let mut served = response("text/plain");
+        security.apply(&mut served);
+
+        assert_eq!(values(&served, header::X_CONTENT_TYPE_OPTIONS), ["nosniff"]);
+        assert!(!served.headers().contains_key(header::REFERRER_POLICY));
+        assert!(!served.headers().contains_key(header::X_FRAME_OPTIONS));
+        assert!(!served
+            .headers()
+            .contains_key(header::CONTENT_SECURITY_POLICY));
+
+        let disabled = SecurityHeaders::new(
+            false,
+            Some("default-src 'self'"),
+            "no-referrer",
+            FrameOptions::Deny,
+            ActiveContent::Sandbox,
+        )
+        .unwrap();
+
+        let mut plain = response("text/plain");
+        disabled.apply(&mut plain);
+        assert_eq!(plain.headers().len(), 1);
+    }
+
+    #[test]
+    fn refuses_invalid_header_values() {
+        let error = SecurityHeaders::new(
+            true,
+            Some("default-src\n'self'"),
+            "no-referrer",
+            FrameOptions::Deny,
+            ActiveContent::Sandbox,
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("--csp"));
+    }
+
+    #[test]
+    fn restricts_active_content() {
+        let headers = |active_content| {
+            SecurityHeaders::new(false, None, "off", FrameOptions::Off, active_content).unwrap()
+        };
+
+        for content_type in [
+            "text/html; charset=utf-8",
+            "TEXT/HTML",
+            "application/xhtml+xml",
+            "image/svg+xml",
+            "text/xml",
+            "application/xml",
+        ] {
+            let mut sandboxed = response(content_type);
+            headers(ActiveContent::Sandbox).restrict_active_content(&mut sandboxed);
+            assert_eq!(
+                values(&sandboxed, header::CONTENT_SECURITY_POLICY),
+                ["sandbox"],
+                "{}",
+                content_type
+            );
+            assert!(!sandboxed
+                .headers()
+                .contains_key(header::CONTENT_DISPOSITION));
+
+            let mut attachment = response(content_type);
+            headers(ActiveContent::Attachment).restrict_active_content(&mut attachment);
+            assert_eq!(
+                values(&attachment, header::CONTENT_DISPOSITION),
+                ["attachment"]
+            );
+            assert!(!attachment
+                .headers()
+                .contains_key(header::CONTENT_SECURITY_POLICY));
+
+            let mut allowed = response(content_type);
+            headers(ActiveContent::Allow).restrict_active_content(&mut allowed);
+            assert_eq!(allowed.headers().len(), 1);
+        }
+
+        for content_type in ["text/plain", "image/png", "application/json"] {
+            let mut passive = response(content_type);
+            headers(ActiveContent::Sandbox).restrict_active_content(&mut passive);
+            headers(ActiveContent::Attachment).restrict_active_content(&mut passive);
+            assert_eq!(passive.headers().len(), 1, "{}", content_type);
+        }
+
+        let mut untyped = Response::builder().body(None).unwrap();
+        headers(ActiveContent::Sandbox).restrict_active_content(&mut untyped);
+        assert!(untyped.headers().is_empty());
+    }
+}
@@ -1,34 +1,859 @@
+use std::{collections::HashMap, net::IpAddr, os::unix::io::RawFd, sync::Arc};
+
 use owo_colors::OwoColorize;
-use tokio::net::TcpListener;
+use tokio::{
+    signal::unix::{signal, Signal, SignalKind},
+    sync::mpsc,
+    task::{JoinError, JoinSet},
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
 
-use crate::{cli::VERBOSE, colorize::MColorize, httpfs::connection::handle_connection};
+use crate::{
+    cli::VERBOSE,
+    colorize::MColorize,
+    httpfs::{
+        acl::create_403,
+        activation::{adopt_listener, systemd_listen_fds},
+        client_ip::accepts_peer,
+        config::ServerConfig,
+        connection::{handle_connection, reject_connection},
+        limits::{create_429, create_503, CONNECTION_RETRY_AFTER},
+        listener::{bind_tcp, bind_unix, default_bind, AsyncStream, Listener, PeerAddr},
+        message::ByteResponse,
+        proxy_protocol::read_proxy_header,
+        tls::create_acceptor,
+    },
+};
 
 pub type UnrecoverableError = Box<dyn std::error::Error>;
 
-pub async fn run_server(
-    directory: &str,
-    port: u16,
-    verbosity: u8,
-) -> Result<(), UnrecoverableError> {
+/// A connection that was accepted but not handled yet
+struct Accepted {
+    stream: Box<dyn AsyncStream>,
+    peer: PeerAddr,
+    /// Set if the connection still needs a TLS handshake
+    tls: Option<TlsAcceptor>,
+}
+
+pub async fn run_server(config: ServerConfig) -> Result<(), UnrecoverableError> {
+    println!(
+        "Starting Server: Serving directory {}",
+        config
+            .hosts
+            .default_host()
+            .root
+            .display()
+            .out_color(|t| t.blue()),
+    );
+
+    if config.hosts.default_host().read_only {
+        println!(
+            "  {}",
+            "Read-only, uploads are disabled".out_color(|t| t.yellow())
+        );
+    } else if !config.upload_dirs.is_empty() {
+        println!(
+            "  Uploads only allowed under {}",
+            config.upload_dirs.join(", ").out_color(|t| t.cyan())
+        );
+    }
+
+    for spec in config.hosts.iter() {
+        println!(
+            "  Virtual host {} → {}{}",
+            spec.pattern.out_color(|t| t.cyan()),
+            spec.host.root.display().out_color(|t| t.blue()),
+            if spec.host.read_only {
+                " (read-only)"
+            } else {
+                ""
+            },
+        );
+    }
+
+    // Set up signal handling before we start accepting, so an early signal isn't missed
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    let inherited_fds = inherited_fds(&config)?;
+    let listeners = open_listeners(&config, &inherited_fds).await?;
+    let config = Arc::new(config);
+
+    let (accepted_tx, mut accepted_rx) = mpsc::channel::<Accepted>(64);
+    let mut accept_loops = JoinSet::new();
+
+    for (listener, tls) in listeners {
+        // Log the actual address, since binding to port 0 picks a random port
+        println!(
+            "Listening on {}{}{}",
+            listener.local_addr()?.out_color(|t| t.green()),
+            if tls.is_some() { " (TLS)" } else { "" },
+            if config.proxy_protocol {
+                " (PROXY protocol)"
+            } else {
+                ""
+            },
+        );
+
+        accept_loops.spawn(accept_loop(
+            listener,
+            tls,
+            config.clone(),
+            accepted_tx.clone(),
+        ));
+    }
+
+    drop(accepted_tx);
+
+    let mut tracker = ConnectionTracker::new();
+
+    let signal_name = loop {
+        tokio::select! {
+            Some(accepted) = accepted_rx.recv() => tracker.spawn(accepted, &config),
+            Some(finished) = tracker.connections.join_next() => tracker.finished(finished),
+            Some(_) = tracker.rejecting.join_next() => {},
+            // Accept loops only stop if they hit an error, so the first one to stop takes the server down
+            Some(res) = accept_loops.join_next() => res??,
+            signal_name = next_signal(&mut sigint, &mut sigterm) => break signal_name,
+        }
+    };
+
+    // Stop accepting, but still serve anything that was accepted and not picked up yet
+    accept_loops.abort_all();
+    tracker.rejecting.abort_all();
+    while let Ok(accepted) = accepted_rx.try_recv() {
+        tracker.spawn(accepted, &config);
+    }
+
+    if inherited_fds.is_empty() {
+        remove_unix_sockets(&config);
+    }
+
+    let total = tracker.active.len();
+    println!(
+        "Received {}, waiting up to {}s for {} connection(s) to finish",
+        signal_name.out_color(|t| t.yellow()),
+        config.grace_period.as_secs_f32(),
+        total.out_color(|t| t.cyan()),
+    );
+
+    let drain = async {
+        while let Some(finished) = tracker.connections.join_next().await {
+            tracker.finished(finished);
+        }
+    };
+
+    tokio::select! {
+        _ = timeout(config.grace_period, drain) => {},
+        signal_name = next_signal(&mut sigint, &mut sigterm) => {
+            println!("Received {} again, not waiting any longer", signal_name.out_color(|t| t.yellow()));
+        },
+    };
+
+    if tracker.active.is_empty() {
+        println!("All connections finished, bye!");
+        return Ok(());
+    }
+
+    tracker.connections.abort_all();
+    while tracker.connections.join_next().await.is_some() {}
+
     println!(
-        "Starting Server: Serving directory {} on port {}",
-        directory.out_color(|t| t.blue()),
-        port.out_color(|t| t.green()),
+        "Aborted {} of {} connection(s):",
+        tracker.active.len().out_color(|t| t.red()),
+        total,
     );
 
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).await?;
+    for peer in tracker.active.values() {
+        println!("  {}", peer.out_color(|t| t.bright_yellow()));
+    }
+
+    Ok(())
+}
+
+/// Most connections being turned away at once, past this they're closed without a response
+const MAX_REJECTING: usize = 64;
+
+/// Keeps track of running connections and who they're from
+///
+/// Every connection task reports its id when it ends, even by panicking, so anything left in
+/// `active` once we give up waiting is a connection we had to abort
+struct ConnectionTracker {
+    connections: JoinSet<()>,
+    /// Ids of connections whose task ended, sent by their `EndGuard`
+    ended: (mpsc::UnboundedSender<u64>, mpsc::UnboundedReceiver<u64>),
+    /// Connections being served, ones turned away for hitting a limit aren't counted
+    active: HashMap<u64, PeerAddr>,
+    /// Connections being sent the response saying why they were turned away
+    rejecting: JoinSet<()>,
+    per_ip: HashMap<IpAddr, usize>,
+    next_id: u64,
+}
+
+impl ConnectionTracker {
+    fn new() -> Self {
+        Self {
+            connections: JoinSet::new(),
+            ended: mpsc::unbounded_channel(),
+            active: HashMap::new(),
+            rejecting: JoinSet::new(),
+            per_ip: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn spawn(&mut self, accepted: Accepted, config: &Arc<ServerConfig>) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        // Headers aren't read yet, so clients behind a trusted proxy all count as the proxy
+        let ip = accepted.peer.ip();
+        let from_ip = ip.map_or(0, |ip| self.per_ip.get(&ip).copied().unwrap_or(0));
+        let limits = config.connection_limits;
+
+        let rejection = if !accepts_peer(
+            &accepted.peer,
+            &config.ip_filter,
+            config.trusted_proxies.as_ref(),
+        ) {
+            Some(create_403("your address isn't allowed to connect"))
+        } else if limits.total.is_some_and(|max| self.active.len() >= max) {
+            Some(create_503(CONNECTION_RETRY_AFTER))
+        } else if limits.per_ip.is_some_and(|max| from_ip >= max) {
+            Some(create_429(CONNECTION_RETRY_AFTER))
+        } else {
+            self.active.insert(id, accepted.peer.clone());
+            if let Some(ip) = ip {
+                *self.per_ip.entry(ip).or_default() += 1;
+            }
+            None
+        };
+
+        if config.verbosity >= VERBOSE {
+            println!(
+                "Connection from {}{} ({} active, {} from this IP)",
+                accepted.peer.out_color(|t| t.bright_yellow()),
+                match &rejection {
+                    Some(response) => format!(", turned away with {}", response.status()),
+                    None => String::new(),
+                },
+                self.active.len(),
+                ip.and_then(|ip| self.per_ip.get(&ip)).copied().unwrap_or(0),
+            );
+        }
+
+        if let Some(response) = rejection {
+            self.reject(accepted, response);
+            return;
+        }
+
+        let config = config.clone();
+        let guard = EndGuard {
+            id,
+            ended: self.ended.0.clone(),
+        };
+        self.connections.spawn(async move {
+            let _guard = guard;
+
+            let stream: Box<dyn AsyncStream> = match accepted.tls {
+                Some(acceptor) => {
+                    // Otherwise a client that never finishes the handshake keeps its slot forever
+                    let handshake = timeout(
+                        config.parse_limits.read_timeout,
+                        acceptor.accept(accepted.stream),
+                    )
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "timed out waiting for the TLS handshake",
+                        ))
+                    });
+
+                    match handshake {
+                        Ok(stream) => Box::new(stream),
+                        Err(e) => {
+                            if config.verbosity >= VERBOSE {
+                                eprintln!("TLS handshake with {} failed: {}", accepted.peer, e);
+                            }
+                            return;
+                        }
+                    }
+                }
+                None => accepted.stream,
+            };
+
+            handle_connection(stream, accepted.peer, &config).await;
+        });
+    }
+
+    /// Answer a connection that was turned away
+    ///
+    /// A TLS handshake costs more than the response is worth, so those are just closed. The
+    /// others get the response from tasks of their own, but only `MAX_REJECTING` at once, since
+    /// they aren't counted against the connection limits
+    fn reject(&mut self, accepted: Accepted, response: ByteResponse) {
+        if accepted.tls.is_some() || self.rejecting.len() >= MAX_REJECTING {
+            return;
+        }
+
+        self.rejecting
+            .spawn(reject_connection(accepted.stream, response));
+    }
+
+    fn finished(&mut self, result: Result<(), JoinError>) {
+        if let Err(e) = result {
+            if e.is_panic() {
+                eprintln!("Connection panicked: {}", e);
+            }
+        }
+
+        // A task's guard is dropped before the JoinSet sees it finish, so its id is already here
+        while let Ok(id) = self.ended.1.try_recv() {
+            let ip = self.active.remove(&id).and_then(|peer| peer.ip());
+
+            if let Some(ip) = ip {
+                if let Some(count) = self.per_ip.get_mut(&ip) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.per_ip.remove(&ip);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sends the id of a connection when its task ends, whether it returned, panicked or was aborted
+///
+/// `JoinError` doesn't say which connection panicked, the task's `Id` would but is unstable
+struct EndGuard {
+    id: u64,
+    ended: mpsc::UnboundedSender<u64>,
+}
+
+impl Drop for EndGuard {
+    fn drop(&mut self) {
+        let _ = self.ended.send(self.id);
+    }
+}
+
+async fn next_signal(sigint: &mut Signal, sigterm: &mut Signal) -> &'static str {
+    tokio::select! {
+        _ = sigint.recv() => "SIGINT",
+        _ = sigterm.recv() => "SIGTERM",
+    }
+}
+
+/// Sockets we inherited from systemd or our parent
+fn inherited_fds(config: &ServerConfig) -> std::io::Result<Vec<RawFd>> {
+    let mut fds = systemd_listen_fds()?;
+    fds.extend(&config.listen_fds);
+    // Adopting the same fd twice would close it twice
+    fds.sort_unstable();
+    fds.dedup();
+    Ok(fds)
+}
+
+/// Use the sockets we inherited if there are any, otherwise bind our own
+///
+/// Listeners are paired with the TLS acceptor to use for them, if they serve HTTPS
+async fn open_listeners(
+    config: &ServerConfig,
+    inherited_fds: &[RawFd],
+) -> std::io::Result<Vec<(Listener, Option<TlsAcceptor>)>> {
+    let acceptor = match &config.tls {
+        Some(tls) => Some(create_acceptor(tls.cert.clone(), tls.key.clone())?),
+        None => None,
+    };
+
+    let tls_binds = config
+        .tls
+        .as_ref()
+        .map(|tls| tls.binds.as_slice())
+        .unwrap_or_default();
+
+    // Without --tls-bind, a certificate means every TCP listener serves HTTPS
+    let tls_for = |listener: &Listener| match listener {
+        Listener::Tcp(_) if tls_binds.is_empty() => acceptor.clone(),
+        _ => None,
+    };
+
+    let mut listeners = vec![];
+
+    if !inherited_fds.is_empty() {
+        if !config.binds.is_empty() || !config.unix_sockets.is_empty() || !tls_binds.is_empty() {
+            println!(
+                "{}",
+                "Using inherited sockets, ignoring --bind, --tls-bind and --unix"
+                    .out_color(|t| t.yellow())
+            );
+        }
+
+        for fd in inherited_fds {
+            let listener = adopt_listener(*fd)?;
+            let tls = tls_for(&listener);
+            listeners.push((listener, tls));
+        }
+
+        return Ok(listeners);
+    }
+
+    let default_bind = [default_bind()];
+    let binds = if config.binds.is_empty() && config.unix_sockets.is_empty() && tls_binds.is_empty()
+    {
+        &default_bind
+    } else {
+        config.binds.as_slice()
+    };
+
+    for bind in binds {
+        let listener = bind_tcp(bind, config.port).await?;
+        let tls = tls_for(&listener);
+        listeners.push((listener, tls));
+    }
+
+    for bind in tls_binds {
+        listeners.push((bind_tcp(bind, config.port).await?, acceptor.clone()));
+    }
+
+    for path in &config.unix_sockets {
+        listeners.push((bind_unix(path, &config.unix_options)?, None));
+    }
+
+    Ok(listeners)
+}
+
+/// Clean up the socket files we created, inherited sockets belong to whoever passed them to us
+fn remove_unix_sockets(config: &ServerConfig) {
+    for path in &config.unix_sockets {
+        if let Err(e) = std::fs::remove_file(path) {
+            eprintln!("Failed to remove unix:{}: {}", path.display(), e);
+        }
+    }
+}
+
+async fn accept_loop(
+    listener: Listener,
+    tls: Option<TlsAcceptor>,
+    config: Arc<ServerConfig>,
+    accepted: mpsc::Sender<Accepted>,
+) -> std::io::Result<()> {
+    // Connections still sending their PROXY protocol header, aborted with the loop
+    let mut pending = JoinSet::new();
 
     loop {
-        let (stream, addr) = listener.accept().await?;
+        let connection = tokio::select! {
+            res = listener.accept() => {
+                let (stream, peer) = res?;
+                let connection = Accepted {
+                    stream,
+                    peer,
+                    tls: tls.clone(),
+                };
+
+                if config.proxy_protocol {
+                    pending.spawn(read_proxied(connection, config.clone()));
+                    continue;
+                }
+
+                connection
+            }
+            Some(res) = pending.join_next() => match res {
+                Ok(Some(connection)) => connection,
+                _ => continue,
+            },
+        };
+
+        if accepted.send(connection).await.is_err() {
+            // The server is shutting down and isn't taking new connections
+            return Ok(());
+        }
+    }
+}
+
+/// Read the PROXY protocol header, so the connection is tracked and checked as the real client's
+///
+/// Returns `None` if there wasn't a valid header in time, which closes the connection
+async fn read_proxied(mut connection: Accepted, config: Arc<ServerConfig>) -> Option<Accepted> {
+    let header = timeout(
+        config.parse_limits.read_timeout,
+        read_proxy_header(&mut connection.stream),
+    )
+    .await
+    .unwrap_or_else(|_| {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "timed out waiting for the PROXY protocol header",
+        ))
+    });
+
+    match header {
+        Ok(Some(client)) => {
+            connection.peer = PeerAddr::Proxied {
+                client,
+                proxy: Box::new(connection.peer),
+            };
+        }
+        // The load balancer connecting for itself, ie. a health check
+        Ok(None) => {}
+        Err(e) => {
+            if config.verbosity >= VERBOSE {
+                eprintln!("Closed connection from {}: {}", connection.peer, e);
+            }
+            return None;
+        }
+    }
+
+    Some(connection)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        net::{SocketAddr, TcpListener},
+        os::unix::{io::IntoRawFd, net::UnixListener},
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    use clap::Parser;
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        net::{TcpStream, UnixStream},
+    };
+    use tokio_rustls::{
+        rustls::{
+            crypto::ring,
+            pki_types::{pem::PemObject, CertificateDer, ServerName},
+            ClientConfig, RootCertStore,
+        },
+        TlsConnector,
+    };
+
+    use super::*;
+    use crate::cli::Cli;
+
+    /// Serve `dir` on a socket that's already listening, the way a parent process passes one
+    fn config(dir: &Path, args: &[&str]) -> (ServerConfig, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fd = listener.into_raw_fd().to_string();
+
+        let mut all_args = vec!["httpfs", "-d", dir.to_str().unwrap(), "--listen-fd", &fd];
+        all_args.extend(args);
+
+        let config = ServerConfig::try_from(&Cli::parse_from(all_args)).unwrap();
+        (config, addr)
+    }
+
+    /// Run the server until `client` is done
+    async fn serve_while<F: Future>(config: ServerConfig, client: F) -> F::Output {
+        tokio::select! {
+            result = run_server(config) => panic!("the server stopped: {:?}", result.err()),
+            output = client => output,
+        }
+    }
+
+    /// GET `path` and read the whole response, the server closes the connection after it
+    async fn get<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, path: &str) -> String {
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await.unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    /// POST `body` to `path` on `host` and read the whole response
+    async fn post(addr: SocketAddr, host: &str, path: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}",
+            path,
+            host,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await.unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    /// A self-signed certificate for `localhost`, as `(cert, key)` paths
+    fn self_signed_cert(dir: &Path) -> (PathBuf, PathBuf) {
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
 
-        if verbosity >= VERBOSE {
-            println!("Connection from {}", addr.out_color(|t| t.bright_yellow()));
+        let certified = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        std::fs::write(&cert, certified.cert.pem()).unwrap();
+        std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+
+        (cert, key)
+    }
+
+    #[tokio::test]
+    async fn serves_inherited_listeners() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        std::fs::write(dir.join("hello.txt"), "hello").unwrap();
+
+        let (config, addr) = config(dir, &[]);
+        let response = serve_while(config, async {
+            get(TcpStream::connect(addr).await.unwrap(), "/hello.txt").await
+        })
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nhello"), "{}", response);
+
+        let socket = dir.join("httpfs.sock");
+        let fd = UnixListener::bind(&socket)
+            .unwrap()
+            .into_raw_fd()
+            .to_string();
+        let args = ["httpfs", "-d", dir.to_str().unwrap(), "--listen-fd", &fd];
+        let config = ServerConfig::try_from(&Cli::parse_from(args)).unwrap();
+
+        let response = serve_while(config, async {
+            get(UnixStream::connect(&socket).await.unwrap(), "/hello.txt").await
+        })
+        .await;
+        assert!(response.ends_with("\r\n\r\nhello"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn refuses_clients_before_reading_their_bodies() {
+        let dir = tempfile::tempdir().unwrap();
+        let args = [
+            "--trusted-proxy",
+            "127.0.0.1",
+            "--deny-ip",
+            "203.0.113.0/24",
+        ];
+        let (config, addr) = config(dir.path(), &args);
+
+        // The body never comes, so reading it would time out instead
+        let response = serve_while(config, async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = "POST /a.bin HTTP/1.1\r\nHost: localhost\r\n\
+                X-Forwarded-For: 203.0.113.9\r\nContent-Length: 1000000\r\n\r\n";
+            stream.write_all(request.as_bytes()).await.unwrap();
+
+            let mut response = vec![];
+            timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+                .await
+                .unwrap()
+                .unwrap();
+            String::from_utf8_lossy(&response).into_owned()
+        })
+        .await;
+
+        assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn refuses_writes_on_read_only_hosts() {
+        let dir = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        let vhost = format!("ro.example={},read-only", other.path().display());
+
+        let (per_host, addr) = config(dir.path(), &["--vhost", &vhost]);
+        let (read_only, writable) = serve_while(per_host, async {
+            (
+                post(addr, "ro.example", "/a.txt", "a").await,
+                post(addr, "localhost", "/a.txt", "a").await,
+            )
+        })
+        .await;
+
+        assert!(read_only.starts_with("HTTP/1.1 405"), "{}", read_only);
+        assert!(
+            read_only.contains("allow: GET, HEAD, OPTIONS\r\n"),
+            "{}",
+            read_only
+        );
+        assert!(!other.path().join("a.txt").exists());
+        assert!(writable.starts_with("HTTP/1.1 201"), "{}", writable);
+        assert_eq!(std::fs::read(dir.path().join("a.txt")).unwrap(), b"a");
+
+        let (all_hosts, addr) = config(dir.path(), &["--read-only", "--vhost", &vhost]);
+        let (everywhere, options) = serve_while(all_hosts, async {
+            let everywhere = post(addr, "localhost", "/b.txt", "b").await;
+
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = "OPTIONS /b.txt HTTP/1.1\r\nHost: localhost\r\n\r\n";
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut options = vec![];
+            stream.read_to_end(&mut options).await.unwrap();
+
+            (everywhere, String::from_utf8_lossy(&options).into_owned())
+        })
+        .await;
+
+        assert!(everywhere.starts_with("HTTP/1.1 405"), "{}", everywhere);
+        assert!(!dir.path().join("b.txt").exists());
+        assert!(options.starts_with("HTTP/1.1 204"), "{}", options);
+        assert!(
+            options.contains("allow: GET, HEAD, OPTIONS\r\n"),
+            "{}",
+            options
+        );
+    }
+
+    #[tokio::test]
+    async fn only_takes_uploads_in_upload_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let args = ["--upload-dir", "/incoming//", "--upload-dir", "/drop/box/"];
+        let (config, addr) = config(dir.path(), &args);
+
+        let responses = serve_while(config, async {
+            let mut responses = vec![];
+            for path in [
+                "/incoming/a.txt",
+                "/incoming/nested/b.txt",
+                "/drop/box/c.txt",
+                "/drop/d.txt",
+                "/incoming-old/e.txt",
+                "/f.txt",
+                "/incoming/../g.txt",
+                "/incoming/%2e%2e/h.txt",
+            ] {
+                responses.push((path, post(addr, "localhost", path, "x").await));
+            }
+            responses
+        })
+        .await;
+
+        for (path, response) in responses {
+            let created = [
+                "/incoming/a.txt",
+                "/incoming/nested/b.txt",
+                "/drop/box/c.txt",
+            ]
+            .contains(&path);
+            let status = if created { "201" } else { "405" };
+
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {}", status)),
+                "{}: {}",
+                path,
+                response
+            );
+        }
+
+        assert!(dir.path().join("incoming/nested/b.txt").exists());
+        for refused in ["drop/d.txt", "incoming-old", "f.txt", "g.txt", "h.txt"] {
+            assert!(!dir.path().join(refused).exists(), "{}", refused);
         }
+    }
+
+    #[test]
+    fn normalizes_upload_dirs() {
+        let parse = |prefix: &str| {
+            Cli::try_parse_from(["httpfs", "--upload-dir", prefix]).map(|cli| cli.upload_dirs)
+        };
+
+        assert_eq!(parse("/incoming/").unwrap(), ["/incoming"]);
+        assert_eq!(parse("/incoming//").unwrap(), ["/incoming"]);
+        assert_eq!(parse("/").unwrap(), ["/"]);
+        assert!(parse("incoming").is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_https() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let (cert, key) = self_signed_cert(dir);
+        let served = dir.join("served");
+        std::fs::create_dir(&served).unwrap();
+        std::fs::write(served.join("hello.txt"), "hello over TLS").unwrap();
+
+        let (config, addr) = config(
+            &served,
+            &[
+                "--tls-cert",
+                cert.to_str().unwrap(),
+                "--tls-key",
+                key.to_str().unwrap(),
+            ],
+        );
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(&cert).unwrap())
+            .unwrap();
+        let client = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(client));
 
-        let dir = directory.to_owned();
+        let response = serve_while(config, async {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let name = ServerName::try_from("localhost").unwrap();
+            let stream = connector.connect(name, stream).await.unwrap();
+            get(stream, "/hello.txt").await
+        })
+        .await;
 
-        tokio::spawn(async move {
-            handle_connection(stream, &dir, verbosity).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nhello over TLS"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn times_out_tls_handshakes() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let (cert, key) = self_signed_cert(dir);
+
+        let (config, addr) = config(
+            dir,
+            &[
+                "--tls-cert",
+                cert.to_str().unwrap(),
+                "--tls-key",
+                key.to_str().unwrap(),
+                "--read-timeout",
+                "0.2",
+            ],
+        );
+
+        // Connect and never start the handshake, the server should hang up on us
+        let closed = serve_while(config, async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let mut buf = [0; 16];
+            timeout(Duration::from_secs(5), stream.read(&mut buf)).await
+        })
+        .await;
+
+        assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))), "{:?}", closed);
+    }
+
+    #[tokio::test]
+    async fn frees_the_slots_of_panicked_connections() {
+        let mut tracker = ConnectionTracker::new();
+        let peer = PeerAddr::Tcp("127.0.0.1:5000".parse().unwrap());
+        tracker.per_ip.insert(peer.ip().unwrap(), 1);
+        tracker.active.insert(0, peer);
+
+        let guard = EndGuard {
+            id: 0,
+            ended: tracker.ended.0.clone(),
+        };
+        tracker.connections.spawn(async move {
+            let _guard = guard;
+            panic!("connection handler panicked");
         });
+
+        let finished = tracker.connections.join_next().await.unwrap();
+        assert!(finished.as_ref().is_err_and(JoinError::is_panic));
+        tracker.finished(finished);
+
+        assert!(tracker.active.is_empty());
+        assert!(tracker.per_ip.is_empty());
     }
 }
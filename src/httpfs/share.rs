@@ -0,0 +1,285 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    time::Duration,
+};
+
+use hmac::{Hmac, Mac};
+use http::Method;
+use owo_colors::OwoColorize;
+use sha2::Sha256;
+
+use crate::{
+    colorize::MColorize,
+    filesystem::flatten_path,
+    httpfs::{
+        parse::QueryMap,
+        tokens::{decode_hex, expires_after, format_timestamp, random_hex, unix_now},
+        vhost::{HostPattern, VirtualHost},
+    },
+};
+
+/// Signs and checks share links, `/path?expires=UNIX-TIME&sig=HMAC`
+///
+/// The signature is a HMAC-SHA256 over the method, virtual host, path and expiry, keyed with a
+/// secret only the server knows. A valid link skips authentication and access rules, but only for
+/// the exact path and method on the host it was made for
+#[derive(Debug)]
+pub struct ShareLinks {
+    secret: Vec<u8>,
+}
+
+impl ShareLinks {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let secret = std::fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read share secret {}: {}", path.display(), e),
+            )
+        })?;
+
+        let secret = secret.trim();
+
+        if secret.len() < 32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Share secret {} is too short, use at least 32 characters",
+                    path.display()
+                ),
+            ));
+        }
+
+        Ok(Self {
+            secret: secret.as_bytes().to_vec(),
+        })
+    }
+
+    /// Sign `method` on `path` of the virtual host named `host` until `expires`, returning the
+    /// signature as hex
+    pub fn sign(&self, method: &Method, host: &str, path: &str, expires: u64) -> String {
+        let signature = self
+            .mac(method, host, path, expires)
+            .finalize()
+            .into_bytes();
+        signature.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Check if a request is using a share link
+    ///
+    /// Returns `None` if the query has no share link parameters at all, otherwise whether the
+    /// link is valid for `method` on `path` of the virtual host named `host`, with the reason if
+    /// it isn't
+    pub fn check(
+        &self,
+        method: &Method,
+        host: &str,
+        path: &str,
+        query: &QueryMap,
+    ) -> Option<Result<(), String>> {
+        let expires = query.get("expires").cloned().flatten();
+        let sig = query.get("sig").cloned().flatten();
+
+        let (expires, sig) = match (expires, sig) {
+            (None, None) => return None,
+            (Some(expires), Some(sig)) => (expires, sig),
+            _ => return Some(Err("share link needs both expires and sig".to_string())),
+        };
+
+        let expires: u64 = match expires.parse() {
+            Ok(expires) => expires,
+            Err(_) => return Some(Err("share link has an invalid expiry".to_string())),
+        };
+
+        let sig = match decode_hex(&sig) {
+            Some(sig) => sig,
+            None => return Some(Err("share link has an invalid signature".to_string())),
+        };
+
+        // verify_slice compares in constant time
+        if self
+            .mac(method, host, path, expires)
+            .verify_slice(&sig)
+            .is_err()
+        {
+            return Some(Err("share link has an invalid signature".to_string()));
+        }
+
+        if unix_now() >= expires {
+            return Some(Err(format!(
+                "share link expired at {}",
+                format_timestamp(expires)
+            )));
+        }
+
+        Some(Ok(()))
+    }
+
+    fn mac(&self, method: &Method, host: &str, path: &str, expires: u64) -> Hmac<Sha256> {
+        // A link for GET also works for HEAD, so browsers and curl -I can check it
+        let method = match *method {
+            Method::HEAD => &Method::GET,
+            _ => method,
+        };
+
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        // With the host, a link for /a.pdf on one virtual host doesn't work for /a.pdf on another
+        mac.update(format!("{}\n{}\n{}\n{}", method, host, path, expires).as_bytes());
+        mac
+    }
+}
+
+/// Run `httpfs share`, printing a signed link for `path` of the virtual host picked by `host`,
+/// or the default host
+///
+/// Creates a new random secret at `secret_path` if there isn't one yet
+pub fn run_share_command(
+    secret_path: &Path,
+    path: &str,
+    method: &Method,
+    host: Option<&HostPattern>,
+    lifetime: Duration,
+    base_url: Option<&str>,
+) -> io::Result<()> {
+    if !secret_path.exists() {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(secret_path)?;
+        writeln!(file, "{}", random_hex(32)?)?;
+        file.sync_all()?;
+
+        eprintln!(
+            "Created a new share secret at {}",
+            secret_path.display().out_color(|t| t.blue())
+        );
+    }
+
+    let links = ShareLinks::load(secret_path)?;
+
+    // Sign the path the way the server will see it, decoded and flattened
+    let path = format!("/{}", flatten_path(path).to_string_lossy());
+    let expires = expires_after(lifetime)?;
+    // Named like the server names its hosts, see `VirtualHost::name`
+    let host = host.map_or_else(|| VirtualHost::new("").name, HostPattern::to_string);
+    let sig = links.sign(method, &host, &path, expires);
+
+    let encoded_path = path
+        .split('/')
+        .map(|segment| urlencoding::encode(segment).into_owned())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    eprintln!(
+        "{} {} until {}",
+        method.out_color(|t| t.green()),
+        path.out_color(|t| t.cyan()),
+        format_timestamp(expires)
+    );
+    println!(
+        "{}{}?expires={}&sig={}",
+        base_url.unwrap_or_default().trim_end_matches('/'),
+        encoded_path,
+        expires,
+        sig
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn links() -> ShareLinks {
+        ShareLinks {
+            secret: b"0123456789abcdef0123456789abcdef".to_vec(),
+        }
+    }
+
+    fn query(expires: u64, sig: &str) -> QueryMap {
+        QueryMap::from([
+            ("expires".to_string(), Some(expires.to_string())),
+            ("sig".to_string(), Some(sig.to_string())),
+        ])
+    }
+
+    #[test]
+    fn accepts_links_for_their_path_method_and_host() {
+        let links = links();
+        let expires = unix_now() + 60;
+        let sig = links.sign(&Method::GET, "*", "/a.pdf", expires);
+        let query = query(expires, &sig);
+
+        assert_eq!(
+            links.check(&Method::GET, "*", "/a.pdf", &query),
+            Some(Ok(()))
+        );
+        assert_eq!(
+            links.check(&Method::HEAD, "*", "/a.pdf", &query),
+            Some(Ok(()))
+        );
+    }
+
+    #[test]
+    fn refuses_links_for_anything_else() {
+        let links = links();
+        let expires = unix_now() + 60;
+        let sig = links.sign(&Method::GET, "*", "/a.pdf", expires);
+        let query = query(expires, &sig);
+
+        assert!(matches!(
+            links.check(&Method::POST, "*", "/a.pdf", &query),
+            Some(Err(_))
+        ));
+        assert!(matches!(
+            links.check(&Method::GET, "*", "/b.pdf", &query),
+            Some(Err(_))
+        ));
+        assert!(matches!(
+            links.check(&Method::GET, "docs.example.com", "/a.pdf", &query),
+            Some(Err(_))
+        ));
+
+        let later = QueryMap::from([
+            ("expires".to_string(), Some((expires + 1).to_string())),
+            ("sig".to_string(), Some(sig)),
+        ]);
+        assert!(matches!(
+            links.check(&Method::GET, "*", "/a.pdf", &later),
+            Some(Err(_))
+        ));
+    }
+
+    #[test]
+    fn refuses_expired_links() {
+        let links = links();
+        let expires = unix_now() - 1;
+        let sig = links.sign(&Method::GET, "*", "/a.pdf", expires);
+
+        let result = links.check(&Method::GET, "*", "/a.pdf", &query(expires, &sig));
+        assert!(matches!(result, Some(Err(reason)) if reason.contains("expired")));
+    }
+
+    #[test]
+    fn ignores_requests_without_links() {
+        let links = links();
+        let query = QueryMap::from([("download".to_string(), None)]);
+
+        assert_eq!(links.check(&Method::GET, "*", "/a.pdf", &query), None);
+        assert!(matches!(
+            links.check(
+                &Method::GET,
+                "*",
+                "/a.pdf",
+                &QueryMap::from([("sig".to_string(), Some("ab".to_string()))])
+            ),
+            Some(Err(_))
+        ));
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use http::{header, StatusCode};
+
+use crate::httpfs::{
+    escape::escape_html,
+    message::{ByteRequest, ByteResponse},
+};
+
+/// A HTML page with `{{name}}` placeholders, which are HTML escaped, and `{{{name}}}` ones,
+/// which are inserted as they are for bits of HTML we generate ourselves
+#[derive(Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug)]
+enum Part {
+    Text(String),
+    Escaped(String),
+    Raw(String),
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut parts = vec![];
+        let mut rest = source;
+
+        while let Some(start) = rest.find("{{") {
+            parts.push(Part::Text(rest[..start].to_string()));
+            rest = &rest[start..];
+
+            let (raw, open, close) = if rest.starts_with("{{{") {
+                (true, "{{{", "}}}")
+            } else {
+                (false, "{{", "}}")
+            };
+
+            let end = rest
+                .find(close)
+                .ok_or_else(|| format!("'{}' is never closed", open))?;
+            let name = rest[open.len()..end].trim().to_string();
+
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!(
+                    "invalid placeholder '{}'",
+                    &rest[..end + close.len()]
+                ));
+            }
+
+            parts.push(if raw {
+                Part::Raw(name)
+            } else {
+                Part::Escaped(name)
+            });
+            rest = &rest[end + close.len()..];
+        }
+
+        parts.push(Part::Text(rest.to_string()));
+        Ok(Self { parts })
+    }
+
+    /// Fill in the placeholders, ones without a value are left empty
+    pub fn render(&self, values: &[(&str, &str)]) -> String {
+        let value = |name: &str| {
+            values
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map_or("", |(_, value)| value)
+        };
+
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Escaped(name) => escape_html(value(name)),
+                Part::Raw(name) => value(name).to_string(),
+            })
+            .collect()
+    }
+}
+
+/// The pages the server renders as HTML, from `--template-dir` or built in
+#[derive(Debug)]
+pub struct Templates {
+    /// `listing.html`: title, breadcrumbs, upload_form, rows, count
+    pub listing: Template,
+    /// `404.html`: path, message
+    pub not_found: Template,
+    /// `error.html`: status, reason, message
+    pub error: Template,
+    /// `upload.html`: path, href, summary, results
+    pub upload: Template,
+}
+
+impl Templates {
+    /// Load the templates in `dir`, using the built in ones for any that aren't there
+    pub fn load(dir: Option<&Path>) -> io::Result<Self> {
+        Ok(Self {
+            listing: load_template(dir, "listing.html", include_str!("templates/listing.html"))?,
+            not_found: load_template(dir, "404.html", include_str!("templates/404.html"))?,
+            error: load_template(dir, "error.html", include_str!("templates/error.html"))?,
+            upload: load_template(dir, "upload.html", include_str!("templates/upload.html"))?,
+        })
+    }
+
+    /// Turn a plain text error into a page, for browsers
+    ///
+    /// Only responses with a plain text body are changed, and only for clients that asked for HTML
+    pub fn render_error_page(&self, request: &ByteRequest, response: &mut ByteResponse) {
+        let status = response.status();
+
+        let wants_html = request
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html"));
+
+        let is_text = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .is_some_and(|content_type| content_type == "text/plain");
+
+        if !(status.is_client_error() || status.is_server_error()) || !wants_html || !is_text {
+            return;
+        }
+
+        let message = match response.body() {
+            Some(body) => String::from_utf8_lossy(body).to_string(),
+            None => return,
+        };
+
+        let page = if status == StatusCode::NOT_FOUND {
+            let path = urlencoding::decode(request.uri().path())
+                .map_or_else(|_| request.uri().path().to_string(), |p| p.to_string());
+
+            self.not_found
+                .render(&[("path", &path), ("message", &message)])
+        } else {
+            self.error.render(&[
+                ("status", status.as_str()),
+                ("reason", status.canonical_reason().unwrap_or_default()),
+                ("message", &message),
+            ])
+        };
+
+        let page = page.into_bytes();
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_LENGTH, page.len().into());
+        headers.insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        *response.body_mut() = Some(page);
+    }
+}
+
+fn load_template(dir: Option<&Path>, name: &str, built_in: &str) -> io::Result<Template> {
+    let path = dir.map(|dir| dir.join(name)).filter(|path| path.exists());
+
+    let (source, origin) = match &path {
+        Some(path) => (std::fs::read_to_string(path)?, path.clone()),
+        None => (built_in.to_string(), PathBuf::from(name)),
+    };
+
+    Template::parse(&source).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid template {}: {}", origin.display(), e),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{Request, Response};
+
+    use super::*;
+
+    fn templates() -> Templates {
+        Templates::load(None).unwrap()
+    }
+
+    fn text_response(status: u16, body: &str) -> ByteResponse {
+        Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(Some(body.as_bytes().to_vec()))
+            .unwrap()
+    }
+
+    fn request(accept: &str) -> ByteRequest {
+        Request::builder()
+            .uri("/missing%20file")
+            .header(header::ACCEPT, accept)
+            .body(None)
+            .unwrap()
+    }
+
+    #[test]
+    fn renders_escaped_and_raw_placeholders() {
+        let page = Template::parse("<h1>{{title}}</h1>{{{rows}}}{{ missing }}").unwrap();
+        assert_eq!(
+            page.render(&[("title", "<b>"), ("rows", "<tr></tr>")]),
+            "<h1>&lt;b&gt;</h1><tr></tr>"
+        );
+    }
+
+    #[test]
+    fn rejects_broken_placeholders() {
+        assert!(Template::parse("{{title").is_err());
+        assert!(Template::parse("{{}}").is_err());
+        assert!(Template::parse("{{a b}}").is_err());
+    }
+
+    #[test]
+    fn built_in_templates_parse() {
+        let templates = templates();
+        let listing = templates.listing.render(&[("title", "/docs/")]);
+        assert!(listing.contains("Index of /docs/"));
+    }
+
+    #[test]
+    fn error_pages_only_for_browsers() {
+        let templates = templates();
+
+        let mut response = text_response(404, "404: not found");
+        templates.render_error_page(&request("text/html"), &mut response);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        let body = String::from_utf8(response.body().clone().unwrap()).unwrap();
+        assert!(body.contains("/missing file"));
+
+        let mut response = text_response(403, "403: <nope>");
+        templates.render_error_page(&request("text/html"), &mut response);
+        let body = String::from_utf8(response.body().clone().unwrap()).unwrap();
+        assert!(body.contains("403: &lt;nope&gt;"));
+
+        let mut response = text_response(403, "403: nope");
+        templates.render_error_page(&request("*/*"), &mut response);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain");
+
+        let mut response = text_response(200, "fine");
+        templates.render_error_page(&request("text/html"), &mut response);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain");
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use owo_colors::OwoColorize;
+use tokio_rustls::{
+    rustls::{
+        crypto::{ring, CryptoProvider},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+use crate::colorize::MColorize;
+
+/// How often we check if the certificate files changed
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Create an acceptor that serves the certificate and key at the given paths
+///
+/// The files are checked for changes every few seconds (on new connections), so renewed
+/// certificates are picked up without restarting the server
+pub fn create_acceptor(cert_path: PathBuf, key_path: PathBuf) -> io::Result<TlsAcceptor> {
+    let provider = Arc::new(ring::default_provider());
+    let resolver = ReloadingCertResolver::new(cert_path, key_path, provider.clone())?;
+
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(to_io_error)?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+
+    config.alpn_protocols = vec![b"http/1.1".to_vec(), b"http/1.0".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[derive(Debug)]
+struct ReloadingCertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<LoadedCert>,
+    last_check: Mutex<Instant>,
+}
+
+#[derive(Debug)]
+struct LoadedCert {
+    key: Arc<CertifiedKey>,
+    /// Modification times of the (cert, key) files when they were loaded
+    modified: (Option<SystemTime>, Option<SystemTime>),
+}
+
+impl ReloadingCertResolver {
+    fn new(
+        cert_path: PathBuf,
+        key_path: PathBuf,
+        provider: Arc<CryptoProvider>,
+    ) -> io::Result<Self> {
+        let modified = (modified(&cert_path), modified(&key_path));
+        let key = load_certified_key(&cert_path, &key_path, &provider)?;
+
+        Ok(Self {
+            cert_path,
+            key_path,
+            provider,
+            current: RwLock::new(LoadedCert {
+                key: Arc::new(key),
+                modified,
+            }),
+            last_check: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Reload the certificate if the files changed since we last loaded them
+    ///
+    /// If the new files can't be loaded (ie. we caught them halfway through being renewed)
+    /// we keep serving the old certificate and try again on the next check
+    fn reload_if_changed(&self) {
+        {
+            let mut last_check = self.last_check.lock().unwrap();
+            if last_check.elapsed() < RELOAD_CHECK_INTERVAL {
+                return;
+            }
+            *last_check = Instant::now();
+        }
+
+        let modified = (modified(&self.cert_path), modified(&self.key_path));
+
+        if self.current.read().unwrap().modified == modified {
+            return;
+        }
+
+        match load_certified_key(&self.cert_path, &self.key_path, &self.provider) {
+            Ok(key) => {
+                *self.current.write().unwrap() = LoadedCert {
+                    key: Arc::new(key),
+                    modified,
+                };
+                println!(
+                    "Reloaded TLS certificate {}",
+                    self.cert_path.display().out_color(|t| t.blue())
+                );
+            }
+            Err(e) => eprintln!(
+                "Failed to reload TLS certificate, still using the old one: {}",
+                e
+            ),
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.reload_if_changed();
+        Some(self.current.read().unwrap().key.clone())
+    }
+}
+
+fn load_certified_key(
+    cert_path: &PathBuf,
+    key_path: &PathBuf,
+    provider: &CryptoProvider,
+) -> io::Result<CertifiedKey> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid certificate {}: {}", cert_path.display(), e),
+            )
+        })?;
+
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificates found in {}", cert_path.display()),
+        ));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid private key {}: {}", key_path.display(), e),
+        )
+    })?;
+
+    CertifiedKey::from_der(certs, key, provider).map_err(to_io_error)
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn to_io_error(e: tokio_rustls::rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
@@ -0,0 +1,677 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::OpenOptions,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use http::Method;
+use owo_colors::OwoColorize;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    cli::TokenCommand,
+    colorize::MColorize,
+    filesystem::{is_under, normalize_prefix, write_file_atomic},
+};
+
+/// Every token starts with this, so they're easy to spot in logs and secret scanners
+const TOKEN_PREFIX: &str = "httpfs_";
+
+/// Random bytes in a token's id, enough that minting never has to try many times
+const ID_BYTES: usize = 8;
+
+/// How often requests check whether the token file changed
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a token may be used for
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// GET and HEAD
+    Read,
+    /// POST, PUT and PATCH
+    Write,
+    /// DELETE
+    Delete,
+}
+
+impl Scope {
+    /// The scope a token needs to use `method`, if any scope allows it at all
+    fn required_for(method: &Method) -> Option<Self> {
+        match *method {
+            Method::GET | Method::HEAD => Some(Scope::Read),
+            Method::POST | Method::PUT | Method::PATCH => Some(Scope::Write),
+            Method::DELETE => Some(Scope::Delete),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::Read => write!(f, "read"),
+            Scope::Write => write!(f, "write"),
+            Scope::Delete => write!(f, "delete"),
+        }
+    }
+}
+
+/// What a request made with a valid token is allowed to do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenGrant {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Only paths under this one can be used, `/` for everything
+    pub prefix: String,
+}
+
+impl TokenGrant {
+    /// Check the token's scopes and path prefix allow `method` on `path`
+    ///
+    /// `path` is the decoded and flattened request path, ie. `/artifacts/build.zip`
+    pub fn permits(&self, method: &Method, path: &str) -> Result<(), String> {
+        match Scope::required_for(method) {
+            Some(scope) if self.scopes.contains(&scope) => {}
+            Some(scope) => {
+                return Err(format!(
+                    "token '{}' is missing the {} scope",
+                    self.name, scope
+                ))
+            }
+            None => return Err(format!("tokens can't be used for {}", method)),
+        }
+
+        if !is_under(path, &self.prefix) {
+            return Err(format!(
+                "token '{}' is limited to {}",
+                self.name, self.prefix
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// A token as stored in the token file, only the hash of the secret is kept
+#[derive(Debug, Clone)]
+struct StoredToken {
+    grant: TokenGrant,
+    /// Unix timestamp after which the token stops working
+    expires: Option<u64>,
+    hash: [u8; 32],
+}
+
+impl StoredToken {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| now >= expires)
+    }
+}
+
+/// API tokens loaded from a file, one per line:
+///
+/// ```text
+/// # ID             NAME      SCOPES      PREFIX      EXPIRES     SHA256 OF THE SECRET
+/// 3f9a1c2b8e04d7a6 ci-upload read,write  /artifacts  1767225600  9f86d081884c7d65...
+/// 77d0e4aa150c93fb backups   read        /           never       2c26b46b68ffc68f...
+/// ```
+///
+/// Tokens are sent as `Authorization: Bearer httpfs_ID_SECRET`. The file is re-read when it
+/// changes, checked at most once a `RELOAD_INTERVAL`, so minting and revoking tokens takes effect
+/// without a restart
+#[derive(Debug)]
+pub struct TokenStore {
+    path: PathBuf,
+    state: Mutex<TokenFile>,
+}
+
+#[derive(Debug, Default)]
+struct TokenFile {
+    modified: Option<SystemTime>,
+    /// When `modified` was last compared to the file's
+    checked: Option<Instant>,
+    tokens: HashMap<String, StoredToken>,
+}
+
+impl TokenStore {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let state = Mutex::new(TokenFile::read(&path)?);
+        Ok(Self { path, state })
+    }
+
+    /// Find the grant for a token, if it exists and hasn't expired
+    pub fn verify(&self, token: &str) -> Option<TokenGrant> {
+        let (id, secret) = split_token(token)?;
+        let hash = hash_secret(secret);
+
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        self.reload_if_changed(&mut state);
+
+        let stored = state.tokens.get(id)?;
+
+        if !bool::from(stored.hash.ct_eq(&hash)) || stored.is_expired(unix_now()) {
+            return None;
+        }
+
+        Some(stored.grant.clone())
+    }
+
+    fn reload_if_changed(&self, state: &mut TokenFile) {
+        let now = Instant::now();
+        if state
+            .checked
+            .is_some_and(|checked| now.duration_since(checked) < RELOAD_INTERVAL)
+        {
+            return;
+        }
+        state.checked = Some(now);
+
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|m| m.modified())
+            .ok();
+
+        if modified == state.modified {
+            return;
+        }
+
+        // Keep the old tokens if the file is broken, rather than locking everyone out
+        match TokenFile::read(&self.path) {
+            Ok(file) => {
+                *state = TokenFile {
+                    checked: state.checked,
+                    ..file
+                }
+            }
+            Err(e) => eprintln!("Failed to reload tokens, keeping the old ones: {}", e),
+        }
+    }
+}
+
+impl TokenFile {
+    fn read(path: &Path) -> io::Result<Self> {
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read tokens {}: {}", path.display(), e),
+            )
+        })?;
+
+        let mut tokens = HashMap::new();
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |e: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {}", path.display(), number + 1, e),
+                )
+            };
+
+            let token = parse_token_line(line).map_err(invalid)?;
+
+            // One would shadow the other, and revoking the id would remove both
+            if tokens.contains_key(&token.grant.id) {
+                return Err(invalid(format!(
+                    "token id '{}' is used more than once",
+                    token.grant.id
+                )));
+            }
+
+            tokens.insert(token.grant.id.clone(), token);
+        }
+
+        Ok(Self {
+            modified,
+            checked: None,
+            tokens,
+        })
+    }
+}
+
+fn parse_token_line(line: &str) -> Result<StoredToken, String> {
+    let words: Vec<&str> = line.split_ascii_whitespace().collect();
+
+    let [id, name, scopes, prefix, expires, hash] = words[..] else {
+        return Err("expected ID NAME SCOPES PREFIX EXPIRES HASH".to_string());
+    };
+
+    let scopes = scopes
+        .split(',')
+        .map(|scope| Scope::from_str(scope, true))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let expires = match expires {
+        "never" => None,
+        timestamp => Some(
+            timestamp
+                .parse()
+                .map_err(|_| format!("invalid expiry '{}'", timestamp))?,
+        ),
+    };
+
+    Ok(StoredToken {
+        grant: TokenGrant {
+            id: id.to_string(),
+            name: name.to_string(),
+            scopes,
+            prefix: normalize_prefix(prefix)?,
+        },
+        expires,
+        hash: decode_hex(hash).ok_or_else(|| format!("invalid hash '{}'", hash))?,
+    })
+}
+
+/// Run a `httpfs token ...` command against the token file at `path`
+pub async fn run_token_command(command: &TokenCommand, path: &Path) -> io::Result<()> {
+    match command {
+        TokenCommand::Mint {
+            name,
+            scopes,
+            prefix,
+            expires,
+        } => {
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Token names can't be empty or contain spaces",
+                ));
+            }
+
+            let grant = TokenGrant {
+                id: unused_id(path)?,
+                name: name.clone(),
+                scopes: scopes.clone(),
+                prefix: normalize_prefix(prefix)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            };
+
+            let secret = random_hex(32)?;
+            let expires = expires.map(expires_after).transpose()?;
+            let line = format_token_line(&grant, expires, &hash_secret(&secret));
+
+            append_line(path, &line)?;
+
+            println!(
+                "Minted token {} ({})",
+                grant.id.out_color(|t| t.cyan()),
+                name
+            );
+            println!("It is only shown this once:");
+            println!("{}{}_{}", TOKEN_PREFIX, grant.id, secret);
+        }
+        TokenCommand::Revoke { id } => {
+            let content = std::fs::read_to_string(path)?;
+            let mut found = false;
+
+            let kept: String = content
+                .lines()
+                .filter(|line| {
+                    let matches = line.split_ascii_whitespace().next() == Some(id.as_str());
+                    found |= matches;
+                    !matches
+                })
+                .map(|line| format!("{}\n", line))
+                .collect();
+
+            if !found {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No token with id '{}' in {}", id, path.display()),
+                ));
+            }
+
+            // Replacing the file would otherwise reset its permissions
+            let permissions = std::fs::metadata(path)?.permissions();
+            write_file_atomic(path, kept.as_bytes()).await?;
+            std::fs::set_permissions(path, permissions)?;
+            println!("Revoked token {}", id.out_color(|t| t.cyan()));
+        }
+        TokenCommand::List => {
+            let file = TokenFile::read(path)?;
+            let mut tokens: Vec<_> = file.tokens.values().collect();
+            tokens.sort_by(|a, b| a.grant.name.cmp(&b.grant.name));
+            let now = unix_now();
+
+            for token in tokens {
+                let scopes: Vec<String> = token.grant.scopes.iter().map(Scope::to_string).collect();
+                let expires = match token.expires {
+                    None => "never".to_string(),
+                    Some(_) if token.is_expired(now) => {
+                        "expired".out_color(|t| t.red()).to_string()
+                    }
+                    Some(expires) => format!("expires {}", format_timestamp(expires)),
+                };
+
+                println!(
+                    "{} {} {} {} {}",
+                    token.grant.id.out_color(|t| t.cyan()),
+                    token.grant.name,
+                    scopes.join(","),
+                    token.grant.prefix.out_color(|t| t.blue()),
+                    expires,
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A random token id that isn't in the token file at `path` yet
+fn unused_id(path: &Path) -> io::Result<String> {
+    let existing = match TokenFile::read(path) {
+        Ok(file) => file.tokens,
+        Err(_) if !path.exists() => HashMap::new(),
+        Err(e) => return Err(e),
+    };
+
+    loop {
+        let id = random_hex(ID_BYTES)?;
+        if !existing.contains_key(&id) {
+            return Ok(id);
+        }
+    }
+}
+
+fn format_token_line(grant: &TokenGrant, expires: Option<u64>, hash: &[u8; 32]) -> String {
+    let scopes: Vec<String> = grant.scopes.iter().map(Scope::to_string).collect();
+
+    format!(
+        "{} {} {} {} {} {}",
+        grant.id,
+        grant.name,
+        scopes.join(","),
+        grant.prefix,
+        expires.map_or("never".to_string(), |e| e.to_string()),
+        hash.iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>(),
+    )
+}
+
+/// Add a line to the token file, creating it readable only by us if it doesn't exist
+fn append_line(path: &Path, line: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)?;
+
+    writeln!(file, "{}", line)?;
+    file.sync_all()
+}
+
+/// Parse a token lifetime like `90d`, `12h`, `30m` or `3600s`
+pub fn parse_lifetime(arg: &str) -> Result<Duration, String> {
+    let invalid = || format!("Expected a lifetime like 30d, 12h or 90m, got '{}'", arg);
+
+    let unit_start = arg
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (amount, unit) = arg.split_at(unit_start);
+    let amount: u64 = amount.parse().map_err(|_| invalid())?;
+
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+
+    amount
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("Lifetime '{}' is too long", arg))
+}
+
+/// The unix time `lifetime` from now, failing if that's too far away to write down
+pub fn expires_after(lifetime: Duration) -> io::Result<u64> {
+    unix_now().checked_add(lifetime.as_secs()).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Lifetime of {}s is too long", lifetime.as_secs()),
+        )
+    })
+}
+
+/// Split `httpfs_ID_SECRET` into its id and secret
+fn split_token(token: &str) -> Option<(&str, &str)> {
+    token.strip_prefix(TOKEN_PREFIX)?.split_once('_')
+}
+
+fn hash_secret(secret: &str) -> [u8; 32] {
+    Sha256::digest(secret.as_bytes()).into()
+}
+
+pub fn random_hex(bytes: usize) -> io::Result<String> {
+    let mut buf = vec![0; bytes];
+    getrandom::getrandom(&mut buf).map_err(io::Error::from)?;
+    Ok(buf.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+pub fn decode_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut bytes = [0; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    Some(bytes)
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Format a unix timestamp as `2025-01-31 14:05 UTC`
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let minutes = timestamp % 86400 / 60;
+
+    // Howard Hinnant's days-to-civil algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        minutes / 60,
+        minutes % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lifetimes() {
+        assert_eq!(parse_lifetime("3600s"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_lifetime("90m"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_lifetime("12h"), Ok(Duration::from_secs(12 * 60 * 60)));
+        assert_eq!(
+            parse_lifetime("30d"),
+            Ok(Duration::from_secs(30 * 24 * 60 * 60))
+        );
+
+        for bad in ["", "30", "d", "-1d", "+1d", "1.5h", "1w", "1 d"] {
+            assert!(parse_lifetime(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn refuses_lifetimes_that_overflow() {
+        assert!(parse_lifetime(&format!("{}d", u64::MAX / 86400 + 1)).is_err());
+        assert!(parse_lifetime(&format!("{}s", u64::MAX)).is_ok());
+
+        assert!(expires_after(Duration::from_secs(u64::MAX)).is_err());
+        assert!(expires_after(Duration::from_secs(60)).unwrap() > unix_now());
+    }
+
+    fn grant(scopes: &[Scope], prefix: &str) -> TokenGrant {
+        TokenGrant {
+            id: "0123456789abcdef".to_string(),
+            name: "ci".to_string(),
+            scopes: scopes.to_vec(),
+            prefix: prefix.to_string(),
+        }
+    }
+
+    #[test]
+    fn checks_scopes_and_prefixes() {
+        let grant = grant(&[Scope::Read, Scope::Write], "/artifacts");
+
+        assert_eq!(grant.permits(&Method::GET, "/artifacts/a.zip"), Ok(()));
+        assert_eq!(grant.permits(&Method::HEAD, "/artifacts"), Ok(()));
+        assert_eq!(grant.permits(&Method::POST, "/artifacts/a.zip"), Ok(()));
+        assert!(grant.permits(&Method::DELETE, "/artifacts/a.zip").is_err());
+        assert!(grant.permits(&Method::GET, "/artifacts-old/a.zip").is_err());
+        assert!(grant.permits(&Method::GET, "/").is_err());
+        assert!(grant.permits(&Method::OPTIONS, "/artifacts").is_err());
+
+        let everything = self::grant(&[Scope::Delete], "/");
+        assert_eq!(everything.permits(&Method::DELETE, "/a/b"), Ok(()));
+    }
+
+    #[test]
+    fn parses_token_lines() {
+        let hash = "ab".repeat(32);
+        let line = format!(
+            "0123456789abcdef ci read,WRITE /artifacts/ 1767225600 {}",
+            hash
+        );
+
+        let token = parse_token_line(&line).unwrap();
+        assert_eq!(
+            token.grant,
+            grant(&[Scope::Read, Scope::Write], "/artifacts")
+        );
+        assert_eq!(token.expires, Some(1767225600));
+        assert_eq!(token.hash, [0xab; 32]);
+
+        let never = format!("0123456789abcdef ci read / never {}", hash);
+        assert_eq!(parse_token_line(&never).unwrap().expires, None);
+
+        for bad in [
+            "0123456789abcdef ci read / never".to_string(),
+            format!("0123456789abcdef ci read / never {} extra", hash),
+            format!("0123456789abcdef ci admin / never {}", hash),
+            format!("0123456789abcdef ci read artifacts never {}", hash),
+            format!("0123456789abcdef ci read / soon {}", hash),
+            format!("0123456789abcdef ci read / never {}", &hash[2..]),
+        ] {
+            assert!(parse_token_line(&bad).is_err(), "{}", bad);
+        }
+    }
+
+    /// A token file with one token for each `(id, secret, expires)`
+    fn token_file(tokens: &[(&str, &str, Option<u64>)]) -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let lines: Vec<String> = tokens
+            .iter()
+            .map(|(id, secret, expires)| {
+                let grant = TokenGrant {
+                    id: id.to_string(),
+                    ..grant(&[Scope::Read], "/")
+                };
+                format_token_line(&grant, *expires, &hash_secret(secret))
+            })
+            .collect();
+        std::fs::write(file.path(), lines.join("\n")).unwrap();
+        file
+    }
+
+    #[test]
+    fn verifies_tokens() {
+        let file = token_file(&[
+            ("aaaa", "secret", None),
+            ("bbbb", "secret", Some(unix_now() - 1)),
+        ]);
+        let store = TokenStore::load(file.path()).unwrap();
+
+        let grant = store.verify("httpfs_aaaa_secret").unwrap();
+        assert_eq!(grant.id, "aaaa");
+
+        for bad in [
+            "httpfs_aaaa_wrong",
+            "httpfs_aaaa_",
+            "httpfs_cccc_secret",
+            // Expired
+            "httpfs_bbbb_secret",
+            "aaaa_secret",
+            "httpfs_aaaasecret",
+        ] {
+            assert_eq!(store.verify(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn reloads_changed_token_files() {
+        let file = token_file(&[("aaaa", "secret", None)]);
+        let store = TokenStore::load(file.path()).unwrap();
+        assert!(store.verify("httpfs_aaaa_secret").is_some());
+
+        let replaced = token_file(&[("bbbb", "secret", None)]);
+        std::fs::copy(replaced.path(), file.path()).unwrap();
+        let later = SystemTime::now() + Duration::from_secs(10);
+        std::fs::File::options()
+            .write(true)
+            .open(file.path())
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+
+        // Not looked at again until the interval is up
+        assert!(store.verify("httpfs_aaaa_secret").is_some());
+
+        store.state.lock().unwrap().checked = None;
+        assert!(store.verify("httpfs_aaaa_secret").is_none());
+        assert!(store.verify("httpfs_bbbb_secret").is_some());
+    }
+
+    #[test]
+    fn refuses_duplicate_ids() {
+        let file = token_file(&[("aaaa", "one", None), ("aaaa", "two", None)]);
+        assert!(TokenStore::load(file.path()).is_err());
+    }
+
+    #[test]
+    fn mints_ids_that_are_not_taken() {
+        let file = token_file(&[("aaaa", "secret", None)]);
+        let id = unused_id(file.path()).unwrap();
+        assert_eq!(id.len(), ID_BYTES * 2);
+        assert_ne!(id, "aaaa");
+
+        let missing = file.path().with_extension("missing");
+        assert!(unused_id(&missing).is_ok());
+    }
+}
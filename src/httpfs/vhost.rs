@@ -0,0 +1,327 @@
+use std::path::PathBuf;
+
+/// A hostname pattern used to pick a virtual host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    /// `example.com`, matches only that exact hostname
+    Exact(String),
+    /// `*.example.com`, matches any subdomain of `example.com` (but not `example.com` itself)
+    /// Stored as the suffix including the leading dot, ie. `.example.com`
+    Wildcard(String),
+}
+
+impl HostPattern {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        let pattern = normalize_hostname(pattern);
+
+        if pattern.is_empty() {
+            return Err("Host pattern is empty".to_string());
+        }
+
+        match pattern.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') && suffix.len() > 1 => {
+                Ok(HostPattern::Wildcard(suffix.to_string()))
+            }
+            Some(_) => Err(format!(
+                "Wildcards are only supported as a leading '*.': '{}'",
+                pattern
+            )),
+            None if pattern.contains('*') => Err(format!(
+                "Wildcards are only supported as a leading '*.': '{}'",
+                pattern
+            )),
+            None => Ok(HostPattern::Exact(pattern)),
+        }
+    }
+
+    fn matches(&self, hostname: &str) -> bool {
+        match self {
+            HostPattern::Exact(name) => name == hostname,
+            HostPattern::Wildcard(suffix) => {
+                hostname.len() > suffix.len() && hostname.ends_with(suffix.as_str())
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HostPattern::Exact(name) => write!(f, "{}", name),
+            HostPattern::Wildcard(suffix) => write!(f, "*{}", suffix),
+        }
+    }
+}
+
+/// Settings for a single virtual host
+#[derive(Debug, Clone)]
+pub struct VirtualHost {
+    /// The pattern that picks this host, or `*` for the default host, which no pattern can be
+    pub name: String,
+    /// Directory that requests for this host are served from
+    pub root: PathBuf,
+    /// Whether directories without an explicit file can be listed
+    pub listing: bool,
+    /// Whether uploads are disabled
+    pub read_only: bool,
+}
+
+impl VirtualHost {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            name: "*".to_string(),
+            root: root.into(),
+            listing: true,
+            read_only: false,
+        }
+    }
+}
+
+/// A `--vhost` argument, `PATTERN=DIR[,OPTION...]`
+#[derive(Debug, Clone)]
+pub struct VirtualHostSpec {
+    pub pattern: HostPattern,
+    pub host: VirtualHost,
+}
+
+/// Parse a `--vhost` argument, like `example.com=/srv/example` or
+/// `*.docs.example.com=/srv/docs,no-listing,read-only`
+pub fn parse_vhost(arg: &str) -> Result<VirtualHostSpec, String> {
+    let (pattern, rest) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("Expected PATTERN=DIR, got '{}'", arg))?;
+
+    let pattern = HostPattern::parse(pattern)?;
+
+    let mut parts = rest.split(',');
+    let root = parts.next().unwrap_or_default();
+
+    if root.is_empty() {
+        return Err(format!("Directory missing for host '{}'", pattern));
+    }
+
+    let mut host = VirtualHost::new(root);
+    host.name = pattern.to_string();
+
+    for option in parts {
+        match option {
+            "listing" => host.listing = true,
+            "no-listing" => host.listing = false,
+            "read-only" => host.read_only = true,
+            "read-write" => host.read_only = false,
+            _ => {
+                return Err(format!(
+                    "Unknown option '{}' for host '{}'",
+                    option, pattern
+                ))
+            }
+        }
+    }
+
+    Ok(VirtualHostSpec { pattern, host })
+}
+
+/// Every virtual host the server knows about, plus the one used when nothing matches
+#[derive(Debug)]
+pub struct VirtualHosts {
+    default: VirtualHost,
+    hosts: Vec<VirtualHostSpec>,
+}
+
+impl VirtualHosts {
+    pub fn new(default: VirtualHost, hosts: Vec<VirtualHostSpec>) -> Self {
+        Self { default, hosts }
+    }
+
+    pub fn default_host(&self) -> &VirtualHost {
+        &self.default
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &VirtualHostSpec> {
+        self.hosts.iter()
+    }
+
+    /// Find the virtual host for a `Host` header value, falling back to the default host
+    ///
+    /// Exact names win over wildcards, and longer wildcards win over shorter ones,
+    /// so `*.a.example.com` is picked over `*.example.com` for `x.a.example.com`
+    pub fn resolve(&self, host_header: Option<&str>) -> &VirtualHost {
+        let hostname = match host_header {
+            Some(host) => normalize_hostname(strip_port(host)),
+            None => return &self.default,
+        };
+
+        let exact = self.hosts.iter().find(|spec| {
+            matches!(spec.pattern, HostPattern::Exact(_)) && spec.pattern.matches(&hostname)
+        });
+
+        if let Some(spec) = exact {
+            return &spec.host;
+        }
+
+        self.hosts
+            .iter()
+            .filter(|spec| spec.pattern.matches(&hostname))
+            .max_by_key(|spec| match &spec.pattern {
+                HostPattern::Wildcard(suffix) => suffix.len(),
+                HostPattern::Exact(_) => 0,
+            })
+            .map(|spec| &spec.host)
+            .unwrap_or(&self.default)
+    }
+}
+
+/// Remove the port from a `Host` header value, IPv6 literals keep their brackets
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        // IPv6 literal, the port (if any) comes after the closing bracket
+        return match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        };
+    }
+
+    match host.rsplit_once(':') {
+        Some((name, _)) => name,
+        None => host,
+    }
+}
+
+/// Hostnames are case-insensitive and may have a trailing dot (`example.com.`)
+fn normalize_hostname(host: &str) -> String {
+    host.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(args: &[&str]) -> VirtualHosts {
+        let specs = args.iter().map(|arg| parse_vhost(arg).unwrap()).collect();
+        VirtualHosts::new(VirtualHost::new("/srv/default"), specs)
+    }
+
+    fn root<'a>(hosts: &'a VirtualHosts, host: Option<&str>) -> &'a str {
+        hosts.resolve(host).root.to_str().unwrap()
+    }
+
+    #[test]
+    fn strips_ports() {
+        assert_eq!(strip_port("example.com:8080"), "example.com");
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("127.0.0.1:80"), "127.0.0.1");
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+        assert_eq!(strip_port("[2001:db8::1]:443"), "[2001:db8::1]");
+        assert_eq!(strip_port("[::1"), "[::1");
+    }
+
+    #[test]
+    fn parses_host_patterns() {
+        assert_eq!(
+            HostPattern::parse("Example.COM."),
+            Ok(HostPattern::Exact("example.com".to_string()))
+        );
+        assert_eq!(
+            HostPattern::parse("*.Example.com"),
+            Ok(HostPattern::Wildcard(".example.com".to_string()))
+        );
+        assert_eq!(
+            HostPattern::parse("*.example.com").unwrap().to_string(),
+            "*.example.com"
+        );
+
+        for pattern in [
+            "",
+            ".",
+            "*",
+            "*.",
+            "*example.com",
+            "www.*.com",
+            "a*.example.com",
+        ] {
+            assert!(HostPattern::parse(pattern).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn matches_wildcards_only_on_subdomains() {
+        let pattern = HostPattern::parse("*.example.com").unwrap();
+
+        assert!(pattern.matches("www.example.com"));
+        assert!(pattern.matches("a.b.example.com"));
+        assert!(!pattern.matches("example.com"));
+        assert!(!pattern.matches(".example.com"));
+        assert!(!pattern.matches("badexample.com"));
+        assert!(!pattern.matches("example.com.evil"));
+    }
+
+    #[test]
+    fn parses_vhost_arguments() {
+        let spec = parse_vhost("example.com=/srv/example").unwrap();
+        assert_eq!(spec.pattern, HostPattern::Exact("example.com".to_string()));
+        assert_eq!(spec.host.name, "example.com");
+        assert_eq!(spec.host.root, PathBuf::from("/srv/example"));
+        assert!(spec.host.listing && !spec.host.read_only);
+
+        let spec = parse_vhost("*.docs.example.com=/srv/docs,no-listing,read-only").unwrap();
+        assert_eq!(spec.host.name, "*.docs.example.com");
+        assert!(!spec.host.listing && spec.host.read_only);
+
+        let spec = parse_vhost("a.example=/srv/a,read-only,read-write").unwrap();
+        assert!(!spec.host.read_only);
+
+        for arg in [
+            "example.com",
+            "example.com=",
+            "=/srv/example",
+            "example.com=/srv/example,writable",
+        ] {
+            assert!(parse_vhost(arg).is_err(), "{}", arg);
+        }
+    }
+
+    #[test]
+    fn resolves_hosts() {
+        let hosts = hosts(&[
+            "*.example.com=/srv/wildcard",
+            "*.a.example.com=/srv/a-wildcard",
+            "www.a.example.com=/srv/www-a",
+            "example.com=/srv/example",
+            "[::1]=/srv/ipv6",
+            "127.0.0.1=/srv/ipv4",
+        ]);
+
+        assert_eq!(root(&hosts, Some("example.com")), "/srv/example");
+        assert_eq!(root(&hosts, Some("EXAMPLE.com:8080")), "/srv/example");
+        assert_eq!(root(&hosts, Some("x.example.com")), "/srv/wildcard");
+        assert_eq!(root(&hosts, Some("x.a.example.com")), "/srv/a-wildcard");
+        assert_eq!(root(&hosts, Some("www.a.example.com")), "/srv/www-a");
+        assert_eq!(root(&hosts, Some("[::1]:8080")), "/srv/ipv6");
+        assert_eq!(root(&hosts, Some("[::1]")), "/srv/ipv6");
+        assert_eq!(root(&hosts, Some("127.0.0.1:80")), "/srv/ipv4");
+    }
+
+    #[test]
+    fn ignores_trailing_dots() {
+        let hosts = hosts(&["example.com.=/srv/example", "*.example.org=/srv/org"]);
+
+        assert_eq!(root(&hosts, Some("example.com")), "/srv/example");
+        assert_eq!(root(&hosts, Some("example.com.")), "/srv/example");
+        assert_eq!(root(&hosts, Some("example.com.:8080")), "/srv/example");
+        assert_eq!(root(&hosts, Some("www.example.org.")), "/srv/org");
+    }
+
+    #[test]
+    fn falls_back_to_the_default_host() {
+        let hosts = hosts(&["example.com=/srv/example", "*.example.org=/srv/org"]);
+
+        assert_eq!(hosts.default_host().name, "*");
+        assert_eq!(root(&hosts, None), "/srv/default");
+        assert_eq!(root(&hosts, Some("")), "/srv/default");
+        assert_eq!(root(&hosts, Some("other.com")), "/srv/default");
+        assert_eq!(root(&hosts, Some("example.org")), "/srv/default");
+        assert_eq!(root(&hosts, Some("www.example.com")), "/srv/default");
+        assert_eq!(root(&hosts, Some("[::1]:8080")), "/srv/default");
+    }
+}
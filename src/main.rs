@@ -1,5 +1,7 @@
-use crate::cli::Cli;
-use crate::httpfs::server::run_server;
+use crate::cli::{Cli, Command};
+use crate::httpfs::{
+    config::ServerConfig, server::run_server, share::run_share_command, tokens::run_token_command,
+};
 use clap::Parser;
 
 mod cli;
@@ -12,13 +14,59 @@ async fn main() {
     let args = Cli::parse();
     args.color.init();
 
+    if let Some(command) = &args.command {
+        let missing = |option: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is required for this command", option),
+            )
+        };
+
+        let result = match command {
+            Command::Token(command) => match &args.token_file {
+                Some(path) => run_token_command(command, path).await,
+                None => Err(missing("--token-file")),
+            },
+            Command::Share {
+                path,
+                expires,
+                method,
+                host,
+                base_url,
+            } => match &args.share_secret {
+                Some(secret) => run_share_command(
+                    secret,
+                    path,
+                    method,
+                    host.as_ref(),
+                    *expires,
+                    base_url.as_deref(),
+                ),
+                None => Err(missing("--share-secret")),
+            },
+        };
+
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
+    let config = match ServerConfig::try_from(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
     // The server runs in a loop unless it hits a completely unrecoverable error.
     // Like "we literally can't serve another client" level bad
-    let res = run_server(&args.dir, args.port, args.verbosity).await;
-
-    if res.is_err() {
+    if let Err(e) = run_server(config).await {
         // oh no
-        eprintln!("{}", res.unwrap_err());
+        eprintln!("{}", e);
         std::process::exit(1);
     }
 }